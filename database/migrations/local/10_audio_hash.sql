@@ -0,0 +1,4 @@
+-- SHA-256 of the audio payload alone, identifying a track regardless of its tags.
+ALTER TABLE tracks ADD COLUMN audio_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS tracks_audio ON tracks (audio_hash);
@@ -0,0 +1,3 @@
+-- Where a copied track ended up, relative to the destination: tracks which would be stored at
+-- the same path get numbered, so the actual path has to be remembered.
+ALTER TABLE tracks ADD COLUMN stored_path BLOB;
@@ -0,0 +1,7 @@
+-- Acoustic fingerprints only depend on the audio, so they're keyed by its hash: they survive
+-- tag edits, moves and rescans.
+-- Files that couldn't be decoded get an empty fingerprint, so that they aren't tried again.
+CREATE TABLE IF NOT EXISTS fingerprints (
+    audio_hash TEXT PRIMARY KEY NOT NULL,
+    fingerprint BLOB NOT NULL
+);
@@ -0,0 +1,3 @@
+-- The artist tag of the track itself: artist holds the album artist whenever there's one, which
+-- says little about the songs of a compilation.
+ALTER TABLE tracks ADD COLUMN track_artist TEXT;
@@ -0,0 +1,14 @@
+-- Duplicate resolution decisions: suppressed tracks are never copied to destinations.
+-- Tracks are referenced by key (audio hash, or tag-derived id) so that decisions survive moves
+-- and rescans, paths are kept for display and to restore quarantined files.
+CREATE TABLE IF NOT EXISTS suppressions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    track_key TEXT NOT NULL,
+    file_path BLOB NOT NULL,
+    kept_path BLOB NOT NULL,
+    reason TEXT NOT NULL,
+    quarantine_path BLOB,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS suppressions_key ON suppressions (track_key);
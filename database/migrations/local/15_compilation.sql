@@ -0,0 +1,5 @@
+ALTER TABLE tracks
+ADD COLUMN compilation BOOL NOT NULL DEFAULT FALSE;
+
+ALTER TABLE state
+ADD COLUMN compilations TEXT;
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS artist_aliases (
+    alias  TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+    artist TEXT NOT NULL
+);
+
+-- Artist as found in tags, before aliases are applied to the artist column.
+ALTER TABLE tracks
+ADD COLUMN tagged_artist TEXT;
+
+UPDATE tracks SET tagged_artist = artist;
+
+ALTER TABLE tracks
+ADD COLUMN artist_sort TEXT;
+
+ALTER TABLE state
+ADD COLUMN folder_names TEXT;
+
+CREATE TRIGGER track_fts_au_update AFTER UPDATE OF track_id, title, album, artist, extension ON tracks BEGIN
+  INSERT INTO track_fts(track_fts, rowid, track_id, title, album, artist, extension) VALUES('delete', old.id, old.track_id, old.title, old.album, old.artist, old.extension);
+  INSERT INTO track_fts(rowid, track_id, title, album, artist, extension) VALUES (new.id, new.track_id, new.title, new.album, new.artist, new.extension);
+END;
@@ -0,0 +1,6 @@
+-- Set once tags stored by older versions have been normalised to NFC.
+ALTER TABLE state
+ADD COLUMN tags_normalised BOOL NOT NULL DEFAULT FALSE;
+
+ALTER TABLE state
+ADD COLUMN path_form TEXT;
@@ -0,0 +1,3 @@
+-- Tracks are matched across databases by key, see model::Track::key: the expression has to be
+-- written exactly this way in queries for the index to be used.
+CREATE INDEX IF NOT EXISTS tracks_key ON tracks (COALESCE(audio_hash, track_id), file_state);
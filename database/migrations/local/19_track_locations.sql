@@ -0,0 +1,16 @@
+-- A row per file: local tracks are told apart by their path, copies by their path on the
+-- destination as well, since a source path may hold another file by the time the copy of the
+-- previous one is deleted.
+-- Older versions recorded each copy twice, in flight and once completed: the completed row, or
+-- else the latest one, is kept. Rows of destinations which didn't record stored paths yet are
+-- merged by source path alone, which may leave behind a file no track points to.
+DELETE FROM tracks WHERE id IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (
+            PARTITION BY file_path, COALESCE(stored_path, X'')
+            ORDER BY file_state, id DESC
+        ) AS n FROM tracks
+    ) WHERE n > 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS tracks_location ON tracks (file_path, COALESCE(stored_path, X''));
@@ -0,0 +1,21 @@
+-- Plays read from the scrobbler logs devices write, see 'history import'.
+-- Tracks are referenced by key, like suppressions, so that plays survive moves and rescans.
+-- A play is recorded once, however many times the log holding it is read.
+CREATE TABLE IF NOT EXISTS plays (
+    track_key TEXT NOT NULL,
+    -- Seconds since the epoch, in the device's time zone if it doesn't know better.
+    played_at INTEGER NOT NULL,
+    PRIMARY KEY (track_key, played_at)
+);
+
+CREATE VIEW IF NOT EXISTS track_plays (track_key, play_count, last_played) AS
+SELECT track_key, COUNT(*), MAX(played_at) FROM plays GROUP BY track_key;
+
+-- Plays of all the tracks of an album, as filters see it.
+CREATE VIEW IF NOT EXISTS album_plays (artist, album, play_count) AS
+SELECT t.artist, t.album, COUNT(*) FROM plays AS p
+JOIN tracks AS t ON COALESCE(t.audio_hash, t.track_id) = p.track_key AND t.file_state = 0
+GROUP BY t.artist, t.album;
+
+-- Log entries are matched to tracks by title first.
+CREATE INDEX IF NOT EXISTS tracks_title ON tracks (title COLLATE NOCASE);
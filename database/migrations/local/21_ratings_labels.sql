@@ -0,0 +1,39 @@
+-- Ratings and labels given with 'label', for filters. Tracks are referenced by key, like
+-- suppressions and plays, and albums by artist and title, so that they survive updates and
+-- rescans.
+CREATE TABLE IF NOT EXISTS ratings (
+    track_key TEXT PRIMARY KEY NOT NULL,
+    rating INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS album_ratings (
+    artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY (artist, album)
+);
+
+CREATE TABLE IF NOT EXISTS labels (
+    track_key TEXT NOT NULL,
+    label TEXT NOT NULL,
+    PRIMARY KEY (track_key, label)
+);
+
+-- Tracks along with what filters see besides their tags: plays, ratings, the album's rating
+-- standing in for the track's own, and newline-separated labels.
+CREATE VIEW IF NOT EXISTS annotated_tracks AS
+SELECT t.*,
+    COALESCE(tp.play_count, 0) AS play_count,
+    COALESCE(ap.play_count, 0) AS album_play_count,
+    tp.last_played,
+    COALESCE(r.rating, ar.rating) AS rating,
+    ar.rating AS album_rating,
+    (
+        SELECT GROUP_CONCAT(l.label, char(10)) FROM labels AS l
+        WHERE l.track_key = COALESCE(t.audio_hash, t.track_id)
+    ) AS labels
+FROM tracks AS t
+LEFT JOIN track_plays AS tp ON tp.track_key = COALESCE(t.audio_hash, t.track_id)
+LEFT JOIN album_plays AS ap ON ap.artist = t.artist AND ap.album = t.album
+LEFT JOIN ratings AS r ON r.track_key = COALESCE(t.audio_hash, t.track_id)
+LEFT JOIN album_ratings AS ar ON ar.artist = t.artist AND ar.album = t.album;
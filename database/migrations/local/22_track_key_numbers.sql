@@ -0,0 +1,69 @@
+-- Tracks whose audio couldn't be hashed are keyed by their tag-derived id along with their disc
+-- and track numbers, see model::Track::key: the id alone is shared by the tracks of an album
+-- repeating a title, such as an intro on each disc.
+-- As before, the expression has to be written exactly this way in queries for the index to be
+-- used.
+DROP INDEX IF EXISTS tracks_key;
+CREATE INDEX IF NOT EXISTS tracks_key ON tracks (
+    COALESCE(audio_hash, track_id || '-' || disc_number || '-' || number), file_state
+);
+
+-- Decisions, plays, ratings and labels recorded under the id alone move to the new keys: those
+-- of every track having the id, or for suppressions the one at the suppressed path.
+UPDATE suppressions SET track_key = (
+    SELECT t.track_id || '-' || t.disc_number || '-' || t.number FROM tracks AS t
+    WHERE t.audio_hash IS NULL AND t.track_id = suppressions.track_key
+    AND t.file_path = suppressions.file_path
+)
+WHERE EXISTS (
+    SELECT 1 FROM tracks AS t
+    WHERE t.audio_hash IS NULL AND t.track_id = suppressions.track_key
+    AND t.file_path = suppressions.file_path
+);
+
+INSERT OR IGNORE INTO plays (track_key, played_at)
+SELECT t.track_id || '-' || t.disc_number || '-' || t.number, p.played_at FROM plays AS p
+JOIN tracks AS t ON t.audio_hash IS NULL AND t.track_id = p.track_key;
+
+DELETE FROM plays WHERE track_key IN (SELECT track_id FROM tracks WHERE audio_hash IS NULL);
+
+INSERT OR IGNORE INTO ratings (track_key, rating)
+SELECT t.track_id || '-' || t.disc_number || '-' || t.number, r.rating FROM ratings AS r
+JOIN tracks AS t ON t.audio_hash IS NULL AND t.track_id = r.track_key;
+
+DELETE FROM ratings WHERE track_key IN (SELECT track_id FROM tracks WHERE audio_hash IS NULL);
+
+INSERT OR IGNORE INTO labels (track_key, label)
+SELECT t.track_id || '-' || t.disc_number || '-' || t.number, l.label FROM labels AS l
+JOIN tracks AS t ON t.audio_hash IS NULL AND t.track_id = l.track_key;
+
+DELETE FROM labels WHERE track_key IN (SELECT track_id FROM tracks WHERE audio_hash IS NULL);
+
+DROP VIEW IF EXISTS annotated_tracks;
+DROP VIEW IF EXISTS album_plays;
+
+CREATE VIEW IF NOT EXISTS album_plays (artist, album, play_count) AS
+SELECT t.artist, t.album, COUNT(*) FROM plays AS p
+JOIN tracks AS t
+ON COALESCE(t.audio_hash, t.track_id || '-' || t.disc_number || '-' || t.number) = p.track_key
+AND t.file_state = 0
+GROUP BY t.artist, t.album;
+
+CREATE VIEW IF NOT EXISTS annotated_tracks AS
+SELECT t.*,
+    COALESCE(tp.play_count, 0) AS play_count,
+    COALESCE(ap.play_count, 0) AS album_play_count,
+    tp.last_played,
+    COALESCE(r.rating, ar.rating) AS rating,
+    ar.rating AS album_rating,
+    (
+        SELECT GROUP_CONCAT(l.label, char(10)) FROM labels AS l
+        WHERE l.track_key = COALESCE(t.audio_hash, t.track_id || '-' || t.disc_number || '-' || t.number)
+    ) AS labels
+FROM tracks AS t
+LEFT JOIN track_plays AS tp
+ON tp.track_key = COALESCE(t.audio_hash, t.track_id || '-' || t.disc_number || '-' || t.number)
+LEFT JOIN album_plays AS ap ON ap.artist = t.artist AND ap.album = t.album
+LEFT JOIN ratings AS r
+ON r.track_key = COALESCE(t.audio_hash, t.track_id || '-' || t.disc_number || '-' || t.number)
+LEFT JOIN album_ratings AS ar ON ar.artist = t.artist AND ar.album = t.album;
@@ -0,0 +1,13 @@
+-- A track found more than once in an album, e.g. the same file under two source directories,
+-- counted each of its plays as many times: plays are now joined to each track key of an album
+-- once.
+DROP VIEW IF EXISTS album_plays;
+
+CREATE VIEW IF NOT EXISTS album_plays (artist, album, play_count) AS
+SELECT k.artist, k.album, COUNT(*) FROM plays AS p
+JOIN (
+    SELECT DISTINCT artist, album,
+        COALESCE(audio_hash, track_id || '-' || disc_number || '-' || number) AS track_key
+    FROM tracks WHERE file_state = 0
+) AS k ON k.track_key = p.track_key
+GROUP BY k.artist, k.album;
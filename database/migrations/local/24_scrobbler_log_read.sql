@@ -0,0 +1,4 @@
+-- How many bytes of a destination's scrobbler log sync has read: players append to it, so only
+-- what follows is read by the next sync.
+ALTER TABLE state
+ADD COLUMN scrobbler_log_read INTEGER NOT NULL DEFAULT 0;
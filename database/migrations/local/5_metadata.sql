@@ -0,0 +1,18 @@
+ALTER TABLE tracks ADD COLUMN year INTEGER;
+ALTER TABLE tracks ADD COLUMN genre TEXT;
+ALTER TABLE tracks ADD COLUMN composer TEXT;
+ALTER TABLE tracks ADD COLUMN track_total INTEGER;
+ALTER TABLE tracks ADD COLUMN duration REAL;
+ALTER TABLE tracks ADD COLUMN bitrate INTEGER;
+ALTER TABLE tracks ADD COLUMN sample_rate INTEGER;
+ALTER TABLE tracks ADD COLUMN bit_depth INTEGER;
+ALTER TABLE tracks ADD COLUMN channels INTEGER;
+ALTER TABLE tracks ADD COLUMN file_size INTEGER;
+ALTER TABLE tracks ADD COLUMN replaygain_track_gain REAL;
+ALTER TABLE tracks ADD COLUMN replaygain_track_peak REAL;
+ALTER TABLE tracks ADD COLUMN replaygain_album_gain REAL;
+ALTER TABLE tracks ADD COLUMN replaygain_album_peak REAL;
+ALTER TABLE tracks ADD COLUMN musicbrainz_track_id TEXT;
+ALTER TABLE tracks ADD COLUMN musicbrainz_album_id TEXT;
+ALTER TABLE tracks ADD COLUMN musicbrainz_artist_id TEXT;
+ALTER TABLE tracks ADD COLUMN musicbrainz_album_artist_id TEXT;
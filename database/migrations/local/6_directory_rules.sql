@@ -0,0 +1,2 @@
+ALTER TABLE directories ADD COLUMN include_patterns TEXT;
+ALTER TABLE directories ADD COLUMN exclude_patterns TEXT;
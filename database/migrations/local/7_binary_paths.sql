@@ -0,0 +1,4 @@
+-- Paths are stored as raw bytes, so that names which aren't valid UTF-8 can be represented:
+-- convert existing ones so that comparisons against BLOB parameters keep matching.
+UPDATE tracks SET file_path = CAST(file_path AS BLOB);
+UPDATE directories SET directory = CAST(directory AS BLOB);
@@ -0,0 +1,2 @@
+ALTER TABLE directories ADD COLUMN follow_links BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE directories ADD COLUMN same_file_system BOOL NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- SHA-256 of the whole file, used along with file_size to recognise files that moved.
+ALTER TABLE tracks ADD COLUMN content_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS tracks_content ON tracks (file_size, content_hash);
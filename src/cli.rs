@@ -1,4 +1,4 @@
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand};
 
 use crate::cmd;
 
@@ -29,4 +29,40 @@ pub enum Commands {
 
     /// Filter tracks to copy over to a destination.
     Filter(cmd::filter::Args),
+
+    /// Shows or changes how tracks are laid out on a destination.
+    Destination(cmd::destination::Args),
+
+    /// Searches tracks by artist, album, title or format, locally or on a destination.
+    Search(cmd::search::Args),
+
+    /// Reports track, album and format counts, sizes and durations, and how much of the library
+    /// a destination holds.
+    Stats(cmd::stats::Args),
+
+    /// Manages source directories previously added with 'add'.
+    Source(cmd::source::Args),
+
+    /// Manages artist aliases, so that differently spelled artists are treated as one.
+    Alias(cmd::alias::Args),
+
+    /// Dumps a database to JSON or CSV, as a backup or to carry it across upgrades.
+    Export(cmd::export::Args),
+
+    /// Rebuilds a database from a dump written by 'export'.
+    Import(cmd::import::Args),
+
+    /// Checks a database for corruption, duplicate rows and a stale full-text index, and fixes
+    /// them on request.
+    Doctor(cmd::doctor::Args),
+
+    /// Rebuilds the local database from disk, or rewrites a destination's database to match the
+    /// local one, after upgrading tracksync.
+    Rescan(cmd::rescan::Args),
+
+    /// Imports plays from the scrobbler logs devices write, for filters to use.
+    History(cmd::history::Args),
+
+    /// Rates and labels tracks, for filters to use.
+    Label(cmd::label::Args),
 }
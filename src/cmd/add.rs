@@ -4,7 +4,7 @@ use crate::cmd::*;
 use crate::*;
 use anyhow::{Context, Result};
 use clap::Args as ClapArgs;
-use futures::{executor::block_on, future::try_join_all};
+use futures::executor::block_on;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use model::FileState;
 
@@ -17,7 +17,7 @@ pub struct Args {
     /// A path in which tracksync will look for music files.
     /// Specify more than one for multiple sources.
     #[arg(short, long = "source", value_name = "SOURCE", action = clap::ArgAction::Append)]
-    pub sources: Option<Vec<String>>,
+    pub sources: Option<Vec<std::path::PathBuf>>,
 
     /// Specifies if the database is to be written is a destination one.
     #[arg(
@@ -26,11 +26,31 @@ pub struct Args {
         default_value_t = false
     )]
     pub is_destination: bool,
+
+    /// Only import files matching this glob pattern, relative to the source directory.
+    /// Specify more than one to import files matching any of them.
+    /// Sources added again keep their patterns unless new ones are given.
+    #[arg(long = "include", value_name = "GLOB", action = clap::ArgAction::Append)]
+    pub include: Vec<String>,
+
+    /// Do not import files or directories matching this glob pattern, relative to the source
+    /// directory. Exclude patterns take precedence over include ones.
+    #[arg(long = "exclude", value_name = "GLOB", action = clap::ArgAction::Append)]
+    pub exclude: Vec<String>,
+
+    /// Follow symbolic links while reading sources, skipping any link that loops back to one
+    /// of its parent directories.
+    #[arg(long, default_value_t = false)]
+    pub follow_links: bool,
+
+    /// Do not cross file system boundaries while reading sources.
+    #[arg(long, default_value_t = false)]
+    pub same_file_system: bool,
 }
 
 impl Args {
     pub fn validate(&self) -> Result<(), error::Error> {
-        if let None = self.sources {
+        if self.sources.is_none() {
             return Err(error::Error::ValidationError(
                 "missing source(s)".to_owned(),
             ));
@@ -43,9 +63,8 @@ impl Args {
 pub async fn run(args: Args, update: bool) -> Result<()> {
     let val_res = args.validate();
 
-    match update {
-        true => {}
-        false => val_res?,
+    if !update {
+        val_res?
     };
 
     log::debug!("CLI args: {:?}", args);
@@ -56,19 +75,95 @@ pub async fn run(args: Args, update: bool) -> Result<()> {
         .with_context(|| "Cannot open local database instance")?;
 
     let sources = match update {
-        false => args.sources.unwrap(),
+        false => {
+            let mut sources = vec![];
+
+            for s in args.sources.clone().unwrap() {
+                sources.push(source_directory(&db, &args, s).await?);
+            }
+
+            sources
+        }
         true => db
             .directories()
             .await
             .with_context(|| "Cannot fetch track directories from database")?,
     };
 
+    import(&db, sources, update).await
+}
+
+/// Returns the source directory to add at path, with the rules and options given in args.
+/// Adding a source again keeps the rules and options it already has, unless args gives new
+/// ones: 'source edit' is the way to clear them.
+async fn source_directory(
+    db: &db::Instance,
+    args: &Args,
+    path: std::path::PathBuf,
+) -> Result<model::Directory> {
+    let mut directory = db
+        .directory(&path)
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?
+        .unwrap_or_else(|| model::Directory {
+            directory: path,
+            ..Default::default()
+        });
+
+    if !args.include.is_empty() {
+        directory.include = args.include.clone();
+    }
+
+    if !args.exclude.is_empty() {
+        directory.exclude = args.exclude.clone();
+    }
+
+    directory.follow_links |= args.follow_links;
+    directory.same_file_system |= args.same_file_system;
+
+    Ok(directory)
+}
+
+/// Reads sources, importing their tracks in db.
+/// When updating, tracks already in db are skipped and those whose file is gone deleted.
+pub(crate) async fn import(
+    db: &db::Instance,
+    sources: Vec<model::Directory>,
+    update: bool,
+) -> Result<()> {
+    let rules = sources
+        .iter()
+        .map(|s| {
+            fs::Rules::new(s)
+                .with_context(|| format!("Invalid patterns for {}", s.directory.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Patterns might have changed since the last time a source was read.
+    for (source, rules) in sources.iter().zip(rules.iter()) {
+        let pruned = prune_excluded(db, source, rules).await?;
+
+        if pruned > 0 {
+            log::info!(
+                "Removed {} tracks excluded by the rules of {}",
+                pruned,
+                source.directory.display()
+            );
+        }
+    }
+
     let mp = MultiProgress::new();
+
+    // Moved files are recognised by their content, make sure older tracks have it.
+    if update {
+        hash_unhashed(db, &mp, None).await?;
+    }
+
     let mut tracks = vec![];
 
     for source in &sources {
         for i in db
-            .track_paths_from_dir(source.clone())
+            .track_paths_from_dir(source)
             .await
             .with_context(|| "Cannot fetch track paths from directory")?
         {
@@ -76,37 +171,45 @@ pub async fn run(args: Args, update: bool) -> Result<()> {
         }
     }
 
-    let tracks_set: hash_set::HashSet<String> = tracks.into_iter().collect();
+    let tracks_set: hash_set::HashSet<model::FilePath> = tracks.into_iter().collect();
+
+    // Sources are read one after the other: each is imported in a transaction, which would
+    // keep the others waiting.
+    let mut res = vec![];
 
-    let res = try_join_all(
-        sources
-            .into_iter()
-            .map(|source| {
-                traverse_and_add_param(&db, &mp, source, {
-                    let tracks_set = tracks_set.clone();
+    for (source, rules) in sources.into_iter().zip(rules.iter()) {
+        res.push(
+            traverse_and_add_param(db, &mp, source, rules.clone(), {
+                let tracks_set = tracks_set.clone();
 
-                    move |path, db, pb| match update {
-                        false => add_dupe_checker(path, db, pb),
-                        true => Ok(!tracks_set.contains(path)),
-                    }
-                })
+                move |path, db, pb| match update {
+                    false => add_dupe_checker(path, db, pb),
+                    true => Ok(tracks_set.contains(path)),
+                }
             })
-            .collect::<Vec<_>>(),
-    )
-    .await?;
+            .await?,
+        );
+    }
 
-    let totals = res.iter().fold((0, 0), |acc, r| (acc.0 + r.0, acc.1 + r.1));
+    let totals = res
+        .iter()
+        .fold((0, 0, 0), |acc, r| (acc.0 + r.0, acc.1 + r.1, acc.2 + r.2));
+
+    if totals.2 > 0 {
+        log::info!("Found {} moved tracks", totals.2);
+    }
 
     match totals.1 {
         0 => log::info!("Imported {} tracks", totals.0),
-        _ => match update {
-            false => log::info!(
-                "Imported {} new tracks, but found {} duplicates",
-                totals.0,
-                totals.1
-            ),
-            true => {}
-        },
+        _ => {
+            if !update {
+                log::info!(
+                    "Imported {} new tracks, but found {} duplicates",
+                    totals.0,
+                    totals.1
+                )
+            }
+        }
     };
 
     if update {
@@ -124,10 +227,12 @@ pub async fn run(args: Args, update: bool) -> Result<()> {
             .await
             .with_context(|| "Cannot create an iterator for existing tracks in database")?;
 
+        let mut gone = vec![];
+
         while let Ok(track) = track_iter.recv().await {
             let track = track?;
 
-            let tp = std::path::Path::new(&track.file_path);
+            let tp = track.file_path.as_path();
 
             match tp.exists() {
                 true => {}
@@ -137,13 +242,15 @@ pub async fn run(args: Args, update: bool) -> Result<()> {
                         track.file_path,
                     ));
 
-                    db.delete(track.id)
-                        .await
-                        .with_context(|| "Cannot delete track from database.")?;
+                    gone.push(track.id);
                 }
             }
         }
 
+        db.delete_tracks(&gone)
+            .await
+            .with_context(|| "Cannot delete tracks from database.")?;
+
         prog.finish();
         mp.remove(&prog);
     }
@@ -151,29 +258,178 @@ pub async fn run(args: Args, update: bool) -> Result<()> {
     Ok(())
 }
 
-fn add_dupe_checker(path: &String, db: &db::Instance, pb: &indicatif::ProgressBar) -> Result<bool> {
+/// Stores content and audio hashes of tracks imported before they were, if their file is still
+/// around.
+/// Files are looked for at their source path, or at their storage path under storage_base for
+/// destination databases: tracks copied by older versions followed the default layout.
+pub(crate) async fn hash_unhashed(
+    db: &db::Instance,
+    mp: &MultiProgress,
+    storage_base: Option<&str>,
+) -> Result<()> {
+    let tracks = db
+        .tracks_without_hashes()
+        .await
+        .with_context(|| "Cannot fetch tracks from database")?;
+
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
+    let prog = mp.add(
+        ProgressBar::new(tracks.len() as u64)
+            .with_message("Hashing tracks imported by an older version...")
+            .with_style(ProgressStyle::default_bar()),
+    );
+
+    for track in tracks {
+        let path = match storage_base {
+            Some(base) => track.storage_path(base, &model::Layout::default()),
+            None => track.file_path.as_path().to_path_buf(),
+        };
+
+        if path.exists() {
+            let size = std::fs::metadata(&path).ok().map(|m| m.len() as i64);
+            let content_hash = track.content_hash.or_else(|| media::content_hash(&path));
+            let audio_hash = track.audio_hash.or_else(|| media::audio_hash(&path));
+
+            db.set_hashes(
+                track.id,
+                size,
+                content_hash.as_deref(),
+                audio_hash.as_deref(),
+            )
+            .await
+            .with_context(|| "Cannot write track data to database")?;
+        }
+
+        prog.inc(1);
+    }
+
+    prog.finish();
+    mp.remove(&prog);
+
+    Ok(())
+}
+
+/// Returns the track whose file isn't on disk anymore and has the same content as the file
+/// properties were read from, if any.
+/// Files whose tags changed along the way are recognised by their audio alone.
+async fn find_moved(
+    tx: &mut db::Transaction,
+    properties: &media::Properties,
+) -> Result<Option<model::Track>> {
+    let mut candidates = vec![];
+
+    if let (Some(size), Some(hash)) = (properties.file_size, &properties.content_hash) {
+        candidates.append(
+            &mut tx
+                .tracks_by_content(size, hash)
+                .await
+                .with_context(|| "Cannot fetch tracks from database")?,
+        );
+    }
+
+    if let Some(hash) = &properties.audio_hash {
+        candidates.append(
+            &mut tx
+                .tracks_by_audio(hash)
+                .await
+                .with_context(|| "Cannot fetch tracks from database")?,
+        );
+    }
+
+    Ok(candidates
+        .into_iter()
+        .find(|t| !t.file_path.as_path().exists()))
+}
+
+/// Deletes from the database all the tracks of directory that rules don't allow anymore.
+pub(crate) async fn prune_excluded(
+    db: &db::Instance,
+    directory: &model::Directory,
+    rules: &fs::Rules,
+) -> Result<u64> {
+    let mut pruned = vec![];
+
+    for track in db
+        .tracks_from_dir(directory)
+        .await
+        .with_context(|| "Cannot fetch tracks from directory")?
+    {
+        if rules.allows(track.file_path.as_path()) {
+            continue;
+        }
+
+        log::debug!("deleting excluded track {}", track.file_path);
+
+        pruned.push(track.id);
+    }
+
+    db.delete_tracks(&pruned)
+        .await
+        .with_context(|| "Cannot delete tracks from database.")?;
+
+    Ok(pruned.len() as u64)
+}
+
+fn add_dupe_checker(
+    path: &model::FilePath,
+    db: &db::Instance,
+    pb: &indicatif::ProgressBar,
+) -> Result<bool> {
     block_on(async {
-        if db.exists(path.clone()).await? {
-            pb.set_message(format!("Found duplicate at {}", path.clone()));
+        if db.exists(path).await? {
+            pb.set_message(format!("Found duplicate at {}", path));
             return Ok(true);
         }
 
-        return Ok(false);
+        Ok(false)
     })
 }
 
 pub(crate) async fn traverse_and_add_param<F>(
     db: &db::Instance,
     mp: &MultiProgress,
-    path: String,
+    directory: model::Directory,
+    rules: fs::Rules,
     dupe_checker: F,
-) -> Result<(u64, u64)>
+) -> Result<(u64, u64, u64)>
 where
-    F: FnOnce(&String, &db::Instance, &indicatif::ProgressBar) -> Result<bool> + Clone,
+    F: FnOnce(&model::FilePath, &db::Instance, &indicatif::ProgressBar) -> Result<bool> + Clone,
 {
-    let paths = fs::traverse(&path).await;
+    // A directory is imported as a whole, or not at all.
+    let mut tx = db
+        .begin()
+        .await
+        .with_context(|| "Cannot start database transaction")?;
+
+    let res = traverse_and_add_in(&mut tx, db, mp, &directory, rules, dupe_checker).await?;
+
+    tx.insert_directory(&directory).await?;
 
-    let base_msg = format!("Reading {}...", path.clone());
+    tx.commit()
+        .await
+        .with_context(|| "Cannot commit imported tracks to database")?;
+
+    Ok(res)
+}
+
+/// Imports the tracks of directory within tx, see traverse_and_add_param.
+pub(crate) async fn traverse_and_add_in<F>(
+    tx: &mut db::Transaction,
+    db: &db::Instance,
+    mp: &MultiProgress,
+    directory: &model::Directory,
+    rules: fs::Rules,
+    dupe_checker: F,
+) -> Result<(u64, u64, u64)>
+where
+    F: FnOnce(&model::FilePath, &db::Instance, &indicatif::ProgressBar) -> Result<bool> + Clone,
+{
+    let paths = fs::traverse(directory, rules).await;
+
+    let base_msg = format!("Reading {}...", directory.directory.display());
 
     let prog = mp.add(
         ProgressBar::new_spinner()
@@ -185,26 +441,56 @@ where
 
     let mut new_tracks = 0;
     let mut duplicate = 0;
+    let mut moved = 0;
 
     while let Ok(p) = paths.recv().await {
-        let p = p?.clone();
+        let p = p?;
 
         let dc = dupe_checker.clone();
-        if dc(&p, db, &prog)? {
+        if dc(&p.as_path().into(), db, &prog)? {
             duplicate += 1;
             continue;
         }
 
-        let tags = audiotags::Tag::new()
-            .read_from_path(p.clone())
-            .with_context(|| format!("Cannot read tags from {}", p.clone()))?;
+        let properties = media::probe(&p);
+
+        // A file that moved keeps its track, so that its identity survives.
+        if let Some(track) = find_moved(tx, &properties).await? {
+            let new_path: model::FilePath = p.as_path().into();
+
+            mp.suspend(|| log::info!("Moved {} to {}", track.file_path, new_path));
+
+            tx.set_file_path(track.id, &new_path)
+                .await
+                .with_context(|| "Cannot write track data to database")?;
+
+            tx.set_hashes(
+                track.id,
+                properties.file_size,
+                properties.content_hash.as_deref(),
+                properties.audio_hash.as_deref(),
+            )
+            .await
+            .with_context(|| "Cannot write track data to database")?;
+
+            moved += 1;
+            continue;
+        }
+
+        let tags = media::read_tags(&p)
+            .with_context(|| format!("Cannot read tags from {}", p.display()))?;
 
-        let mut track: model::Track = model::RawTrack { tags, path: p }.into();
+        let mut track: model::Track = model::RawTrack {
+            tags,
+            properties,
+            path: p,
+        }
+        .into();
         track.file_state = FileState::Copied;
 
-        db.insert_track(&track)
+        tx.insert_track(&track)
             .await
-            .with_context(|| format!("Cannot write track data to database"))?;
+            .with_context(|| "Cannot write track data to database")?;
 
         prog.set_message(format!(
             "{}\nFound track: {} - {}, from {}",
@@ -220,7 +506,5 @@ where
     prog.finish();
     mp.remove(&prog);
 
-    db.insert_directory(path).await?;
-
-    Ok((new_tracks, duplicate))
+    Ok((new_tracks, duplicate, moved))
 }
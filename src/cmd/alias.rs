@@ -0,0 +1,140 @@
+use crate::cmd::*;
+use crate::*;
+use anyhow::{anyhow, Context, Result};
+use clap::{Args as ClapArgs, Subcommand};
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Lists artist aliases.
+    List,
+
+    /// Makes an artist name an alias of another one, e.g. "Beatles, The" of "The Beatles".
+    /// Tracks tagged with the alias are stored, compared and filtered as the artist's.
+    Add(AddArgs),
+
+    /// Removes an artist alias, giving tracks tagged with it their artist back.
+    Remove(RemoveArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct AddArgs {
+    /// Artist name to replace, compared case-insensitively.
+    pub alias: String,
+
+    /// Artist name to replace it with.
+    pub artist: String,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct RemoveArgs {
+    /// Alias to remove, as it was passed to 'alias add'.
+    pub alias: String,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    match args.command {
+        Commands::List => list(&db).await,
+        Commands::Add(add_args) => add(&db, add_args).await,
+        Commands::Remove(remove_args) => remove(&db, remove_args).await,
+    }
+}
+
+async fn list(db: &db::Instance) -> Result<()> {
+    for a in db
+        .aliases()
+        .await
+        .with_context(|| "Cannot fetch artist aliases from database")?
+    {
+        println!("{} -> {}", a.alias, a.artist);
+    }
+
+    Ok(())
+}
+
+async fn add(db: &db::Instance, args: AddArgs) -> Result<()> {
+    // Compare with tags as they're stored.
+    let args = AddArgs {
+        alias: model::nfc(&args.alias),
+        artist: model::nfc(&args.artist),
+    };
+
+    let aliases = db
+        .aliases()
+        .await
+        .with_context(|| "Cannot fetch artist aliases from database")?;
+
+    // Aliases of aliases would depend on the order they're applied in.
+    if let Some(a) = aliases
+        .iter()
+        .find(|a| a.alias.eq_ignore_ascii_case(&args.artist))
+    {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "{} is itself an alias of {}",
+            a.alias, a.artist
+        ))));
+    }
+
+    if let Some(a) = aliases
+        .iter()
+        .find(|a| a.artist.eq_ignore_ascii_case(&args.alias))
+    {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "{} already has aliases, e.g. {}",
+            a.artist, a.alias
+        ))));
+    }
+
+    if args.alias == args.artist {
+        return Err(anyhow!(error::Error::ValidationError(
+            "an artist can't be an alias of itself".to_owned()
+        )));
+    }
+
+    let renamed = db
+        .insert_alias(&model::Alias {
+            alias: args.alias.clone(),
+            artist: args.artist.clone(),
+        })
+        .await
+        .with_context(|| "Cannot store artist alias")?;
+
+    log::info!(
+        "{} is now an alias of {}, renamed {} tracks",
+        args.alias,
+        args.artist,
+        renamed
+    );
+
+    Ok(())
+}
+
+async fn remove(db: &db::Instance, args: RemoveArgs) -> Result<()> {
+    let renamed = db
+        .delete_alias(&model::nfc(&args.alias))
+        .await
+        .with_context(|| "Cannot remove artist alias")?
+        .ok_or_else(|| {
+            anyhow!(error::Error::ValidationError(format!(
+                "{} is not an artist alias",
+                args.alias
+            )))
+        })?;
+
+    log::info!("Removed alias {}, renamed {} tracks", args.alias, renamed);
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use super::error;
 use crate::{db, model};
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Args as ClapArgs;
 
 #[derive(ClapArgs, Debug)]
@@ -11,7 +11,7 @@ pub struct Args {
 
 impl Args {
     pub fn validate(&self) -> Result<(), error::Error> {
-        if let None = self.destination {
+        if self.destination.is_none() {
             return Err(error::Error::ValidationError(
                 "missing destination".to_owned(),
             ));
@@ -28,7 +28,14 @@ pub async fn run(args: Args) -> Result<()> {
 
     let dest_db = db::Instance::new(&dest_dir, true).await?;
 
-    for track in dest_db.tracks_by_state(model::FileState::Copying).await? {
+    let tracks = dest_db.tracks_by_state(model::FileState::Copying).await?;
+
+    // Rows are only deleted once their file is gone: files that can't be deleted keep theirs,
+    // for the next clean to retry. A crash before the copy started leaves no file behind.
+    let mut deleted = vec![];
+    let mut failed = 0;
+
+    for track in tracks {
         log::info!(
             "Deleting non-cleanly copied track: {} - {}, from {}",
             track.title,
@@ -36,10 +43,26 @@ pub async fn run(args: Args) -> Result<()> {
             track.album,
         );
 
-        let storage = track.storage_path(&dest_dir);
-        dest_db.delete(track.id).await?;
-        std::fs::remove_file(storage.clone())
-            .with_context(|| format!("Cannot delete file {}", storage.clone()))?;
+        let storage = track.storage_path(&dest_dir, &model::Layout::default());
+
+        match std::fs::remove_file(&storage) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Cannot delete file {}: {}", storage.display(), e);
+                failed += 1;
+            }
+            _ => deleted.push(track.id),
+        }
+    }
+
+    dest_db
+        .delete_tracks(&deleted)
+        .await
+        .with_context(|| "Cannot delete tracks from destination database")?;
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "Cannot delete {failed} non-cleanly copied tracks, run clean again"
+        ));
     }
 
     Ok(())
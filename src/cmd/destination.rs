@@ -0,0 +1,121 @@
+use crate::{cmd::error, db, model};
+use anyhow::{anyhow, Context, Result};
+use clap::Args as ClapArgs;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Path where tracksync database is stored.
+    #[arg(long)]
+    pub destination: Option<String>,
+
+    /// Group compilations in this folder, by album, instead of in their album artist's folder.
+    #[arg(long, value_name = "FOLDER")]
+    pub compilations: Option<String>,
+
+    /// Store compilations in their album artist's folder, "Various Artists" if they don't have
+    /// one.
+    #[arg(long, default_value_t = false, conflicts_with = "compilations")]
+    pub no_compilations: bool,
+
+    /// Name artist folders after the artist's display name, or after their sort name, e.g.
+    /// "Beatles, The".
+    #[arg(long, value_enum, value_name = "NAMES")]
+    pub folder_names: Option<model::FolderNames>,
+
+    /// Unicode normalisation form of file and folder names, NFD for devices expecting
+    /// decomposed names.
+    #[arg(long, value_enum, value_name = "FORM")]
+    pub path_form: Option<model::PathForm>,
+}
+
+impl Args {
+    pub fn validate(&self) -> Result<()> {
+        if self.destination.is_none() {
+            return Err(anyhow!(error::Error::ValidationError(
+                "missing destination".to_owned(),
+            )));
+        };
+
+        if self
+            .compilations
+            .as_ref()
+            .is_some_and(|c| c.trim().is_empty())
+        {
+            return Err(anyhow!(error::Error::ValidationError(
+                "compilations folder can't be empty".to_owned(),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    args.validate()?;
+
+    let destination = args.destination.unwrap();
+
+    let dest_db = db::Instance::new(&destination, true)
+        .await
+        .with_context(|| "Cannot open destination database instance")?;
+
+    let mut layout = dest_db
+        .layout()
+        .await
+        .with_context(|| "Cannot fetch destination layout")?;
+
+    if args.compilations.is_none()
+        && !args.no_compilations
+        && args.folder_names.is_none()
+        && args.path_form.is_none()
+    {
+        println!(
+            "compilations: {}",
+            layout
+                .compilations
+                .as_deref()
+                .unwrap_or("in album artist folder")
+        );
+        println!(
+            "folder names: {}",
+            match layout.folder_names {
+                model::FolderNames::Display => "display",
+                model::FolderNames::Sort => "sort",
+            }
+        );
+        println!(
+            "path form: {}",
+            match layout.path_form {
+                model::PathForm::Nfc => "nfc",
+                model::PathForm::Nfd => "nfd",
+                model::PathForm::Tagged => "tagged",
+            }
+        );
+
+        return Ok(());
+    }
+
+    if args.compilations.is_some() || args.no_compilations {
+        layout.compilations = args.compilations;
+    }
+
+    if let Some(folder_names) = args.folder_names {
+        layout.folder_names = folder_names;
+    }
+
+    if let Some(path_form) = args.path_form {
+        layout.path_form = path_form;
+    }
+
+    dest_db
+        .set_layout(&layout)
+        .await
+        .with_context(|| "Cannot store destination layout")?;
+
+    log::info!(
+        "Updated layout of {}, tracks already copied stay where they are",
+        destination
+    );
+
+    Ok(())
+}
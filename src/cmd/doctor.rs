@@ -0,0 +1,180 @@
+use crate::{db, model};
+use anyhow::{Context, Result};
+use clap::Args as ClapArgs;
+use std::os::unix::ffi::OsStrExt;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Check this destination's database instead of the local one.
+    #[arg(long)]
+    pub destination: Option<String>,
+
+    /// Fix the problems found, rather than only reporting them.
+    #[arg(long, default_value_t = false)]
+    pub fix: bool,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let (path, is_external) = match &args.destination {
+        Some(destination) => (destination, true),
+        None => (&args.database_path, false),
+    };
+
+    let db = db::Instance::new(path, is_external)
+        .await
+        .with_context(|| "Cannot open database instance")?;
+
+    let mut problems = 0;
+    let mut fixable = 0;
+
+    // Corruption can't be fixed from here: the database has to be restored, or rebuilt.
+    let corruption = db
+        .integrity_check()
+        .await
+        .with_context(|| "Cannot run integrity check")?;
+
+    match corruption.is_empty() {
+        true => println!("integrity: ok"),
+        false => {
+            println!(
+                "integrity: {} problems, restore a backup or run rescan",
+                corruption.len()
+            );
+
+            for c in &corruption {
+                println!("\t{c}");
+            }
+
+            problems += corruption.len();
+        }
+    }
+
+    let state_rows = db
+        .state_rows()
+        .await
+        .with_context(|| "Cannot count state rows")?;
+
+    if state_rows == 1 {
+        println!("state: ok");
+    } else {
+        println!("state: {state_rows} rows instead of 1");
+        problems += 1;
+        fixable += 1;
+
+        if args.fix {
+            db.dedupe_state()
+                .await
+                .with_context(|| "Cannot delete extra state rows")?;
+        }
+    }
+
+    if is_external {
+        // Interrupted copies may have left partial files behind, that's what clean is for.
+        let interrupted = db
+            .tracks_by_state(model::FileState::Copying)
+            .await
+            .with_context(|| "Cannot look for interrupted copies")?
+            .len();
+
+        match interrupted {
+            0 => println!("interrupted copies: ok"),
+            n => {
+                println!("interrupted copies: {n}, run clean to delete them");
+                problems += n;
+            }
+        }
+    } else {
+        let orphans = orphaned_tracks(&db).await?;
+
+        report_tracks("tracks outside of source directories", &orphans);
+        problems += orphans.len();
+        fixable += orphans.len();
+
+        if args.fix {
+            delete_tracks(&db, &orphans).await?;
+        }
+    }
+
+    // Checked last, deletions above keep the index up to date.
+    let fts_intact = db
+        .fts_intact()
+        .await
+        .with_context(|| "Cannot check full-text index")?;
+
+    match fts_intact {
+        true => println!("full-text index: ok"),
+        false => {
+            println!("full-text index: out of sync with tracks");
+            problems += 1;
+            fixable += 1;
+        }
+    }
+
+    // Rebuilding is cheap, and fixes drift the check can't see.
+    if args.fix {
+        db.rebuild_fts()
+            .await
+            .with_context(|| "Cannot rebuild full-text index")?;
+    }
+
+    match (problems, fixable, args.fix) {
+        (0, _, _) => log::info!("No problems found"),
+        (_, 0, _) => log::info!("Found {problems} problems, none can be fixed by doctor"),
+        (_, _, false) => {
+            log::info!("Found {problems} problems, run with --fix to fix {fixable} of them")
+        }
+        (_, _, true) => log::info!("Found {problems} problems, fixed {fixable}"),
+    }
+
+    Ok(())
+}
+
+/// Returns the tracks of the local database that no source directory contains, left behind
+/// by directories removed outside of 'source remove'.
+async fn orphaned_tracks(db: &db::Instance) -> Result<Vec<model::Track>> {
+    let prefixes = db
+        .directories()
+        .await
+        .with_context(|| "Cannot fetch source directories")?
+        .iter()
+        .map(|d| d.prefix())
+        .collect::<Vec<_>>();
+
+    Ok(db
+        .tracks()
+        .await
+        .with_context(|| "Cannot fetch tracks")?
+        .into_iter()
+        .filter(|t| {
+            let path = t.file_path.as_path().as_os_str().as_bytes();
+
+            !prefixes.iter().any(|p| path.starts_with(p))
+        })
+        .collect())
+}
+
+fn report_tracks(problem: &str, tracks: &[model::Track]) {
+    if tracks.is_empty() {
+        println!("{problem}: ok");
+        return;
+    }
+
+    println!("{problem}: {}", tracks.len());
+
+    for t in tracks {
+        println!(
+            "\t{} - {} - {}: {}",
+            t.artist, t.album, t.title, t.file_path
+        );
+    }
+}
+
+async fn delete_tracks(db: &db::Instance, tracks: &[model::Track]) -> Result<()> {
+    db.delete_tracks(&tracks.iter().map(|t| t.id).collect::<Vec<_>>())
+        .await
+        .with_context(|| "Cannot delete tracks")
+}
@@ -1,13 +1,121 @@
-use crate::db;
-use anyhow::{Context, Result};
+use super::error;
+use crate::{db, fingerprint, matching, model};
+use anyhow::{anyhow, Context, Result};
 use clap::Args as ClapArgs;
-use std::collections::{hash_map, hash_set};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{btree_map, btree_set, hash_map, hash_set};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the matching configuration file, looked for in the database directory.
+const CONFIG_FILE: &str = "dupes.json";
 
 #[derive(ClapArgs, Debug)]
 pub struct Args {
     /// Directory in which tracksync will store its local database.
     #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
     pub database_path: String,
+
+    /// Look for tracks and albums that are the same recording, whatever their tags or format,
+    /// by comparing their acoustic fingerprints.
+    /// Tracks are fingerprinted the first time they're compared, which takes a while.
+    #[arg(long, default_value_t = false)]
+    pub acoustic: bool,
+
+    /// Look for tracks appearing more than once, e.g. on both a studio album and a compilation,
+    /// by their artist, title and duration.
+    #[arg(long, default_value_t = false, conflicts_with = "acoustic")]
+    pub tracks: bool,
+
+    /// Only consider tracks to be the same if their audio is identical too.
+    #[arg(long, default_value_t = false, requires = "tracks")]
+    pub content: bool,
+
+    /// Suppress every copy of each duplicate but the best one, so that sync never copies them.
+    /// Without --tracks or --acoustic, albums stored in more than one format are resolved.
+    #[arg(long, default_value_t = false)]
+    pub resolve: bool,
+
+    /// Rule deciding which copy of a duplicate is the best.
+    /// Specify more than one to break ties, in order of importance. Defaults to format, then
+    /// bitrate.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "RULE",
+        action = clap::ArgAction::Append,
+        requires = "resolve"
+    )]
+    pub prefer: Vec<Preference>,
+
+    /// Formats in order of preference, used by the format rule.
+    #[arg(
+        long,
+        value_name = "EXTENSIONS",
+        value_delimiter = ',',
+        default_value = "flac,m4a,ogg,mp3,mp4"
+    )]
+    pub format_order: Vec<String>,
+
+    /// Move the files of suppressed tracks to this directory, under their original path.
+    #[arg(long, value_name = "DIR", requires = "resolve")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Format of the duplicates report.
+    /// json and csv follow the schema documented in the README, and are meant for scripts.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Text,
+        conflicts_with_all = ["resolve", "suppressed", "undo"]
+    )]
+    pub format: Format,
+
+    /// List the tracks suppressed by previous resolutions.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["resolve", "undo"])]
+    pub suppressed: bool,
+
+    /// Revert a previous resolution, moving its file back from quarantine if needed.
+    /// Specify more than one to revert more of them.
+    #[arg(long, value_name = "ID", action = clap::ArgAction::Append, conflicts_with = "resolve")]
+    pub undo: Option<Vec<i64>>,
+
+    /// JSON file with the matching settings and normalisation rules, see the README.
+    /// Defaults to dupes.json in the database directory, if it exists.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Minimum similarity of two album titles, from 0 to 1, for them to be duplicates.
+    #[arg(long, value_name = "SIMILARITY")]
+    pub album_threshold: Option<f64>,
+
+    /// Minimum similarity of two artist names, from 0 to 1, for them to be the same artist.
+    #[arg(long, value_name = "SIMILARITY")]
+    pub artist_threshold: Option<f64>,
+
+    /// Minimum similarity of two fingerprints, from 0 to 1, for them to be the same recording.
+    #[arg(long, value_name = "SIMILARITY")]
+    pub acoustic_threshold: Option<f64>,
+
+    /// Maximum difference in seconds between the durations of two copies of the same track.
+    #[arg(long, value_name = "SECONDS")]
+    pub duration_tolerance: Option<f64>,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Preference {
+    /// Prefer formats listed first in --format-order.
+    Format,
+    /// Prefer higher bitrates.
+    Bitrate,
+    /// Prefer higher sample rates.
+    SampleRate,
+    /// Prefer higher bit depths.
+    BitDepth,
+    /// Prefer albums with more tracks.
+    Tracks,
+    /// Prefer bigger files.
+    Size,
 }
 
 pub async fn run(args: Args) -> Result<()> {
@@ -16,115 +124,208 @@ pub async fn run(args: Args) -> Result<()> {
         .await
         .with_context(|| "Cannot open local database instance")?;
 
-    let albums = db.albums().await.with_context(|| "Cannot fetch albums")?;
+    if args.suppressed {
+        return list_suppressed(&db).await;
+    }
 
-    // (Artist, album keywords)
-    let keywords: Vec<(String, Vec<String>)> = albums
-        .into_iter()
-        .map(|a| {
-            let album = split_after_parenthesis(a.title.clone());
-
-            (
-                clean(a.artist.clone()),
-                album
-                    .split_whitespace()
-                    .into_iter()
-                    .filter_map(|word| {
-                        if word.len() > 3 {
-                            return Some(clean(word.to_owned()));
-                        }
-
-                        return None;
-                    })
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .collect();
+    if let Some(ids) = args.undo {
+        return undo(&db, ids).await;
+    }
 
-    let mut dedup: hash_set::HashSet<(String, String)> = hash_set::HashSet::new();
+    let config = load_config(&args)?;
+    let normaliser = matching::Normaliser::new(&config)
+        .with_context(|| "Invalid qualifier in matching configuration")?;
 
-    for keyword in keywords {
-        if keyword.1.len() == 0 {
-            continue;
+    let resolver = Resolver {
+        preferences: match args.prefer.is_empty() {
+            true => vec![Preference::Format, Preference::Bitrate],
+            false => args.prefer,
+        },
+        format_order: args.format_order,
+        quarantine: args.quarantine,
+    };
+
+    if let Some(quarantine) = &resolver.quarantine {
+        for d in db
+            .directories()
+            .await
+            .with_context(|| "Cannot fetch track directories from database")?
+        {
+            if quarantine.starts_with(&d.directory) {
+                return Err(anyhow!(error::Error::ValidationError(format!(
+                    "quarantine directory can't be inside source {}",
+                    d.directory.display()
+                ))));
+            }
         }
+    }
+
+    let suppressed = db
+        .suppressed_keys()
+        .await
+        .with_context(|| "Cannot fetch suppressed tracks")?;
 
-        log::debug!("looking for: {:?}", keyword.1);
-        // Album name : (track id, format)
-        let albums = db
-            .fuzzy_find_album(&keyword.1)
+    if args.acoustic || args.tracks {
+        let tracks: Vec<model::Track> = db
+            .tracks_by_state(model::FileState::Copied)
             .await
-            .with_context(|| "Could not fuzzy find album")?
+            .with_context(|| "Cannot fetch tracks")?
             .into_iter()
-            .map(|e| {
-                (
-                    split_after_parenthesis(e.1),
-                    (split_after_parenthesis(e.2), e.0),
-                )
-            })
-            .collect::<hash_map::HashMap<_, _>>();
+            .filter(|t| !suppressed.contains(&t.key()))
+            .collect();
 
-        log::debug!("found {} entries", albums.len());
-        if albums.is_empty() {
-            log::debug!("did not find any album for fuzzy query");
-            continue;
-        }
+        let groups = match args.acoustic {
+            true => acoustic_groups(&db, &tracks, config.acoustic_threshold).await?,
+            false => track_groups(
+                &tracks,
+                args.content,
+                &normaliser,
+                config.duration_tolerance,
+            )
+            .into_iter()
+            .map(|g| (g, 1.0))
+            .collect(),
+        };
 
-        if albums.len() <= 1 {
-            log::debug!("found just one album for query, likely no dupe");
-            continue;
+        if args.resolve {
+            let groups = groups
+                .into_iter()
+                .map(|(g, _)| g.into_iter().map(|t| vec![t]).collect())
+                .collect();
+
+            return resolver.resolve(&db, groups).await;
         }
 
-        let album_names: Vec<String> = albums.keys().into_iter().cloned().collect();
+        match args.format {
+            Format::Text if args.acoustic => print_acoustic_groups(&groups, &tracks),
+            Format::Text => {
+                let groups: Vec<_> = groups.into_iter().map(|(g, _)| g).collect();
+                print_track_groups(&groups)
+            }
+            format => {
+                let report = match args.acoustic {
+                    true => acoustic_report(&groups, &tracks),
+                    false => groups
+                        .iter()
+                        .map(|(g, confidence)| Group {
+                            kind: Kind::Track,
+                            // Names are compared loosely, audio exactly.
+                            match_type: match args.content {
+                                true => MatchType::Exact,
+                                false => MatchType::Fuzzy,
+                            },
+                            confidence: *confidence,
+                            members: g.iter().map(Member::from).collect(),
+                        })
+                        .collect(),
+                };
 
-        for (album_name, metadata) in &albums {
-            let mut options = album_names.clone();
-            if let Some(pos) = options.iter().position(|x| **x == *album_name) {
-                options.remove(pos);
+                write_report(&report, format)?
             }
+        };
 
-            let (format, _) = metadata;
+        return Ok(());
+    }
 
-            match similar_string::find_best_similarity(album_name.clone(), &options) {
-                Some(res) => {
-                    let (dupe_name, score) = res;
-                    let an_trim = album_name.trim().to_string();
+    if args.resolve {
+        return resolve_albums(&db, &resolver, &suppressed).await;
+    }
 
-                    match dedup.get(&(an_trim.clone(), dupe_name.clone())) {
-                        Some(_) => continue,
-                        None => {
-                            dedup.insert((an_trim.clone(), dupe_name.clone()));
-                        }
-                    };
+    let groups = album_groups(&db, &config, &normaliser).await?;
 
-                    if res.1 < 0.6 {
-                        continue;
-                    }
+    match args.format {
+        Format::Text => print_album_groups(&groups),
+        format => write_report(&groups, format)?,
+    };
 
-                    let dupe_meta = albums.get(&dupe_name).unwrap();
+    Ok(())
+}
 
-                    let dupe_path = db
-                        .tracks_by_id(vec![dupe_meta.1.clone()])
+/// Finds albums that look alike by their artist and title, and albums stored in more than one
+/// format.
+async fn album_groups(
+    db: &db::Instance,
+    config: &matching::Config,
+    normaliser: &matching::Normaliser,
+) -> Result<Vec<Group>> {
+    let mut albums: Vec<(String, String)> = db
+        .albums()
+        .await
+        .with_context(|| "Cannot fetch albums")?
+        .into_iter()
+        .map(|a| (a.artist, a.title))
+        .collect();
+
+    // The same album in more than one format is an exact match, found below.
+    albums.sort();
+    albums.dedup();
+
+    // Albums are only compared to those of the same artist, spelled more or less the same way.
+    let names: Vec<String> = albums
+        .iter()
+        .map(|(artist, _)| normaliser.normalise(artist))
+        .collect::<btree_set::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let clusters: hash_map::HashMap<&String, usize> = names
+        .iter()
+        .zip(matching::cluster(&names, config.artist_threshold))
+        .collect();
+
+    // (Artist, title, normalised title)
+    type Album = (String, String, String);
+    let mut artists: btree_map::BTreeMap<usize, Vec<Album>> = btree_map::BTreeMap::new();
+
+    for (artist, title) in albums {
+        let cluster = clusters[&normaliser.normalise(&artist)];
+        let normalised = normaliser.normalise(&title);
+
+        artists
+            .entry(cluster)
+            .or_default()
+            .push((artist, title, normalised));
+    }
+
+    let mut groups = vec![];
+
+    for albums in artists.into_values() {
+        for (n, a) in albums.iter().enumerate() {
+            for b in &albums[n + 1..] {
+                let score = matching::similarity(&a.2, &b.2);
+
+                if score < config.album_threshold {
+                    continue;
+                }
+
+                let mut members = vec![];
+
+                for (artist, title, _) in [a, b] {
+                    let paths = db
+                        .album_paths(title, artist)
                         .await
-                        .with_context(|| "Cannot fetch dupe track")?
-                        .first()
-                        .unwrap()
-                        .file_path
-                        .clone();
-
-                    // parse dupe path and get the directory containing it
-                    let dupe_path = std::path::Path::new(&dupe_path).parent().unwrap();
-                    let dupe_path = dupe_path.to_str().unwrap();
-
-                    println!(
-                        "Maybe duplicate:\n\t\"{}\": \"{}\" (confidence: {:.1}%) \n\tat path {}, format {}",
-                        album_name.trim(),
-                        dupe_name.trim(),
-                        score * (100 as f64),
-                        dupe_path,
-                        format,
-                    );
+                        .with_context(|| "Cannot fetch duplicate album")?;
+
+                    members.push(Member {
+                        album: title.clone(),
+                        artist: artist.clone(),
+                        title: None,
+                        format: paths
+                            .iter()
+                            .map(|(_, ext)| ext.as_str())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                        directory: paths.first().map(|(p, _)| p.clone()).unwrap_or_default(),
+                        file: None,
+                    });
                 }
-                None => {}
+
+                groups.push(Group {
+                    kind: Kind::Album,
+                    match_type: MatchType::Fuzzy,
+                    confidence: score,
+                    members,
+                });
             }
         }
     }
@@ -135,45 +336,875 @@ pub async fn run(args: Args) -> Result<()> {
         .with_context(|| "Cannot fetch duplicate albums")?;
 
     for sd in std_duplicates {
-        let (album, amt) = sd;
+        let (album, _) = sd;
         let paths = db
             .album_paths(&album.title, &album.artist)
             .await
             .with_context(|| "Cannot fetch duplicate album")?;
 
-        println!(r#"Found "{}" in {} formats:"#, album.title, amt);
+        groups.push(Group {
+            kind: Kind::Album,
+            match_type: MatchType::Exact,
+            confidence: 1.0,
+            members: paths
+                .into_iter()
+                .map(|(path, ext)| Member {
+                    album: album.title.clone(),
+                    artist: album.artist.clone(),
+                    title: None,
+                    format: ext,
+                    directory: path,
+                    file: None,
+                })
+                .collect(),
+        });
+    }
+
+    Ok(groups)
+}
+
+fn print_album_groups(groups: &[Group]) {
+    for g in groups {
+        match g.match_type {
+            MatchType::Fuzzy => println!(
+                "Maybe duplicate:\n\t\"{}\": \"{}\" (confidence: {:.1}%) \n\tat path {}, format {}",
+                g.members[0].album,
+                g.members[1].album,
+                g.confidence * 100_f64,
+                g.members[1].directory,
+                g.members[0].format,
+            ),
+            _ => {
+                println!(
+                    r#"Found "{}" in {} formats:"#,
+                    g.members[0].album,
+                    g.members.len()
+                );
 
-        for ele in paths {
-            let (path, ext) = ele;
-            println!("\t {}: {}", path, ext);
+                for m in &g.members {
+                    println!("\t {}: {}", m.directory, m.format);
+                }
+            }
+        }
+    }
+}
+
+/// Groups tracks appearing more than once, by their artist, title and duration and, if content
+/// is set, their audio.
+fn track_groups(
+    tracks: &[model::Track],
+    content: bool,
+    normaliser: &matching::Normaliser,
+    duration_tolerance: f64,
+) -> Vec<Vec<model::Track>> {
+    let mut by_name: hash_map::HashMap<(String, String, Option<String>), Vec<model::Track>> =
+        hash_map::HashMap::new();
+
+    for t in tracks {
+        let artist = t.track_artist.clone().unwrap_or(t.artist.clone());
+        let hash = match content {
+            true => t.audio_hash.clone(),
+            false => None,
+        };
+
+        by_name
+            .entry((
+                normaliser.normalise(&artist),
+                normaliser.normalise(&t.title),
+                hash,
+            ))
+            .or_default()
+            .push(t.clone());
+    }
+
+    let mut groups = vec![];
+
+    for mut tracks in by_name.into_values() {
+        if tracks.len() < 2 {
+            continue;
+        }
+
+        // Same name but very different durations make different versions of a song, e.g. a
+        // radio edit and an extended mix.
+        tracks.sort_by(|a, b| {
+            a.duration
+                .unwrap_or_default()
+                .total_cmp(&b.duration.unwrap_or_default())
+        });
+
+        let mut group: Vec<model::Track> = vec![];
+
+        for t in tracks {
+            if let Some(last) = group.last() {
+                let delta =
+                    (t.duration.unwrap_or_default() - last.duration.unwrap_or_default()).abs();
+
+                if delta > duration_tolerance {
+                    groups.push(std::mem::take(&mut group));
+                }
+            }
+
+            group.push(t);
+        }
+
+        groups.push(group);
+    }
+
+    groups.retain(|g| g.len() > 1);
+    groups.sort_by(|a, b| (&a[0].artist, &a[0].title).cmp(&(&b[0].artist, &b[0].title)));
+
+    groups
+}
+
+fn print_track_groups(groups: &[Vec<model::Track>]) {
+    for g in groups {
+        let first = &g[0];
+
+        println!(
+            r#"Found "{}" by {} {} times:"#,
+            first.title,
+            first.track_artist.as_ref().unwrap_or(&first.artist),
+            g.len()
+        );
+
+        for t in g {
+            let duration = t.duration.unwrap_or_default().round() as i64;
+
+            println!(
+                "\t{} by {} [{}, {}:{:02}]: {}",
+                t.album,
+                t.artist,
+                t.extension,
+                duration / 60,
+                duration % 60,
+                t.file_path
+            );
+        }
+    }
+}
+
+/// Groups tracks that are the same recording, by their acoustic fingerprint.
+/// Each group comes with the lowest similarity between its members' fingerprints.
+async fn acoustic_groups(
+    db: &db::Instance,
+    tracks: &[model::Track],
+    threshold: f64,
+) -> Result<Vec<(Vec<model::Track>, f64)>> {
+    fingerprint_missing(db).await?;
+
+    let (hashes, fingerprints): (Vec<String>, Vec<Vec<u32>>) = db
+        .fingerprints()
+        .await
+        .with_context(|| "Cannot fetch fingerprints")?
+        .into_iter()
+        .unzip();
+
+    let mut by_hash: hash_map::HashMap<&String, Vec<model::Track>> = hash_map::HashMap::new();
+
+    for t in tracks {
+        if let Some(hash) = &t.audio_hash {
+            by_hash.entry(hash).or_default().push(t.clone());
+        }
+    }
+
+    let mut groups: Vec<(Vec<model::Track>, f64)> = vec![];
+    let mut grouped: hash_set::HashSet<&String> = hash_set::HashSet::new();
+
+    for g in fingerprint::group(&fingerprints, threshold) {
+        let members: Vec<model::Track> = g
+            .iter()
+            .flat_map(|i| by_hash.get(&hashes[*i]).cloned().unwrap_or_default())
+            .collect();
+
+        grouped.extend(g.iter().map(|i| &hashes[*i]));
+
+        if members.len() > 1 {
+            let mut lowest: f64 = 1.0;
+
+            for (n, a) in g.iter().enumerate() {
+                for b in &g[n + 1..] {
+                    lowest = lowest.min(fingerprint::similarity(
+                        &fingerprints[*a],
+                        &fingerprints[*b],
+                    ));
+                }
+            }
+
+            groups.push((members, lowest));
+        }
+    }
+
+    // Identical copies of a file share their fingerprint, and make a group of their own.
+    for (hash, ts) in by_hash {
+        if ts.len() > 1 && !grouped.contains(hash) {
+            groups.push((ts, 1.0));
+        }
+    }
+
+    Ok(groups)
+}
+
+/// An album, told apart by the directory it's in too: the same album might be stored twice
+/// with identical tags.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct AlbumKey {
+    artist: String,
+    album: String,
+    directory: String,
+}
+
+impl From<&model::Track> for AlbumKey {
+    fn from(t: &model::Track) -> Self {
+        Self {
+            artist: t.artist.clone(),
+            album: t.album.clone(),
+            directory: directory_of(t),
+        }
+    }
+}
+
+/// Pairs of albums sharing at least half of their tracks, by recording, along with how many
+/// tracks they share and how many the smallest of the two has.
+fn acoustic_albums(
+    groups: &[(Vec<model::Track>, f64)],
+    tracks: &[model::Track],
+) -> Vec<(AlbumKey, AlbumKey, usize, usize)> {
+    let mut album_sizes: hash_map::HashMap<AlbumKey, usize> = hash_map::HashMap::new();
+
+    for t in tracks {
+        *album_sizes.entry(t.into()).or_default() += 1;
+    }
+
+    let mut album_pairs: hash_map::HashMap<_, usize> = hash_map::HashMap::new();
+
+    for (g, _) in groups {
+        let albums: hash_set::HashSet<AlbumKey> = g.iter().map(AlbumKey::from).collect();
+        let mut albums: Vec<_> = albums.into_iter().collect();
+        albums.sort();
+
+        for (n, a) in albums.iter().enumerate() {
+            for b in &albums[n + 1..] {
+                *album_pairs.entry((a.clone(), b.clone())).or_default() += 1;
+            }
+        }
+    }
+
+    let mut album_pairs: Vec<_> = album_pairs.into_iter().collect();
+    album_pairs.sort();
+
+    album_pairs
+        .into_iter()
+        .filter_map(|((a, b), matching)| {
+            let smallest = album_sizes[&a].min(album_sizes[&b]);
+
+            // A couple of shared tracks make a compilation, not a duplicate album.
+            if matching * 2 < smallest || matching < 2 {
+                return None;
+            }
+
+            Some((a, b, matching, smallest))
+        })
+        .collect()
+}
+
+fn print_acoustic_groups(groups: &[(Vec<model::Track>, f64)], tracks: &[model::Track]) {
+    for (g, _) in groups {
+        println!("Same recording:");
+
+        for t in g {
+            println!(
+                "\t{} - {} - {} [{}]: {}",
+                t.artist, t.album, t.title, t.extension, t.file_path
+            );
+        }
+    }
+
+    for (a, b, matching, smallest) in acoustic_albums(groups, tracks) {
+        println!(
+            "Same album: {} of {} tracks match\n\t\"{}\" by {} at {}\n\t\"{}\" by {} at {}",
+            matching, smallest, a.album, a.artist, a.directory, b.album, b.artist, b.directory,
+        );
+    }
+}
+
+/// Converts acoustic groups to report groups, recordings first and albums then.
+fn acoustic_report(groups: &[(Vec<model::Track>, f64)], tracks: &[model::Track]) -> Vec<Group> {
+    let mut formats: hash_map::HashMap<AlbumKey, hash_set::HashSet<&str>> =
+        hash_map::HashMap::new();
+
+    for t in tracks {
+        formats
+            .entry(t.into())
+            .or_default()
+            .insert(t.extension.as_str());
+    }
+
+    let mut report: Vec<Group> = groups
+        .iter()
+        .map(|(g, confidence)| Group {
+            kind: Kind::Track,
+            match_type: MatchType::Acoustic,
+            confidence: *confidence,
+            members: g.iter().map(Member::from).collect(),
+        })
+        .collect();
+
+    for (a, b, matching, smallest) in acoustic_albums(groups, tracks) {
+        let members = [a, b]
+            .into_iter()
+            .map(|k| {
+                let mut format: Vec<&str> = formats[&k].iter().cloned().collect();
+                format.sort();
+
+                Member {
+                    format: format.join(","),
+                    album: k.album,
+                    artist: k.artist,
+                    title: None,
+                    directory: k.directory,
+                    file: None,
+                }
+            })
+            .collect();
+
+        report.push(Group {
+            kind: Kind::Album,
+            match_type: MatchType::Acoustic,
+            confidence: matching as f64 / smallest as f64,
+            members,
+        });
+    }
+
+    report
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+/// A set of albums or tracks found to be duplicates of each other, as written to reports.
+#[derive(serde::Serialize, Debug)]
+struct Group {
+    kind: Kind,
+    match_type: MatchType,
+    /// From 0 to 1: title similarity for fuzzy matches, fingerprint similarity or share of
+    /// matching tracks for acoustic ones, 1 for exact ones.
+    confidence: f64,
+    members: Vec<Member>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Album,
+    Track,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum MatchType {
+    /// Same tags, or same audio.
+    Exact,
+    /// Similar titles.
+    Fuzzy,
+    /// Same recording, by acoustic fingerprint.
+    Acoustic,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Member {
+    album: String,
+    artist: String,
+    /// Track title, missing for albums.
+    title: Option<String>,
+    format: String,
+    directory: String,
+    /// Track path, missing for albums.
+    file: Option<String>,
+}
+
+impl From<&model::Track> for Member {
+    fn from(t: &model::Track) -> Self {
+        Self {
+            album: t.album.clone(),
+            artist: t.artist.clone(),
+            title: Some(t.title.clone()),
+            format: t.extension.clone(),
+            directory: directory_of(t),
+            file: Some(t.file_path.to_string()),
+        }
+    }
+}
+
+/// A report line in csv format: one per group member.
+#[derive(serde::Serialize)]
+struct Row<'a> {
+    group: usize,
+    kind: Kind,
+    match_type: MatchType,
+    confidence: f64,
+    album: &'a str,
+    artist: &'a str,
+    title: Option<&'a str>,
+    format: &'a str,
+    directory: &'a str,
+    file: Option<&'a str>,
+}
+
+fn directory_of(t: &model::Track) -> String {
+    t.file_path
+        .as_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .display()
+        .to_string()
+}
+
+fn write_report(groups: &[Group], format: Format) -> Result<()> {
+    match format {
+        Format::Text => Ok(()),
+        Format::Json => write_json(groups),
+        Format::Csv => write_csv(groups),
+    }
+    .with_context(|| "Cannot write report")
+}
+
+fn write_json(groups: &[Group]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    serde_json::to_writer_pretty(&mut stdout, groups)?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+fn write_csv(groups: &[Group]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+
+    for (n, g) in groups.iter().enumerate() {
+        for m in &g.members {
+            writer.serialize(Row {
+                group: n + 1,
+                kind: g.kind,
+                match_type: g.match_type,
+                confidence: g.confidence,
+                album: &m.album,
+                artist: &m.artist,
+                title: m.title.as_deref(),
+                format: &m.format,
+                directory: &m.directory,
+                file: m.file.as_deref(),
+            })?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads the matching configuration from the file given by args or, if there's none, from the
+/// database directory, overriding it with the thresholds given by args.
+fn load_config(args: &Args) -> Result<matching::Config> {
+    let path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => Some(Path::new(&args.database_path).join(CONFIG_FILE)).filter(|p| p.exists()),
+    };
+
+    let mut config = match path {
+        Some(path) => {
+            let raw = std::fs::read_to_string(&path)
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+
+            serde_json::from_str(&raw)
+                .with_context(|| format!("Invalid matching configuration in {}", path.display()))?
+        }
+        None => matching::Config::default(),
+    };
+
+    if let Some(t) = args.album_threshold {
+        config.album_threshold = t;
+    }
+
+    if let Some(t) = args.artist_threshold {
+        config.artist_threshold = t;
+    }
+
+    if let Some(t) = args.acoustic_threshold {
+        config.acoustic_threshold = t;
+    }
+
+    if let Some(t) = args.duration_tolerance {
+        config.duration_tolerance = t;
+    }
+
+    for t in [
+        config.album_threshold,
+        config.artist_threshold,
+        config.acoustic_threshold,
+    ] {
+        if !(0.0..=1.0).contains(&t) {
+            return Err(anyhow!(error::Error::ValidationError(format!(
+                "similarity thresholds must be between 0 and 1, got {t}"
+            ))));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Decides which copy of a duplicate is the best one, suppressing the others.
+struct Resolver {
+    preferences: Vec<Preference>,
+    format_order: Vec<String>,
+    quarantine: Option<PathBuf>,
+}
+
+impl Resolver {
+    /// Scores a copy of a duplicate, made of one or more tracks, by preference.
+    /// The higher the score, the better.
+    fn score(&self, preference: &Preference, copy: &[model::Track]) -> i64 {
+        let min = |f: fn(&model::Track) -> Option<i64>| {
+            copy.iter()
+                .map(|t| f(t).unwrap_or_default())
+                .min()
+                .unwrap_or_default()
+        };
+
+        match preference {
+            Preference::Format => {
+                let worst = copy
+                    .iter()
+                    .map(|t| {
+                        self.format_order
+                            .iter()
+                            .position(|f| f.eq_ignore_ascii_case(&t.extension))
+                            .unwrap_or(self.format_order.len())
+                    })
+                    .max()
+                    .unwrap_or_default();
+
+                -(worst as i64)
+            }
+            Preference::Bitrate => {
+                copy.iter()
+                    .map(|t| t.bitrate.unwrap_or_default())
+                    .sum::<i64>()
+                    / copy.len().max(1) as i64
+            }
+            Preference::SampleRate => min(|t| t.sample_rate),
+            Preference::BitDepth => min(|t| t.bit_depth),
+            Preference::Tracks => copy.len() as i64,
+            Preference::Size => copy.iter().map(|t| t.file_size.unwrap_or_default()).sum(),
+        }
+    }
+
+    fn scores(&self, copy: &[model::Track]) -> Vec<i64> {
+        self.preferences
+            .iter()
+            .map(|p| self.score(p, copy))
+            .collect()
+    }
+
+    /// Explains why winner has been preferred over loser, given their scores.
+    fn reason(
+        &self,
+        winner: &[model::Track],
+        loser: &[model::Track],
+        w: &[i64],
+        l: &[i64],
+    ) -> String {
+        let (preference, w, l) = match self
+            .preferences
+            .iter()
+            .zip(w.iter().zip(l.iter()))
+            .find(|(_, (w, l))| w != l)
+        {
+            Some((p, (w, l))) => (p, w, l),
+            None => return "identical copy".to_owned(),
+        };
+
+        let extension = |copy: &[model::Track]| {
+            copy.first()
+                .map(|t| t.extension.clone())
+                .unwrap_or_default()
+        };
+
+        match preference {
+            Preference::Format => {
+                format!("{} preferred over {}", extension(winner), extension(loser))
+            }
+            Preference::Bitrate => format!("higher bitrate, {} over {} kbit/s", w, l),
+            Preference::SampleRate => format!("higher sample rate, {} over {} Hz", w, l),
+            Preference::BitDepth => format!("higher bit depth, {} over {} bits", w, l),
+            Preference::Tracks => format!("more tracks, {} over {}", w, l),
+            Preference::Size => format!("bigger files, {} over {} bytes", w, l),
+        }
+    }
+
+    /// Keeps the best copy of each group of duplicates, suppressing the others.
+    /// Each copy is made of one or more tracks, e.g. a whole album.
+    async fn resolve(&self, db: &db::Instance, groups: Vec<Vec<Vec<model::Track>>>) -> Result<()> {
+        let mut suppressed = 0;
+
+        for copies in groups {
+            let scores: Vec<Vec<i64>> = copies.iter().map(|c| self.scores(c)).collect();
+
+            let best = match scores.iter().max() {
+                Some(best) => best,
+                None => continue,
+            };
+
+            let winners: Vec<usize> = (0..copies.len()).filter(|i| scores[*i] == *best).collect();
+
+            if winners.len() > 1 {
+                log::info!(
+                    "Cannot decide between {}, skipping",
+                    winners
+                        .iter()
+                        .map(|i| location(&copies[*i]).to_string())
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                );
+
+                continue;
+            }
+
+            let winner = &copies[winners[0]];
+            let winner_keys: hash_set::HashSet<String> = winner.iter().map(|t| t.key()).collect();
+            let kept_path = location(winner);
+
+            for (i, copy) in copies.iter().enumerate() {
+                if i == winners[0] {
+                    continue;
+                }
+
+                let reason = self.reason(winner, copy, best, &scores[i]);
+
+                for t in copy {
+                    // Identical copies of the winner are copied only once anyway.
+                    if winner_keys.contains(&t.key()) {
+                        continue;
+                    }
+
+                    self.suppress(db, t, &kept_path, &reason).await?;
+                    suppressed += 1;
+                }
+            }
+        }
+
+        log::info!("Suppressed {} tracks", suppressed);
+
+        Ok(())
+    }
+
+    /// Records that track is suppressed, moving its file to quarantine if there's one.
+    /// The suppression is only committed once the file is moved, and the file moved back if
+    /// committing fails: a quarantined file always has a suppression recording where it was.
+    async fn suppress(
+        &self,
+        db: &db::Instance,
+        track: &model::Track,
+        kept_path: &model::FilePath,
+        reason: &str,
+    ) -> Result<()> {
+        let from = track.file_path.as_path();
+        let quarantine_path = self
+            .quarantine
+            .as_ref()
+            .map(|quarantine| quarantine.join(from.strip_prefix("/").unwrap_or(from)));
+
+        log::info!("Suppressing {}: {}", track.file_path, reason);
+
+        let mut tx = db
+            .begin()
+            .await
+            .with_context(|| "Cannot start database transaction")?;
+
+        tx.insert_suppression(&model::Suppression {
+            track_key: track.key(),
+            file_path: track.file_path.clone(),
+            kept_path: kept_path.clone(),
+            reason: reason.to_owned(),
+            quarantine_path: quarantine_path.as_deref().map(|p| p.into()),
+            ..Default::default()
+        })
+        .await
+        .with_context(|| "Cannot store suppressed track")?;
+
+        if let Some(to) = &quarantine_path {
+            move_file(from, to)?;
+        }
+
+        if let Err(e) = tx.commit().await {
+            if let Some(to) = &quarantine_path {
+                move_file(to, from)?;
+            }
+
+            return Err(e).with_context(|| "Cannot store suppressed track");
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns where a copy of a duplicate is: the path of its track, or the directory holding them
+/// if it's more than one.
+fn location(copy: &[model::Track]) -> model::FilePath {
+    match copy {
+        [t] => t.file_path.clone(),
+        _ => copy
+            .first()
+            .and_then(|t| t.file_path.as_path().parent())
+            .unwrap_or(Path::new(""))
+            .into(),
+    }
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    let parent = to
+        .parent()
+        .with_context(|| format!("Cannot obtain parent directory of {}", to.display()))?;
+
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("Cannot create directory tree {}", parent.display()))?;
+
+    // Renaming doesn't work across file systems, copy and delete instead.
+    if std::fs::rename(from, to).is_err() {
+        fs_extra::file::move_file(from, to, &fs_extra::file::CopyOptions::new())
+            .map_err(error::Error::CopyError)
+            .with_context(|| format!("Cannot move {} to {}", from.display(), to.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Resolves albums stored in more than one format.
+async fn resolve_albums(
+    db: &db::Instance,
+    resolver: &Resolver,
+    suppressed: &hash_set::HashSet<String>,
+) -> Result<()> {
+    let mut groups = vec![];
+
+    for (album, _) in db
+        .duplicate_albums()
+        .await
+        .with_context(|| "Cannot fetch duplicate albums")?
+    {
+        let mut by_format: btree_map::BTreeMap<String, Vec<model::Track>> =
+            btree_map::BTreeMap::new();
+
+        for t in db
+            .album_tracks(&album.title, &album.artist)
+            .await
+            .with_context(|| "Cannot fetch duplicate album")?
+        {
+            if !suppressed.contains(&t.key()) {
+                by_format.entry(t.extension.clone()).or_default().push(t);
+            }
+        }
+
+        if by_format.len() > 1 {
+            groups.push(by_format.into_values().collect());
+        }
+    }
+
+    resolver.resolve(db, groups).await
+}
+
+async fn list_suppressed(db: &db::Instance) -> Result<()> {
+    for s in db
+        .suppressions()
+        .await
+        .with_context(|| "Cannot fetch suppressed tracks")?
+    {
+        println!("{} [{}]: {}", s.id, s.created_at, s.file_path);
+        println!("\treason: {}", s.reason);
+        println!("\tkept: {}", s.kept_path);
+
+        if let Some(q) = s.quarantine_path {
+            println!("\tquarantined at: {}", q);
         }
     }
 
     Ok(())
 }
 
-fn clean(s: String) -> String {
-    s.replace("(", " ")
-        .replace(")", " ")
-        .replace(":", " ")
-        .replace(r#"'"#, r#" "#)
-        .replace(".", " ")
-        .replace("<", " ")
-        .replace(">", " ")
-        .replace(",", " ")
-        .replace("-", " ")
-        .replace("[", " ")
-        .replace("]", " ")
-        .replace("?", " ")
-        .replace("/", " ")
-        .replace("!", " ")
+async fn undo(db: &db::Instance, ids: Vec<i64>) -> Result<()> {
+    for id in ids {
+        let s = db
+            .suppression(id)
+            .await
+            .with_context(|| "Cannot fetch suppressed tracks")?
+            .ok_or_else(|| {
+                anyhow!(error::Error::ValidationError(format!(
+                    "no suppressed track with id {id}"
+                )))
+            })?;
+
+        if let Some(q) = &s.quarantine_path {
+            move_file(q.as_path(), s.file_path.as_path())?;
+        }
+
+        db.delete_suppression(id)
+            .await
+            .with_context(|| "Cannot delete suppressed track")?;
+
+        log::info!("Restored {}", s.file_path);
+    }
+
+    Ok(())
 }
 
-fn split_after_parenthesis(s: String) -> String {
-    let split: Vec<(usize, char)> = s.char_indices().filter(|e| e.1 == '(').collect();
+/// Computes the fingerprint of every track that doesn't have one yet.
+async fn fingerprint_missing(db: &db::Instance) -> Result<()> {
+    let tracks = db
+        .tracks_without_fingerprint()
+        .await
+        .with_context(|| "Cannot fetch tracks")?;
+
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
+    let prog = ProgressBar::new(tracks.len() as u64)
+        .with_style(ProgressStyle::default_bar())
+        .with_message("Fingerprinting tracks...");
+
+    for t in tracks {
+        let hash = match &t.audio_hash {
+            Some(hash) => hash.clone(),
+            None => continue,
+        };
+
+        let path = t.file_path.as_path().to_path_buf();
 
-    match split.len() {
-        0 => s,
-        _ => s.clone().split_at(split.first().unwrap().0).0.to_string(),
+        if !path.exists() {
+            prog.inc(1);
+            continue;
+        }
+
+        let fingerprint =
+            match async_std::task::spawn_blocking(move || fingerprint::compute(&path)).await {
+                Ok(fp) => fp,
+                Err(e) => {
+                    prog.suspend(|| log::warn!("Cannot fingerprint {}: {}", t.file_path, e));
+                    vec![]
+                }
+            };
+
+        db.insert_fingerprint(&hash, &fingerprint)
+            .await
+            .with_context(|| "Cannot store fingerprint")?;
+
+        prog.inc(1);
     }
+
+    prog.finish_and_clear();
+
+    Ok(())
 }
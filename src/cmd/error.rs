@@ -1,6 +1,7 @@
 use crate::*;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     ValidationError(String),
     DatabaseError(sqlx::Error),
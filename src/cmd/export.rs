@@ -0,0 +1,80 @@
+use crate::{cmd::error, db, dump};
+use anyhow::{anyhow, Context, Result};
+use clap::Args as ClapArgs;
+use std::io::Write;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Export this destination's database instead of the local one.
+    #[arg(long)]
+    pub destination: Option<String>,
+
+    /// Dump format: a single JSON document, or a directory of CSV files, one per table.
+    #[arg(long, value_enum, default_value = "json")]
+    pub format: Format,
+
+    /// File to write JSON to, standard output if missing; directory to write CSV files to.
+    #[arg(short, long)]
+    pub output: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Args {
+    pub fn validate(&self) -> Result<()> {
+        if matches!(self.format, Format::Csv) && self.output.is_none() {
+            return Err(anyhow!(error::Error::ValidationError(
+                "CSV dumps need an output directory".to_owned(),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    args.validate()?;
+
+    let (path, external) = match &args.destination {
+        Some(destination) => (destination, true),
+        None => (&args.database_path, false),
+    };
+
+    let db = db::Instance::new(path, external)
+        .await
+        .with_context(|| "Cannot open database instance")?;
+
+    let dump = dump::Dump::read(&db, external).await?;
+
+    match (args.format, args.output) {
+        (Format::Json, None) => {
+            let mut stdout = std::io::stdout().lock();
+
+            dump.to_json(&mut stdout)?;
+            writeln!(stdout)?;
+        }
+        (Format::Json, Some(output)) => {
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("Cannot create {output}"))?;
+
+            let mut writer = std::io::BufWriter::new(file);
+
+            dump.to_json(&mut writer)?;
+            writeln!(writer)?;
+        }
+        (Format::Csv, Some(output)) => dump.to_csv(std::path::Path::new(&output))?,
+        (Format::Csv, None) => unreachable!(),
+    }
+
+    log::info!("Exported {} tracks", dump.tracks.len());
+
+    Ok(())
+}
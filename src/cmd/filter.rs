@@ -2,7 +2,7 @@ use crate::{cmd::error, db, filter};
 use anyhow::{anyhow, Context, Result};
 use clap::Args as ClapArgs;
 
-const DEFAULT_FILTER: &'static str = include_str!("default_filter.rhai");
+const DEFAULT_FILTER: &str = include_str!("default_filter.rhai");
 
 #[derive(ClapArgs)]
 pub struct Args {
@@ -23,7 +23,7 @@ pub struct Args {
 
 impl Args {
     pub fn validate(&self) -> Result<()> {
-        if let None = self.destination {
+        if self.destination.is_none() {
             return Err(anyhow!(error::Error::ValidationError(
                 "missing destination".to_owned(),
             )));
@@ -63,8 +63,8 @@ pub async fn run(args: Args) -> Result<()> {
 
     filter::check(vec![res.clone()]).with_context(|| "Filtering script check failed")?;
 
-    Ok(dest_db
+    dest_db
         .set_filter(res)
         .await
-        .with_context(|| "Cannot store filter")?)
+        .with_context(|| "Cannot store filter")
 }
@@ -0,0 +1,299 @@
+use crate::{cmd::table, db, model};
+use anyhow::{Context, Result};
+use clap::{Args as ClapArgs, Subcommand};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The log Rockbox, and players like it, write at the root of their storage.
+pub const LOG_FILE: &str = ".scrobbler.log";
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Reads plays from a destination's scrobbler log, or from log files, into the local
+    /// database. Logs can be read again safely: plays already recorded are skipped.
+    Import(ImportArgs),
+
+    /// Lists the most played tracks.
+    List(ListArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct ImportArgs {
+    /// Destination whose .scrobbler.log to read.
+    #[arg(long, required_unless_present = "log")]
+    pub destination: Option<String>,
+
+    /// Scrobbler log to read, in Audioscrobbler format. Specify more than one for multiple logs.
+    #[arg(long)]
+    pub log: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct ListArgs {
+    /// How many tracks to list.
+    #[arg(long, default_value_t = 20)]
+    pub top: i64,
+}
+
+/// A play found in a scrobbler log.
+struct Entry {
+    artist: String,
+    album: String,
+    title: String,
+    played_at: i64,
+    musicbrainz_track_id: Option<String>,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    match args.command {
+        Commands::Import(import_args) => import(&db, import_args).await,
+        Commands::List(list_args) => list(&db, list_args).await,
+    }
+}
+
+async fn import(db: &db::Instance, args: ImportArgs) -> Result<()> {
+    let mut logs = args.log.iter().map(Path::new).collect::<Vec<_>>();
+
+    let destination_log = args
+        .destination
+        .as_ref()
+        .map(|d| Path::new(d).join(LOG_FILE));
+
+    if let Some(log) = &destination_log {
+        if !log.exists() {
+            log::info!("{} has no plays to import", log.display());
+        } else {
+            logs.push(log);
+        }
+    }
+
+    for log in logs {
+        import_log(db, log, 0).await?;
+    }
+
+    Ok(())
+}
+
+/// Records the plays of a scrobbler log in the local database, from byte offset on.
+/// Logs shorter than offset were emptied since, and are read from the start.
+/// Returns the offset of the end of the last complete line: a line the player is still
+/// writing is read the next time.
+pub async fn import_log(db: &db::Instance, log: &Path, offset: u64) -> Result<u64> {
+    let mut content =
+        std::fs::read(log).with_context(|| format!("Cannot read {}", log.display()))?;
+
+    let offset = match offset as usize <= content.len() {
+        true => offset as usize,
+        false => 0,
+    };
+
+    let end = content
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map(|n| n + 1)
+        .unwrap_or(0)
+        .max(offset);
+
+    content.truncate(end);
+
+    let (entries, malformed) = parse(&String::from_utf8_lossy(&content[offset..]));
+
+    if malformed > 0 {
+        log::warn!("Skipped {malformed} malformed lines of {}", log.display());
+    }
+
+    let mut plays = vec![];
+    let mut unmatched = 0;
+
+    for e in &entries {
+        let tracks = db
+            .tracks_by_scrobble(
+                &model::nfc(&e.artist),
+                &model::nfc(&e.album),
+                &model::nfc(&e.title),
+                e.musicbrainz_track_id.as_deref(),
+            )
+            .await
+            .with_context(|| "Cannot look up played tracks")?;
+
+        if tracks.is_empty() {
+            log::debug!(
+                "No track found for {} - {} - {}",
+                e.artist,
+                e.album,
+                e.title
+            );
+            unmatched += 1;
+            continue;
+        }
+
+        // Every copy of the song was played, as far as filters are concerned.
+        let keys: HashSet<String> = tracks.iter().map(|t| t.key()).collect();
+
+        plays.extend(keys.into_iter().map(|track_key| model::Play {
+            track_key,
+            played_at: e.played_at,
+        }));
+    }
+
+    let new = db
+        .insert_plays(&plays)
+        .await
+        .with_context(|| "Cannot store plays")?;
+
+    log::info!(
+        "Read {} plays from {}: {new} new, {unmatched} matching no track",
+        entries.len(),
+        log.display()
+    );
+
+    Ok(end as u64)
+}
+
+/// Parses a log in Audioscrobbler 1.1 format, returning its plays and how many lines couldn't
+/// be read.
+/// Header lines start with '#', entries are made of tab-separated artist, album, title, track
+/// number, duration, rating, timestamp and, optionally, MusicBrainz track id. Tracks rated 'S'
+/// were skipped rather than listened to.
+fn parse(content: &str) -> (Vec<Entry>, usize) {
+    let mut entries = vec![];
+    let mut malformed = 0;
+
+    for line in content.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+
+        if fields.len() < 7 {
+            malformed += 1;
+            continue;
+        }
+
+        if fields[5] == "S" {
+            continue;
+        }
+
+        let Ok(played_at) = fields[6].trim().parse::<i64>() else {
+            malformed += 1;
+            continue;
+        };
+
+        entries.push(Entry {
+            artist: fields[0].to_owned(),
+            album: fields[1].to_owned(),
+            title: fields[2].to_owned(),
+            played_at,
+            musicbrainz_track_id: fields
+                .get(7)
+                .map(|id| id.trim())
+                .filter(|id| !id.is_empty())
+                .map(|id| id.to_owned()),
+        });
+    }
+
+    (entries, malformed)
+}
+
+async fn list(db: &db::Instance, args: ListArgs) -> Result<()> {
+    let rows = db
+        .most_played(args.top)
+        .await
+        .with_context(|| "Cannot fetch most played tracks")?
+        .iter()
+        .map(|t| {
+            vec![
+                t.play_count.to_string(),
+                t.last_played.map(date).unwrap_or_default(),
+                t.artist.clone(),
+                t.album.clone(),
+                t.title.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    table::print(&["PLAYS", "LAST PLAYED", "ARTIST", "ALBUM", "TITLE"], &rows);
+
+    Ok(())
+}
+
+/// Formats seconds since the epoch as a date, e.g. 2024-05-17.
+fn date(seconds: i64) -> String {
+    // Days to civil date, from Howard Hinnant's date algorithms.
+    let days = seconds.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "#AUDIOSCROBBLER/1.1
+#TZ/UNKNOWN
+#CLIENT/Rockbox sansaclipplus $Revision$
+The Beatles\tAbbey Road\tCome Together\t1\t259\tL\t1715950000\t
+The Beatles\tAbbey Road\tSomething\t2\t182\tS\t1715950300\t
+Nirvana\tNevermind\tLithium\t5\t257\tL\t1715951000\t4c5b1d3a-0000-4000-8000-000000000000
+Nirvana\tNevermind\tPolly
+Nirvana\tNevermind\tBreed\t4\t183\tL\tyesterday\t
+
+";
+
+    #[test]
+    fn listened_entries() {
+        let (entries, malformed) = parse(LOG);
+
+        assert_eq!(malformed, 2);
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].artist, "The Beatles");
+        assert_eq!(entries[0].album, "Abbey Road");
+        assert_eq!(entries[0].title, "Come Together");
+        assert_eq!(entries[0].played_at, 1715950000);
+        assert_eq!(entries[0].musicbrainz_track_id, None);
+
+        assert_eq!(entries[1].title, "Lithium");
+        assert_eq!(
+            entries[1].musicbrainz_track_id.as_deref(),
+            Some("4c5b1d3a-0000-4000-8000-000000000000")
+        );
+    }
+
+    #[test]
+    fn headers_only() {
+        assert_eq!(parse("#AUDIOSCROBBLER/1.1\n#TZ/UTC\n").0.len(), 0);
+        assert_eq!(parse("").1, 0);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1715950000), "2024-05-17");
+        assert_eq!(date(951782400), "2000-02-29");
+    }
+}
@@ -0,0 +1,55 @@
+use crate::{cmd::error, db, dump};
+use anyhow::{anyhow, Context, Result};
+use clap::Args as ClapArgs;
+use std::path::Path;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory to rebuild the database in: the local database's by default, a destination's
+    /// for dumps of destinations.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Dump written by 'export': a JSON file, or a directory of CSV files.
+    pub input: String,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let input = Path::new(&args.input);
+
+    let dump = match input.is_dir() {
+        true => dump::Dump::from_csv(input)?,
+        false => dump::Dump::from_json(std::io::BufReader::new(
+            std::fs::File::open(input).with_context(|| format!("Cannot open {}", args.input))?,
+        ))?,
+    };
+
+    // Destination dumps are imported in the destination's directory, which is created if needed.
+    std::fs::create_dir_all(&args.database_path)
+        .with_context(|| format!("Cannot create directory {}", args.database_path))?;
+
+    let db = db::Instance::new(&args.database_path, dump.state.external)
+        .await
+        .with_context(|| "Cannot open database instance")?;
+
+    if !db
+        .is_empty()
+        .await
+        .with_context(|| "Cannot inspect database")?
+    {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "database in {} isn't empty, import to a new directory",
+            args.database_path
+        ))));
+    }
+
+    dump.write(&db).await?;
+
+    log::info!(
+        "Imported {} tracks from {} sources",
+        dump.tracks.len(),
+        dump.directories.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use crate::{
+    cmd::{error, search, table},
+    db, model,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::{Args as ClapArgs, Subcommand};
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Lists the tracks given a rating or a label, their album's rating included.
+    List(ListArgs),
+
+    /// Gives a label to the tracks matching a query, e.g. 'label add favourites artist:beatles'.
+    Add(LabelArgs),
+
+    /// Takes a label away from the tracks matching a query.
+    Remove(LabelArgs),
+
+    /// Rates the tracks matching a query, or their albums, from 1 to 5: 0 removes ratings.
+    Rate(RateArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct ListArgs {
+    /// Only list the tracks matching this query, written as for 'search'.
+    #[arg(value_name = "QUERY")]
+    pub query: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct LabelArgs {
+    /// Label, e.g. 'favourites'.
+    pub label: String,
+
+    /// Tracks to label, selected as with 'search', e.g. 'artist:beatles album:abbey*'.
+    #[arg(required = true, value_name = "QUERY")]
+    pub query: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct RateArgs {
+    /// Rating, from 1 to 5, or 0 to remove ratings.
+    #[arg(value_parser = clap::value_parser!(i64).range(0..=5))]
+    pub rating: i64,
+
+    /// Tracks to rate, selected as with 'search', e.g. 'artist:beatles album:abbey*'.
+    #[arg(required = true, value_name = "QUERY")]
+    pub query: Vec<String>,
+
+    /// Rate the albums of the tracks matching the query instead.
+    /// Tracks rated on their own keep their rating.
+    #[arg(long, default_value_t = false)]
+    pub albums: bool,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    match args.command {
+        Commands::List(list_args) => list(&db, list_args).await,
+        Commands::Add(label_args) => add(&db, label_args).await,
+        Commands::Remove(label_args) => remove(&db, label_args).await,
+        Commands::Rate(rate_args) => rate(&db, rate_args).await,
+    }
+}
+
+async fn list(db: &db::Instance, args: ListArgs) -> Result<()> {
+    let mut tracks = db
+        .annotated_tracks()
+        .await
+        .with_context(|| "Cannot fetch rated and labelled tracks")?;
+
+    if !args.query.is_empty() {
+        let selected: HashSet<i64> = search::select(db, &args.query)
+            .await?
+            .iter()
+            .map(|t| t.id)
+            .collect();
+
+        tracks.retain(|t| selected.contains(&t.id));
+    }
+
+    let rows = tracks
+        .iter()
+        .map(|t| {
+            vec![
+                t.rating.map(|r| r.to_string()).unwrap_or_default(),
+                t.labels
+                    .as_deref()
+                    .unwrap_or_default()
+                    .lines()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                t.artist.clone(),
+                t.album.clone(),
+                t.title.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    table::print(&["RATING", "LABELS", "ARTIST", "ALBUM", "TITLE"], &rows);
+
+    Ok(())
+}
+
+async fn add(db: &db::Instance, args: LabelArgs) -> Result<()> {
+    let label = validate_label(&args.label)?;
+    let labels = labels(&select(db, &args.query).await?, &label);
+
+    let added = db
+        .insert_labels(&labels)
+        .await
+        .with_context(|| "Cannot store labels")?;
+
+    log::info!(
+        "Labelled {added} tracks with {label}, {} had it already",
+        labels.len() as u64 - added
+    );
+
+    Ok(())
+}
+
+async fn remove(db: &db::Instance, args: LabelArgs) -> Result<()> {
+    let label = validate_label(&args.label)?;
+    let labels = labels(&select(db, &args.query).await?, &label);
+
+    let removed = db
+        .delete_labels(&labels)
+        .await
+        .with_context(|| "Cannot delete labels")?;
+
+    log::info!("Removed {label} from {removed} tracks");
+
+    Ok(())
+}
+
+async fn rate(db: &db::Instance, args: RateArgs) -> Result<()> {
+    let tracks = select(db, &args.query).await?;
+
+    if args.albums {
+        let albums: Vec<(String, String)> = tracks
+            .iter()
+            .map(|t| (t.artist.clone(), t.album.clone()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        match args.rating {
+            0 => {
+                db.delete_album_ratings(&albums)
+                    .await
+                    .with_context(|| "Cannot delete album ratings")?;
+
+                log::info!("Removed the ratings of {} albums", albums.len());
+            }
+            rating => {
+                db.insert_album_ratings(
+                    &albums
+                        .iter()
+                        .map(|(artist, album)| model::AlbumRating {
+                            artist: artist.clone(),
+                            album: album.clone(),
+                            rating,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .await
+                .with_context(|| "Cannot store album ratings")?;
+
+                log::info!("Rated {} albums {rating}", albums.len());
+            }
+        }
+
+        return Ok(());
+    }
+
+    let keys: Vec<String> = tracks
+        .iter()
+        .map(|t| t.key())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    match args.rating {
+        0 => {
+            db.delete_ratings(&keys)
+                .await
+                .with_context(|| "Cannot delete ratings")?;
+
+            log::info!("Removed the ratings of {} tracks", keys.len());
+        }
+        rating => {
+            db.insert_ratings(
+                &keys
+                    .iter()
+                    .map(|k| model::Rating {
+                        track_key: k.clone(),
+                        rating,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .with_context(|| "Cannot store ratings")?;
+
+            log::info!("Rated {} tracks {rating}", keys.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the tracks a query selects, failing if there are none: a mistyped query shouldn't
+/// go unnoticed.
+async fn select(db: &db::Instance, query: &[String]) -> Result<Vec<model::Track>> {
+    let tracks = search::select(db, query).await?;
+
+    if tracks.is_empty() {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "no track matches \"{}\"",
+            query.join(" ")
+        ))));
+    }
+
+    Ok(tracks)
+}
+
+/// Labels are stored in NFC, like tags, and can't span lines: filters see them one per line.
+fn validate_label(label: &str) -> Result<String> {
+    let label = model::nfc(label.trim());
+
+    if label.is_empty() || label.contains('\n') {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "invalid label \"{label}\""
+        ))));
+    }
+
+    Ok(label)
+}
+
+/// Returns label for each of the tracks, once per key.
+fn labels(tracks: &[model::Track], label: &str) -> Vec<model::Label> {
+    tracks
+        .iter()
+        .map(|t| t.key())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|track_key| model::Label {
+            track_key,
+            label: label.to_owned(),
+        })
+        .collect()
+}
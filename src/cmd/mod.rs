@@ -1,6 +1,18 @@
 pub mod add;
+pub mod alias;
 pub mod clean;
+pub mod destination;
+pub mod doctor;
 pub mod dupes;
 pub mod error;
+pub mod export;
 pub mod filter;
+pub mod history;
+pub mod import;
+pub mod label;
+pub mod rescan;
+pub mod search;
+pub mod source;
+pub mod stats;
 pub mod sync;
+pub mod table;
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use crate::cmd::*;
+use crate::*;
+use anyhow::{Context, Result};
+use clap::Args as ClapArgs;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync stores its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Rewrite the database of this destination instead of the local one, matching the files
+    /// it holds to the tracks of the local database.
+    /// Rescan the local database first.
+    #[arg(long)]
+    pub destination: Option<String>,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let local_db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    match args.destination {
+        None => rescan_local(&local_db).await,
+        Some(dest_dir) => {
+            let dest_db = db::Instance::new(&dest_dir, true)
+                .await
+                .with_context(|| "Cannot open destination database instance")?;
+
+            rescan_destination(&local_db, &dest_db, &dest_dir).await
+        }
+    }
+}
+
+/// Reads all the source directories again, replacing every track with a fresh one.
+/// Tracks are replaced in a single transaction: a failed rescan leaves the database as it was.
+async fn rescan_local(db: &db::Instance) -> Result<()> {
+    let sources = db
+        .directories()
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?;
+
+    let mut tx = db
+        .begin()
+        .await
+        .with_context(|| "Cannot start database transaction")?;
+
+    tx.clear_tracks()
+        .await
+        .with_context(|| "Cannot delete tracks from database")?;
+
+    let mp = MultiProgress::new();
+    let mut imported = 0;
+
+    for source in &sources {
+        let rules = fs::Rules::new(source)
+            .with_context(|| format!("Invalid patterns for {}", source.directory.display()))?;
+
+        let (new_tracks, _, _) =
+            add::traverse_and_add_in(&mut tx, db, &mp, source, rules, |_, _, _| Ok(false)).await?;
+
+        imported += new_tracks;
+    }
+
+    tx.commit()
+        .await
+        .with_context(|| "Cannot commit imported tracks to database")?;
+
+    log::info!("Imported {imported} tracks");
+
+    Ok(())
+}
+
+/// Replaces every track of a destination database with one built from the files actually stored
+/// in it.
+/// Files are matched to local tracks by their audio or content first, and by their path then.
+/// Files matching no local track are described by their own tags, so that sync can tell
+/// whether they're still wanted.
+async fn rescan_destination(
+    local_db: &db::Instance,
+    dest_db: &db::Instance,
+    dest_dir: &str,
+) -> Result<()> {
+    let layout = dest_db
+        .layout()
+        .await
+        .with_context(|| "Cannot fetch destination layout")?;
+
+    let local_tracks = local_db
+        .tracks_by_state(model::FileState::Copied)
+        .await
+        .with_context(|| "Cannot get tracks from local database")?;
+
+    let mut by_hash: HashMap<String, &model::Track> = HashMap::new();
+    let mut by_path: HashMap<std::path::PathBuf, &model::Track> = HashMap::new();
+
+    for track in &local_tracks {
+        if let Some(hash) = &track.audio_hash {
+            by_hash.insert(hash.clone(), track);
+        }
+
+        if let Some(hash) = &track.content_hash {
+            by_hash.insert(hash.clone(), track);
+        }
+
+        // Tracks might have been copied before the current layout was chosen.
+        by_path.insert(
+            track.storage_path(dest_dir, &model::Layout::default()),
+            track,
+        );
+        by_path.insert(track.storage_path(dest_dir, &layout), track);
+    }
+
+    let directory = model::Directory {
+        directory: dest_dir.into(),
+        ..Default::default()
+    };
+
+    let rules =
+        fs::Rules::new(&directory).with_context(|| format!("Invalid patterns for {}", dest_dir))?;
+
+    let paths = fs::traverse(&directory, rules).await;
+
+    let mp = MultiProgress::new();
+    let prog = mp.add(
+        ProgressBar::new_spinner()
+            .with_message(format!("Reading {}...", dest_dir))
+            .with_style(ProgressStyle::default_spinner()),
+    );
+
+    prog.enable_steady_tick(std::time::Duration::from_millis(50));
+
+    let mut tracks = vec![];
+    let mut unmatched = 0;
+
+    while let Ok(p) = paths.recv().await {
+        let p = p?;
+
+        prog.set_message(format!("Reading {}...\nFound: {}", dest_dir, p.display()));
+
+        let properties = media::probe(&p);
+
+        let matched = [&properties.audio_hash, &properties.content_hash]
+            .into_iter()
+            .flatten()
+            .find_map(|h| by_hash.get(h))
+            .or_else(|| by_path.get(&p));
+
+        let mut track: model::Track = match matched {
+            Some(local) => (*local).clone(),
+            None => {
+                let tags = media::read_tags(&p)
+                    .with_context(|| format!("Cannot read tags from {}", p.display()))?;
+
+                mp.suspend(|| {
+                    log::warn!(
+                        "{} doesn't match any local track, keeping its own tags",
+                        p.display()
+                    )
+                });
+
+                unmatched += 1;
+
+                model::RawTrack {
+                    tags,
+                    properties,
+                    path: p.clone(),
+                }
+                .into()
+            }
+        };
+
+        track.file_state = model::FileState::Copied;
+        track.stored_path = p.strip_prefix(dest_dir).ok().map(Into::into);
+
+        tracks.push(track);
+    }
+
+    prog.finish();
+    mp.remove(&prog);
+
+    dest_db
+        .replace_tracks(&tracks)
+        .await
+        .with_context(|| "Cannot write tracks to destination database")?;
+
+    log::info!(
+        "Rewrote destination database with {} tracks, {} of which don't match any local track",
+        tracks.len(),
+        unmatched
+    );
+
+    Ok(())
+}
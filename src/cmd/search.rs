@@ -0,0 +1,398 @@
+use crate::{
+    cmd::{error, table},
+    db, model,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::Args as ClapArgs;
+use std::io::Write;
+
+/// Fields queries can be qualified with, and the index column each one searches.
+const FIELDS: &[(&str, &str)] = &[
+    ("artist", "artist"),
+    ("album", "album"),
+    ("title", "title"),
+    ("ext", "extension"),
+];
+
+const OPERATORS: &[&str] = &["AND", "OR", "NOT"];
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Search this destination's tracks instead of the local ones.
+    #[arg(long)]
+    pub destination: Option<String>,
+
+    /// Words to look for, e.g. 'artist:beatles album:abbey ext:flac'.
+    /// Words can be qualified with artist:, album:, title: or ext:, end with * to match as a
+    /// prefix, and be combined with AND, OR, NOT and parentheses. Quote phrases.
+    #[arg(required = true, value_name = "QUERY")]
+    pub query: Vec<String>,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value = "table")]
+    pub format: Format,
+
+    /// Group results by album.
+    #[arg(long, default_value_t = false)]
+    pub albums: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+/// A track found by a search, as written to output.
+#[derive(serde::Serialize, Debug)]
+struct Hit {
+    artist: String,
+    album: String,
+    disc: i64,
+    number: i64,
+    title: String,
+    format: String,
+    path: String,
+}
+
+/// An album and the tracks found on it.
+#[derive(serde::Serialize, Debug)]
+struct Album {
+    artist: String,
+    album: String,
+    tracks: Vec<Hit>,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let (db, is_external) = match &args.destination {
+        Some(destination) => (
+            db::Instance::new(destination, true)
+                .await
+                .with_context(|| "Cannot open destination database instance")?,
+            true,
+        ),
+        None => (
+            db::Instance::new(&args.database_path, false)
+                .await
+                .with_context(|| "Cannot open local database instance")?,
+            false,
+        ),
+    };
+
+    let tracks = select(&db, &args.query).await?;
+
+    let hits = tracks
+        .iter()
+        .map(|t| hit(t, is_external))
+        .collect::<Vec<_>>();
+
+    match (args.format, args.albums) {
+        (Format::Table, false) => print_table(&hits),
+        (Format::Table, true) => print_albums(&group_albums(hits)),
+        (Format::Json, false) => write_json(&hits)?,
+        (Format::Json, true) => write_json(&group_albums(hits))?,
+    }
+
+    Ok(())
+}
+
+/// Returns the tracks matching the words of a search query.
+pub async fn select(db: &db::Instance, words: &[String]) -> Result<Vec<model::Track>> {
+    let query = fts_query(&model::nfc(&words.join(" ")))?;
+
+    log::debug!("search query: {query}");
+
+    db.search(&query).await.map_err(|e| match e {
+        sqlx::Error::Database(e) => anyhow!(error::Error::ValidationError(format!(
+            "invalid query: {}",
+            e.message()
+        ))),
+        e => anyhow!(e).context("Cannot search tracks"),
+    })
+}
+
+/// Translates a search query into FTS5 syntax.
+/// Words are quoted, so that punctuation in them, e.g. "AC/DC", is searched rather than parsed.
+fn fts_query(query: &str) -> Result<String> {
+    let mut terms = vec![];
+
+    for token in tokenize(query) {
+        if token.quoted.is_empty() && (token.text == "(" || token.text == ")") {
+            terms.push(token.text);
+            continue;
+        }
+
+        if token.quoted.is_empty() && OPERATORS.contains(&token.text.as_str()) {
+            terms.push(token.text);
+            continue;
+        }
+
+        let mut text = token.text.as_str();
+        let mut column = None;
+
+        // Only an unquoted qualifier names a field: "title:foo" searches for the phrase.
+        if let Some((field, rest)) = text.split_once(':') {
+            if !token.quoted.iter().any(|&q| q < field.len()) {
+                match FIELDS.iter().find(|(f, _)| f.eq_ignore_ascii_case(field)) {
+                    Some((_, c)) => {
+                        column = Some(*c);
+                        text = rest;
+                    }
+                    // Likely a typo, rather than a word to search, e.g. "10:15".
+                    None if !field.is_empty() && field.chars().all(|c| c.is_alphabetic()) => {
+                        return Err(anyhow!(error::Error::ValidationError(format!(
+                            "unknown field \"{field}\", expected artist, album, title or ext"
+                        ))));
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        let prefix = match text.strip_suffix('*') {
+            Some(t) if !token.quoted.contains(&(token.text.len() - 1)) => {
+                text = t;
+                true
+            }
+            _ => false,
+        };
+
+        if text.trim().is_empty() {
+            return Err(anyhow!(error::Error::ValidationError(format!(
+                "nothing to search for in \"{}\"",
+                token.text
+            ))));
+        }
+
+        let mut term = String::new();
+
+        if let Some(c) = column {
+            term.push_str(c);
+            term.push_str(" : ");
+        }
+
+        term.push_str(&format!("\"{}\"", text.replace('"', "\"\"")));
+
+        if prefix {
+            term.push('*');
+        }
+
+        terms.push(term);
+    }
+
+    if terms.is_empty() {
+        return Err(anyhow!(error::Error::ValidationError(
+            "empty query".to_owned()
+        )));
+    }
+
+    Ok(terms.join(" "))
+}
+
+/// A word of a query, with quotes removed.
+#[derive(Default)]
+struct Token {
+    text: String,
+    /// Offsets in text of the characters that were quoted.
+    quoted: Vec<usize>,
+}
+
+/// Splits a query on whitespace and parentheses outside of quotes.
+fn tokenize(query: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut current = Token::default();
+    let mut in_quotes = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if in_quotes => {
+                current.quoted.push(current.text.len());
+                current.text.push(c);
+            }
+            '(' | ')' => {
+                flush(&mut current, &mut tokens);
+                tokens.push(Token {
+                    text: c.to_string(),
+                    quoted: vec![],
+                });
+            }
+            c if c.is_whitespace() => flush(&mut current, &mut tokens),
+            c => current.text.push(c),
+        }
+    }
+
+    flush(&mut current, &mut tokens);
+
+    tokens
+}
+
+fn flush(current: &mut Token, tokens: &mut Vec<Token>) {
+    if !current.text.is_empty() {
+        tokens.push(std::mem::take(current));
+    }
+}
+
+fn hit(track: &model::Track, is_external: bool) -> Hit {
+    // Destination tracks are found at their storage path, relative to the destination.
+    let path = match is_external {
+        true => track
+            .storage_path("", &model::Layout::default())
+            .to_string_lossy()
+            .into_owned(),
+        false => track.file_path.to_string(),
+    };
+
+    Hit {
+        artist: track.artist.clone(),
+        album: track.album.clone(),
+        disc: track.disc_number,
+        number: track.number,
+        title: track.title.clone(),
+        format: track.extension.clone(),
+        path,
+    }
+}
+
+/// Groups hits by album, keeping their order.
+fn group_albums(hits: Vec<Hit>) -> Vec<Album> {
+    let mut albums: Vec<Album> = vec![];
+
+    for h in hits {
+        match albums.last_mut() {
+            Some(a) if a.artist == h.artist && a.album == h.album => a.tracks.push(h),
+            _ => albums.push(Album {
+                artist: h.artist.clone(),
+                album: h.album.clone(),
+                tracks: vec![h],
+            }),
+        }
+    }
+
+    albums
+}
+
+fn print_table(hits: &[Hit]) {
+    let rows = hits
+        .iter()
+        .map(|h| {
+            vec![
+                h.artist.clone(),
+                h.album.clone(),
+                format!("{}-{:02}", h.disc, h.number),
+                h.title.clone(),
+                h.format.clone(),
+                h.path.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    table::print(
+        &["ARTIST", "ALBUM", "TRACK", "TITLE", "FORMAT", "PATH"],
+        &rows,
+    );
+}
+
+fn print_albums(albums: &[Album]) {
+    for (n, a) in albums.iter().enumerate() {
+        if n > 0 {
+            println!();
+        }
+
+        println!("{} - {} ({} tracks)", a.artist, a.album, a.tracks.len());
+
+        let rows = a
+            .tracks
+            .iter()
+            .map(|h| {
+                vec![
+                    format!("    {}-{:02}", h.disc, h.number),
+                    h.title.clone(),
+                    h.format.clone(),
+                    h.path.clone(),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        table::print(&[], &rows);
+    }
+}
+
+fn write_json(value: &impl serde::Serialize) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    serde_json::to_writer_pretty(&mut stdout, value).with_context(|| "Cannot write results")?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validation_error(query: &str) -> bool {
+        matches!(
+            fts_query(query).unwrap_err().downcast_ref(),
+            Some(error::Error::ValidationError(_))
+        )
+    }
+
+    #[test]
+    fn tokens_split_outside_quotes() {
+        let tokens = tokenize(r#"(a "b c")d"#);
+        let texts: Vec<_> = tokens.iter().map(|t| t.text.as_str()).collect();
+
+        assert_eq!(texts, ["(", "a", "b c", ")", "d"]);
+        assert_eq!(tokens[2].quoted, [0, 1, 2]);
+        assert!(tokens[1].quoted.is_empty());
+    }
+
+    #[test]
+    fn words_are_quoted() {
+        assert_eq!(fts_query("AC/DC").unwrap(), r#""AC/DC""#);
+        assert_eq!(fts_query(r#""pink floyd""#).unwrap(), r#""pink floyd""#);
+        assert_eq!(fts_query(r#"12"""#).unwrap(), r#""12""#);
+        assert_eq!(fts_query(r#"a"b"#).unwrap(), r#""ab""#);
+    }
+
+    #[test]
+    fn fields_and_prefixes() {
+        assert_eq!(
+            fts_query("Artist:beatles album:abbey*").unwrap(),
+            r#"artist : "beatles" album : "abbey"*"#
+        );
+        assert_eq!(fts_query("ext:mp3").unwrap(), r#"extension : "mp3""#);
+        assert_eq!(fts_query(r#""title:foo""#).unwrap(), r#""title:foo""#);
+        assert_eq!(fts_query(r#""abbey*""#).unwrap(), r#""abbey*""#);
+        assert_eq!(fts_query("10:15").unwrap(), r#""10:15""#);
+        assert!(validation_error("genre:rock"));
+    }
+
+    #[test]
+    fn nothing_to_search() {
+        assert!(validation_error("*"));
+        assert!(validation_error("title:*"));
+        assert!(validation_error("title:"));
+        assert!(validation_error("  "));
+        assert!(validation_error(r#""""#));
+    }
+
+    #[test]
+    fn operators_and_parentheses() {
+        assert_eq!(
+            fts_query("a NOT (b OR c)").unwrap(),
+            r#""a" NOT ( "b" OR "c" )"#
+        );
+        assert_eq!(fts_query("a or not b").unwrap(), r#""a" "or" "not" "b""#);
+        assert_eq!(fts_query(r#""OR""#).unwrap(), r#""OR""#);
+
+        // Left for FTS5 to reject, see select.
+        assert_eq!(fts_query("(a OR b").unwrap(), r#"( "a" OR "b""#);
+        assert_eq!(fts_query("a)").unwrap(), r#""a" )"#);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::cmd::*;
+use crate::*;
+use anyhow::{anyhow, Context, Result};
+use clap::{Args as ClapArgs, Subcommand};
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Lists source directories along with their rules and options.
+    List,
+
+    /// Edits the rules and options of a source directory, removing from the database any track
+    /// the new rules exclude.
+    Edit(EditArgs),
+
+    /// Removes a source directory, along with all of its tracks, from the database.
+    Remove(RemoveArgs),
+
+    /// Moves source directories and their tracks to a new location, for example after a
+    /// library has been moved to a different disk.
+    /// Tracks keep their identity, so destinations won't copy them again.
+    Relocate(RelocateArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct EditArgs {
+    /// Source directory to edit, as it was passed to 'add'.
+    pub directory: std::path::PathBuf,
+
+    /// Replaces the include patterns of the source directory.
+    /// Specify more than one to import files matching any of them.
+    #[arg(long = "include", value_name = "GLOB", action = clap::ArgAction::Append)]
+    pub include: Option<Vec<String>>,
+
+    /// Replaces the exclude patterns of the source directory.
+    #[arg(long = "exclude", value_name = "GLOB", action = clap::ArgAction::Append)]
+    pub exclude: Option<Vec<String>>,
+
+    /// Removes all the include and exclude patterns before applying new ones.
+    #[arg(long, default_value_t = false)]
+    pub clear: bool,
+
+    /// Follow symbolic links while reading the source directory.
+    #[arg(long, value_name = "TRUE|FALSE")]
+    pub follow_links: Option<bool>,
+
+    /// Do not cross file system boundaries while reading the source directory.
+    #[arg(long, value_name = "TRUE|FALSE")]
+    pub same_file_system: Option<bool>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct RemoveArgs {
+    /// Source directory to remove, as it was passed to 'add'.
+    pub directory: std::path::PathBuf,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct RelocateArgs {
+    /// Old location of the tracks, either a source directory or one of its parents.
+    #[arg(long)]
+    pub from: std::path::PathBuf,
+
+    /// New location of the tracks.
+    #[arg(long)]
+    pub to: std::path::PathBuf,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    match args.command {
+        Commands::List => list(&db).await,
+        Commands::Edit(edit_args) => edit(&db, edit_args).await,
+        Commands::Remove(remove_args) => remove(&db, remove_args).await,
+        Commands::Relocate(relocate_args) => relocate(&db, relocate_args).await,
+    }
+}
+
+async fn list(db: &db::Instance) -> Result<()> {
+    for d in db
+        .directories()
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?
+    {
+        println!("{}", d.directory.display());
+
+        for p in d.include {
+            println!("\tinclude: {}", p);
+        }
+
+        for p in d.exclude {
+            println!("\texclude: {}", p);
+        }
+
+        println!("\tfollow links: {}", d.follow_links);
+        println!("\tsame file system: {}", d.same_file_system);
+    }
+
+    Ok(())
+}
+
+async fn edit(db: &db::Instance, args: EditArgs) -> Result<()> {
+    let mut directory = db
+        .directory(&args.directory)
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?
+        .ok_or_else(|| {
+            anyhow!(error::Error::ValidationError(format!(
+                "{} is not a source directory",
+                args.directory.display()
+            )))
+        })?;
+
+    if args.clear {
+        directory.include.clear();
+        directory.exclude.clear();
+    }
+
+    if let Some(include) = args.include {
+        directory.include = include;
+    }
+
+    if let Some(exclude) = args.exclude {
+        directory.exclude = exclude;
+    }
+
+    if let Some(follow_links) = args.follow_links {
+        directory.follow_links = follow_links;
+    }
+
+    if let Some(same_file_system) = args.same_file_system {
+        directory.same_file_system = same_file_system;
+    }
+
+    let rules = fs::Rules::new(&directory)
+        .with_context(|| format!("Invalid patterns for {}", directory.directory.display()))?;
+
+    db.insert_directory(&directory)
+        .await
+        .with_context(|| "Cannot store source directory")?;
+
+    let pruned = add::prune_excluded(db, &directory, &rules).await?;
+
+    log::info!(
+        "Updated rules of {}, removed {} excluded tracks",
+        directory.directory.display(),
+        pruned
+    );
+
+    Ok(())
+}
+
+async fn remove(db: &db::Instance, args: RemoveArgs) -> Result<()> {
+    let directory = db
+        .directory(&args.directory)
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?
+        .ok_or_else(|| {
+            anyhow!(error::Error::ValidationError(format!(
+                "{} is not a source directory",
+                args.directory.display()
+            )))
+        })?;
+
+    let removed = db
+        .delete_directory(&directory)
+        .await
+        .with_context(|| "Cannot remove source directory")?;
+
+    log::info!(
+        "Removed {} and its {} tracks",
+        directory.directory.display(),
+        removed
+    );
+
+    Ok(())
+}
+
+async fn relocate(db: &db::Instance, args: RelocateArgs) -> Result<()> {
+    let found = db
+        .directories()
+        .await
+        .with_context(|| "Cannot fetch track directories from database")?
+        .iter()
+        .any(|d| d.directory.starts_with(&args.from));
+
+    if !found {
+        return Err(anyhow!(error::Error::ValidationError(format!(
+            "no source directory found under {}",
+            args.from.display()
+        ))));
+    }
+
+    if !args.to.is_dir() {
+        log::warn!(
+            "{} is not a readable directory, tracks will be unavailable until it is",
+            args.to.display()
+        );
+    }
+
+    let (tracks, directories) = db.relocate(&args.from, &args.to).await.with_context(|| {
+        format!(
+            "Cannot relocate {} to {}",
+            args.from.display(),
+            args.to.display()
+        )
+    })?;
+
+    log::info!(
+        "Relocated {} source directories and {} tracks from {} to {}",
+        directories,
+        tracks,
+        args.from.display(),
+        args.to.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use crate::{
+    cmd::{sync, table},
+    db, model,
+};
+use anyhow::{Context, Result};
+use clap::Args as ClapArgs;
+use indicatif::HumanBytes;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Directory in which tracksync will store its local database.
+    #[arg(short, long, default_value_t = db::default_database_dir().to_str().unwrap().to_owned())]
+    pub database_path: String,
+
+    /// Report on this destination's tracks, and how much of the library they cover.
+    #[arg(long)]
+    pub destination: Option<String>,
+
+    /// How many of the largest albums to list.
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: Format,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Report {
+    tracks: usize,
+    albums: usize,
+    artists: usize,
+    size: i64,
+    /// Seconds.
+    duration: f64,
+    formats: Vec<Totals>,
+    largest_albums: Vec<Totals>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<Coverage>,
+}
+
+/// Track count, size and duration of a format or an album.
+#[derive(serde::Serialize, Debug, Default)]
+struct Totals {
+    name: String,
+    tracks: usize,
+    size: i64,
+    duration: f64,
+}
+
+impl Totals {
+    fn add(&mut self, track: &model::Track) {
+        self.tracks += 1;
+        self.size += track.file_size.unwrap_or_default();
+        self.duration += track.duration.unwrap_or_default();
+    }
+}
+
+/// How the library's tracks are split with regards to a destination.
+#[derive(serde::Serialize, Debug)]
+struct Coverage {
+    library: Totals,
+    /// Tracks found on the destination.
+    synced: Totals,
+    /// Tracks the destination's filters leave out.
+    filtered_out: Totals,
+    /// Tracks suppressed by duplicate resolution.
+    suppressed: Totals,
+    /// Tracks the next sync will copy.
+    not_synced: Totals,
+    /// Tracks on the destination that aren't in the library anymore.
+    not_in_library: Totals,
+}
+
+pub async fn run(args: Args) -> Result<()> {
+    let local_db = db::Instance::new(&args.database_path, false)
+        .await
+        .with_context(|| "Cannot open local database instance")?;
+
+    let local_tracks = local_db
+        .tracks_by_state(model::FileState::Copied)
+        .await
+        .with_context(|| "Cannot fetch tracks from local database")?;
+
+    let report = match &args.destination {
+        None => report(&local_tracks, args.top, None),
+        Some(destination) => {
+            let dest_db = db::Instance::new(destination, true)
+                .await
+                .with_context(|| "Cannot open destination database instance")?;
+
+            let dest_tracks = dest_db
+                .tracks_by_state(model::FileState::Copied)
+                .await
+                .with_context(|| "Cannot fetch tracks from destination database")?;
+
+            let coverage = coverage(&local_db, &dest_db, local_tracks, &dest_tracks).await?;
+
+            report(&dest_tracks, args.top, Some(coverage))
+        }
+    };
+
+    match args.format {
+        Format::Text => print_report(&report),
+        Format::Json => {
+            let mut stdout = std::io::stdout().lock();
+
+            serde_json::to_writer_pretty(&mut stdout, &report)
+                .with_context(|| "Cannot write report")?;
+            writeln!(stdout)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn report(tracks: &[model::Track], top: usize, coverage: Option<Coverage>) -> Report {
+    let mut total = Totals::default();
+    let mut formats: BTreeMap<&str, Totals> = BTreeMap::new();
+    let mut albums: HashMap<(&str, &str), Totals> = HashMap::new();
+    let mut artists = HashSet::new();
+
+    for t in tracks {
+        total.add(t);
+        artists.insert(t.artist.as_str());
+
+        formats
+            .entry(t.extension.as_str())
+            .or_insert_with(|| Totals {
+                name: t.extension.clone(),
+                ..Default::default()
+            })
+            .add(t);
+
+        albums
+            .entry((t.artist.as_str(), t.album.as_str()))
+            .or_insert_with(|| Totals {
+                name: format!("{} - {}", t.artist, t.album),
+                ..Default::default()
+            })
+            .add(t);
+    }
+
+    let album_count = albums.len();
+
+    let mut largest_albums = albums.into_values().collect::<Vec<_>>();
+    largest_albums.sort_by(|a, b| b.size.cmp(&a.size).then(a.name.cmp(&b.name)));
+    largest_albums.truncate(top);
+
+    Report {
+        tracks: total.tracks,
+        albums: album_count,
+        artists: artists.len(),
+        size: total.size,
+        duration: total.duration,
+        formats: formats.into_values().collect(),
+        largest_albums,
+        coverage,
+    }
+}
+
+/// Splits the library's tracks by whether and why they're on the destination, the way sync
+/// would.
+async fn coverage(
+    local_db: &db::Instance,
+    dest_db: &db::Instance,
+    local_tracks: Vec<model::Track>,
+    dest_tracks: &[model::Track],
+) -> Result<Coverage> {
+    let suppressed_keys = local_db
+        .suppressed_keys()
+        .await
+        .with_context(|| "Cannot fetch suppressed tracks")?;
+
+    let filters = sync::load_filters(dest_db).await?;
+
+    let dest_keys: HashSet<String> = dest_tracks.iter().map(|t| t.key()).collect();
+    let local_keys: HashSet<String> = local_tracks.iter().map(|t| t.key()).collect();
+
+    let mut coverage = Coverage {
+        library: totals("library", &local_tracks),
+        synced: totals("synced", []),
+        filtered_out: Totals::default(),
+        suppressed: totals("suppressed", []),
+        not_synced: Totals::default(),
+        not_in_library: totals(
+            "not in library",
+            dest_tracks
+                .iter()
+                .filter(|t| !local_keys.contains(&t.key())),
+        ),
+    };
+
+    let mut missing = vec![];
+
+    for t in local_tracks {
+        match (
+            dest_keys.contains(&t.key()),
+            suppressed_keys.contains(&t.key()),
+        ) {
+            (true, _) => coverage.synced.add(&t),
+            (false, true) => coverage.suppressed.add(&t),
+            (false, false) => missing.push(t),
+        }
+    }
+
+    let (kept, left_out) = sync::partition_tracks(missing, filters.as_ref())?;
+
+    coverage.not_synced = totals("not synced", &kept);
+    coverage.filtered_out = totals("filtered out", &left_out);
+
+    Ok(coverage)
+}
+
+fn totals<'a>(name: &str, tracks: impl IntoIterator<Item = &'a model::Track>) -> Totals {
+    let mut totals = Totals {
+        name: name.to_owned(),
+        ..Default::default()
+    };
+
+    for t in tracks {
+        totals.add(t);
+    }
+
+    totals
+}
+
+fn print_report(report: &Report) {
+    println!("Tracks:   {}", report.tracks);
+    println!("Albums:   {}", report.albums);
+    println!("Artists:  {}", report.artists);
+    println!("Size:     {}", HumanBytes(report.size as u64));
+    println!("Duration: {}", human_duration(report.duration));
+
+    println!("\nFormats:");
+    print_totals(&report.formats, report.size);
+
+    println!("\nLargest albums:");
+    print_totals(&report.largest_albums, report.size);
+
+    if let Some(c) = &report.coverage {
+        println!(
+            "\nCoverage of the library, {} tracks, {}:",
+            c.library.tracks,
+            HumanBytes(c.library.size as u64)
+        );
+
+        let rows = [&c.synced, &c.filtered_out, &c.suppressed, &c.not_synced]
+            .iter()
+            .map(|t| {
+                vec![
+                    format!("    {}", t.name),
+                    format!("{} tracks", t.tracks),
+                    percent(t.tracks as f64, c.library.tracks as f64),
+                    HumanBytes(t.size as u64).to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        table::print(&[], &rows);
+
+        if c.not_in_library.tracks > 0 {
+            println!(
+                "{} tracks on the destination aren't in the library anymore, {}",
+                c.not_in_library.tracks,
+                HumanBytes(c.not_in_library.size as u64)
+            );
+        }
+    }
+}
+
+/// Prints totals along with their share of size.
+fn print_totals(totals: &[Totals], size: i64) {
+    let rows = totals
+        .iter()
+        .map(|t| {
+            vec![
+                format!("    {}", t.name),
+                format!("{} tracks", t.tracks),
+                HumanBytes(t.size as u64).to_string(),
+                percent(t.size as f64, size as f64),
+                human_duration(t.duration),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    table::print(&[], &rows);
+}
+
+fn percent(part: f64, total: f64) -> String {
+    match total > 0.0 {
+        true => format!("{:.1}%", part / total * 100.0),
+        false => "-".to_owned(),
+    }
+}
+
+fn human_duration(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).round() as u64;
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
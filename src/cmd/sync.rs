@@ -6,10 +6,13 @@ use anyhow::Ok;
 use anyhow::{Context, Result};
 use clap::Args as ClapArgs;
 use fs_extra::file::{copy_with_progress, CopyOptions};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use indicatif::MultiProgress;
-use std::collections::hash_set;
 use std::os::unix::fs::MetadataExt;
 
+/// How many tracks filters are run over at once.
+const FILTER_BATCH: usize = 1000;
+
 #[derive(ClapArgs)]
 pub struct Args {
     /// Path where to look for tracksync source data.
@@ -36,7 +39,7 @@ pub struct Args {
 
 impl Args {
     pub fn validate(&self) -> Result<()> {
-        if let None = self.destination {
+        if self.destination.is_none() {
             return Err(anyhow!(error::Error::ValidationError(
                 "missing destination".to_owned(),
             )));
@@ -59,52 +62,106 @@ pub async fn run(args: Args) -> Result<()> {
         .await
         .with_context(|| "Cannot open destination database instance")?;
 
-    let diff = db::diff(&local_db, &dest_db)
-        .await
-        .with_context(|| "Cannot calculate difference between local and destination databases")?;
+    // Tracks are told apart by their audio, make sure tracks imported or copied by older
+    // versions have it.
+    // Dry runs leave the destination's database alone: its unhashed tracks may be listed as
+    // copies and deletions.
+    let mp = MultiProgress::new();
+    add::hash_unhashed(&local_db, &mp, None).await?;
+
+    if args.dry_run {
+        let unhashed = dest_db
+            .tracks_without_hashes()
+            .await
+            .with_context(|| "Cannot fetch destination tracks")?
+            .len();
+
+        if unhashed > 0 {
+            log::warn!(
+                "{unhashed} tracks on the destination were copied by an older version and will be \
+                hashed by the next sync: until then, they may be listed as copies and deletions"
+            );
+        }
+    } else {
+        add::hash_unhashed(&dest_db, &mp, Some(&dest_dir)).await?;
+    }
+
+    // Filters see plays up to this sync. Dry runs don't record them: they're read when the
+    // destination is actually synced, or by 'history import'.
+    let log = std::path::Path::new(&dest_dir).join(history::LOG_FILE);
 
-    let mut reverse_diff = db::diff(&dest_db, &local_db)
+    if log.exists() && !args.dry_run {
+        let read = dest_db
+            .scrobbler_log_read()
+            .await
+            .with_context(|| "Cannot fetch how much of the scrobbler log was read")?;
+
+        let read = history::import_log(&local_db, &log, read as u64).await?;
+
+        dest_db
+            .set_scrobbler_log_read(read as i64)
+            .await
+            .with_context(|| "Cannot record how much of the scrobbler log was read")?;
+    }
+
+    let layout = dest_db
+        .layout()
         .await
-        .with_context(|| "Cannot calculate difference between destination and local databases")?;
+        .with_context(|| "Cannot fetch destination layout")?;
 
-    let raw_filter = local_db
-        .filter()
+    let filters = load_filters(&dest_db).await?;
+
+    let mut planner = local_db
+        .planner(&dest_db)
         .await
-        .with_context(|| "Could not fetch filters.")?;
+        .with_context(|| "Cannot compare local and destination databases")?;
 
-    let filters = match raw_filter {
-        Some(raw_filter) => Some(
-            crate::filter::evaluate(vec![raw_filter])
-                .with_context(|| "Could not evaluate filters")?,
-        ),
-        None => None,
-    };
+    // Tracks suppressed by duplicate resolution are never copied, and removed if already there.
+    let mut to_copy = vec![];
+
+    filter_stream(planner.copies(), filters.as_ref(), |kept, _| {
+        to_copy.extend(kept)
+    })
+    .await
+    .with_context(|| "Cannot find tracks to copy")?;
 
     // find any filtered tracks that were already copied
-    reverse_diff.append(&mut diff_databases(&local_db, &dest_db, filters.as_ref(), false).await?);
+    if filters.is_some() {
+        let mut filtered_keys = vec![];
+
+        filter_stream(planner.synced(), filters.as_ref(), |_, left_out| {
+            filtered_keys.extend(left_out.iter().map(|t| t.key()))
+        })
+        .await
+        .with_context(|| "Cannot find copied tracks left out by filters")?;
+
+        planner
+            .mark_filtered(filtered_keys)
+            .await
+            .with_context(|| "Cannot record copied tracks left out by filters")?;
+    }
 
-    // now filter out all tracks to copy by using the filters
-    let diff = filter_tracks_by_id(filters.as_ref(), &local_db, diff).await?;
+    let to_delete = planner
+        .deletions()
+        .try_collect::<Vec<_>>()
+        .await
+        .with_context(|| "Cannot find tracks to delete")?;
 
     if args.dry_run {
-        dry_run_copy(&local_db, &dest_dir, diff, filters.as_ref()).await?;
-        dry_run_delete(&dest_db, &dest_dir, reverse_diff, filters.as_ref()).await?;
+        dry_run_copy(&dest_db, &dest_dir, &layout, to_copy).await?;
+        dry_run_delete(&dest_dir, to_delete);
+        dry_run_rename(&mut planner, &dest_db, &dest_dir, &layout).await?;
         return Ok(());
     }
 
     if !args.no_delete {
-        run_delete(&dest_db, &dest_dir, reverse_diff, filters.as_ref()).await?
+        run_delete(&dest_db, &dest_dir, to_delete).await?
     }
 
-    run_copy(
-        &local_db,
-        &dest_db,
-        &dest_dir,
-        diff,
-        filters.as_ref(),
-        args.link,
-    )
-    .await?;
+    run_copy(&dest_db, &dest_dir, &layout, to_copy, args.link).await?;
+
+    // Renames go last: tracks deleted above aren't renamed needlessly.
+    run_rename(&mut planner, &dest_db, &dest_dir, &layout).await?;
 
     Ok(())
 }
@@ -140,56 +197,52 @@ fn track_style() -> indicatif::ProgressStyle {
     .progress_chars("##-")
 }
 
-async fn diff_databases(
-    source: &db::Instance,
-    destination: &db::Instance,
-    filters: Option<&Vec<crate::filter::ScriptRuntime>>,
-    delete: bool,
-) -> Result<Vec<String>> {
-    let local_tracks = source.tracks_by_state(model::FileState::Copied).await?;
-
-    let local_tracks = filter_tracks(local_tracks, filters, delete)?;
-
-    let dest_tracks = destination
-        .tracks_by_state(model::FileState::Copied)
-        .await?;
-
-    let src_ids: hash_set::HashSet<String> = local_tracks
-        .clone()
-        .into_iter()
-        .map(|t| t.track_id)
-        .collect();
-
-    let dst_ids: hash_set::HashSet<String> = dest_tracks
-        .clone()
-        .into_iter()
-        .map(|t| t.track_id)
-        .collect();
-
-    log::debug!("local {} dest {}", src_ids.len(), dst_ids.len());
-
-    let d: hash_set::HashSet<&String> = dst_ids.difference(&src_ids).collect();
+/// Evaluates the filters stored on a destination, if any.
+pub async fn load_filters(
+    dest_db: &db::Instance,
+) -> Result<Option<Vec<crate::filter::ScriptRuntime>>> {
+    let raw_filter = dest_db
+        .filter()
+        .await
+        .with_context(|| "Could not fetch filters.")?;
 
-    Ok(d.into_iter().map(|e| e.clone()).collect())
+    match raw_filter {
+        Some(raw_filter) => Ok(Some(
+            crate::filter::evaluate(vec![raw_filter])
+                .with_context(|| "Could not evaluate filters")?,
+        )),
+        None => Ok(None),
+    }
 }
 
-async fn filter_tracks_by_id(
+/// Runs filters over a stream of tracks in batches of FILTER_BATCH, handing each batch to f
+/// split into the tracks filters let through and the ones they leave out.
+async fn filter_stream(
+    mut tracks: BoxStream<'_, Result<model::Track, sqlx::Error>>,
     filters: Option<&Vec<crate::filter::ScriptRuntime>>,
-    db: &db::Instance,
-    ids: Vec<String>,
-) -> Result<Vec<String>> {
-    let tracks = db.tracks_by_id(ids).await?;
+    mut f: impl FnMut(Vec<model::Track>, Vec<model::Track>),
+) -> Result<()> {
+    loop {
+        let batch = (&mut tracks)
+            .take(FILTER_BATCH)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        if batch.is_empty() {
+            return Ok(());
+        }
 
-    let tracks = filter_tracks(tracks, filters, false)?;
+        let (kept, left_out) = partition_tracks(batch, filters)?;
 
-    Ok(tracks.into_iter().map(|t| t.track_id).collect())
+        f(kept, left_out);
+    }
 }
 
-fn filter_tracks(
+/// Splits tracks into the ones filters let through, and the ones they leave out.
+pub fn partition_tracks(
     raw_tracks: Vec<model::Track>,
     filters: Option<&Vec<crate::filter::ScriptRuntime>>,
-    delete: bool,
-) -> Result<Vec<model::Track>> {
+) -> Result<(Vec<model::Track>, Vec<model::Track>)> {
     if let Some(filters) = filters {
         let mut filter_res = vec![];
 
@@ -197,58 +250,44 @@ fn filter_tracks(
             let base_tracks = raw_tracks
                 .clone()
                 .into_iter()
-                .map(|t| Into::<model::BaseTrack>::into(t))
+                .map(Into::<model::BaseTrack>::into)
                 .collect();
 
             filter_res = f.run(base_tracks)?;
         }
 
-        return Ok(raw_tracks
-            .into_iter()
-            .enumerate()
-            .filter_map(|elem| {
-                let (idx, track) = elem;
+        let mut kept = vec![];
+        let mut left_out = vec![];
 
-                if filter_res[idx] && !delete {
-                    return None;
-                }
+        for (idx, track) in raw_tracks.into_iter().enumerate() {
+            match filter_res[idx] {
+                true => kept.push(track),
+                false => left_out.push(track),
+            }
+        }
 
-                return Some(track);
-            })
-            .collect());
+        return Ok((kept, left_out));
     }
 
-    Ok(raw_tracks)
+    Ok((raw_tracks, vec![]))
 }
 
 async fn run_copy(
-    local_db: &db::Instance,
     dest_db: &db::Instance,
-    dest_dir: &String,
-    diff: Vec<String>,
-    filters: Option<&Vec<crate::filter::ScriptRuntime>>,
+    dest_dir: &str,
+    layout: &model::Layout,
+    tracks: Vec<model::Track>,
     link: bool,
 ) -> Result<()> {
-    let diff_len = diff.len();
-
-    let tracks = filter_tracks(
-        local_db
-            .tracks_by_id(diff)
-            .await
-            .with_context(|| "Cannot get tracks from local database")?,
-        filters,
-        false,
-    )?;
-
     // Copy tracks
     let mp = MultiProgress::new();
 
-    let total_bar = mp.add(progress_bar(diff_len as u64, total_style()));
+    let total_bar = mp.add(progress_bar(tracks.len() as u64, total_style()));
 
     total_bar.tick();
 
     for track in tracks {
-        copy(track, &dest_db, &dest_dir, &mp, link).await?;
+        copy(track, dest_db, dest_dir, layout, &mp, link).await?;
         total_bar.inc(1);
     }
 
@@ -258,82 +297,56 @@ async fn run_copy(
 }
 
 async fn dry_run_copy(
-    local_db: &db::Instance,
-    dest_dir: &String,
-    diff: Vec<String>,
-    filters: Option<&Vec<crate::filter::ScriptRuntime>>,
+    dest_db: &db::Instance,
+    dest_dir: &str,
+    layout: &model::Layout,
+    tracks: Vec<model::Track>,
 ) -> Result<()> {
-    let tracks = filter_tracks(
-        local_db
-            .tracks_by_id(diff)
-            .await
-            .with_context(|| "Cannot get tracks from local database")?,
-        filters,
-        false,
-    )?;
-
     for track in tracks {
-        let track_storage_path = track.storage_path(&dest_dir);
+        let track_storage_path = free_storage_path(&track, dest_db, dest_dir, layout).await?;
 
-        log::info!("Will copy {} to {}", track.file_path, track_storage_path);
+        log::info!(
+            "Will copy {} to {}",
+            track.file_path,
+            track_storage_path.display()
+        );
     }
 
     Ok(())
 }
 
-async fn dry_run_delete(
-    dest_db: &db::Instance,
-    dest_dir: &String,
-    diff: Vec<String>,
-    filters: Option<&Vec<crate::filter::ScriptRuntime>>,
-) -> Result<()> {
-    let tracks = filter_tracks(
-        dest_db
-            .tracks_by_id(diff)
-            .await
-            .with_context(|| "Cannot get tracks from destination database")?,
-        filters,
-        true,
-    )?;
-
+fn dry_run_delete(dest_dir: &str, tracks: Vec<model::Track>) {
     for track in tracks {
-        let track_storage_path = track.storage_path(&dest_dir);
+        let track_storage_path = track.storage_path(dest_dir, &model::Layout::default());
 
-        log::info!("Will delete {}", track_storage_path)
+        log::info!("Will delete {}", track_storage_path.display())
     }
-
-    Ok(())
 }
 
 async fn run_delete(
     dest_db: &db::Instance,
-    dest_dir: &String,
-    diff: Vec<String>,
-    filters: Option<&Vec<crate::filter::ScriptRuntime>>,
+    dest_dir: &str,
+    tracks: Vec<model::Track>,
 ) -> Result<()> {
-    let diff_len = diff.len();
-
-    if diff_len == 0 {
+    if tracks.is_empty() {
         return Ok(());
     }
 
-    let tracks = filter_tracks(
-        dest_db
-            .tracks_by_id(diff)
-            .await
-            .with_context(|| "Cannot get tracks from destination database")?,
-        filters,
-        true,
-    )?;
+    // Rows are deleted first, all at once: a crash leaves files no track points to behind,
+    // rather than tracks pointing to no file.
+    dest_db
+        .delete_tracks(&tracks.iter().map(|t| t.id).collect::<Vec<_>>())
+        .await
+        .with_context(|| "Cannot delete tracks from destination database")?;
 
     let mp = MultiProgress::new();
 
-    let total_bar = mp.add(progress_bar(diff_len as u64, delete_style()));
+    let total_bar = mp.add(progress_bar(tracks.len() as u64, delete_style()));
 
     total_bar.tick();
 
     for track in tracks {
-        delete(track, &dest_db, &dest_dir, &mp).await?;
+        delete(track, dest_dir, &mp)?;
         total_bar.inc(1);
     }
 
@@ -342,21 +355,153 @@ async fn run_delete(
     Ok(())
 }
 
-async fn delete(
-    track: model::Track,
+async fn dry_run_rename(
+    planner: &mut db::Planner,
     dest_db: &db::Instance,
-    dest_dir: &String,
-    mp: &indicatif::MultiProgress,
+    dest_dir: &str,
+    layout: &model::Layout,
 ) -> Result<()> {
-    let track_storage_path = track.storage_path(&dest_dir);
+    let renames = planner
+        .renames()
+        .try_collect::<Vec<_>>()
+        .await
+        .with_context(|| "Cannot find retagged or moved tracks")?;
+
+    for r in renames {
+        let (from, to) = rename_paths(planner, &r, dest_db, dest_dir, layout).await?;
+
+        match from == to {
+            true => log::info!("Will update {} from {}", from.display(), r.file_path),
+            false => log::info!(
+                "Will move {} to {}, {} was retagged",
+                from.display(),
+                to.display(),
+                r.file_path
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Updates copied tracks whose local track was retagged or moved.
+/// Files of retagged tracks are moved to where layout stores them now, and their row updated
+/// once they're there. Files of tracks whose source only moved stay where they are.
+async fn run_rename(
+    planner: &mut db::Planner,
+    dest_db: &db::Instance,
+    dest_dir: &str,
+    layout: &model::Layout,
+) -> Result<()> {
+    let renames = planner
+        .renames()
+        .try_collect::<Vec<_>>()
+        .await
+        .with_context(|| "Cannot find retagged or moved tracks")?;
+
+    let mut moved = 0;
+
+    for r in &renames {
+        let (from, to) = rename_paths(planner, r, dest_db, dest_dir, layout).await?;
+
+        if from != to {
+            move_file(&from, &to, dest_dir)?;
+            moved += 1;
+        }
+
+        let stored_path = to.strip_prefix(dest_dir).unwrap_or(&to).into();
+
+        planner
+            .rename(r, &stored_path)
+            .await
+            .with_context(|| format!("Cannot update track {} on destination", r.file_path))?;
+    }
+
+    if !renames.is_empty() {
+        log::info!(
+            "Updated {} retagged or moved tracks, moved {} files",
+            renames.len(),
+            moved
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns where the file of a renamed track is, and where it should be.
+/// Tracks copied before their storage path was recorded followed the default layout, which is
+/// computed from the tags about to change.
+async fn rename_paths(
+    planner: &mut db::Planner,
+    rename: &db::Rename,
+    dest_db: &db::Instance,
+    dest_dir: &str,
+    layout: &model::Layout,
+) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+    let from = rename
+        .track
+        .storage_path(dest_dir, &model::Layout::default());
+
+    let local = planner
+        .local_track(rename.local_id)
+        .await
+        .with_context(|| format!("Cannot get track {} from local database", rename.file_path))?;
+
+    // A missing file can't be moved, its row is updated all the same.
+    if local.track_id == rename.track.track_id || !from.exists() {
+        return Ok((from.clone(), from));
+    }
+
+    let to = free_storage_path(&local, dest_db, dest_dir, layout).await?;
+
+    Ok((from, to))
+}
+
+/// Moves a file within dest_dir, creating the directories it goes to and removing the ones it
+/// leaves empty.
+fn move_file(from: &std::path::Path, to: &std::path::Path, dest_dir: &str) -> Result<()> {
+    let parent = to
+        .parent()
+        .with_context(|| "Cannot obtain base destination directory")?;
+
+    std::fs::create_dir_all(parent).with_context(|| {
+        format!(
+            "Cannot create destination directory tree {}",
+            parent.display()
+        )
+    })?;
+
+    std::fs::rename(from, to)
+        .with_context(|| format!("Cannot move {} to {}", from.display(), to.display()))?;
+
+    let dest_dir = std::path::Path::new(dest_dir);
+
+    for dir in from.ancestors().skip(1) {
+        if dir == dest_dir || !dir.starts_with(dest_dir) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn delete(track: model::Track, dest_dir: &str, mp: &indicatif::MultiProgress) -> Result<()> {
+    // Tracks copied before their storage path was recorded followed the default layout.
+    let track_storage_path = track.storage_path(dest_dir, &model::Layout::default());
 
     let bar = mp.add(
-        progress_bar(1, track_style()).with_message(format!("Deleting: {}", track_storage_path)),
+        progress_bar(1, track_style())
+            .with_message(format!("Deleting: {}", track_storage_path.display())),
     );
 
-    dest_db.delete(track.id).await?;
-    std::fs::remove_file(track_storage_path.clone())
-        .with_context(|| format!("Cannot delete file {}", track_storage_path.clone()))?;
+    // Another row of the same track might have deleted the file already.
+    match std::fs::remove_file(&track_storage_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e)
+                .with_context(|| format!("Cannot delete file {}", track_storage_path.display()));
+        }
+        _ => {}
+    }
 
     bar.inc(1);
 
@@ -368,21 +513,27 @@ async fn delete(
 async fn copy(
     track: model::Track,
     dest_db: &db::Instance,
-    dest_dir: &String,
+    dest_dir: &str,
+    layout: &model::Layout,
     mp: &indicatif::MultiProgress,
     link: bool,
 ) -> Result<()> {
-    let track_storage_path = track.storage_path(&dest_dir);
-    let sp = std::path::Path::new(&track_storage_path);
+    let track_storage_path = free_storage_path(&track, dest_db, dest_dir, layout).await?;
 
-    let parent = sp
+    let parent = track_storage_path
         .parent()
         .with_context(|| "Cannot obtain base destination directory")?;
 
+    // Each step commits on its own: the in-flight copy has to be recorded before the file is
+    // written, for clean to find it after a crash.
     // step 1: add an in-flight copy to the destination database
     let mut dest_track = track.clone();
     dest_track.file_state = crate::model::FileState::Copying;
-    dest_db
+    dest_track.stored_path = track_storage_path
+        .strip_prefix(dest_dir)
+        .ok()
+        .map(Into::into);
+    let dest_id = dest_db
         .insert_track(&dest_track)
         .await
         .with_context(|| "Cannot insert in-progress copying track in destination database")?;
@@ -391,49 +542,45 @@ async fn copy(
     std::fs::create_dir_all(parent).with_context(|| {
         format!(
             "Cannot create destination directory tree {}",
-            parent.to_str().unwrap()
+            parent.display()
         )
     })?;
 
-    let orig_file_path = std::path::Path::new(&track.file_path);
-    let orig_file = std::fs::File::open(orig_file_path).with_context(|| {
-        format!(
-            "Cannot open origin file {}",
-            orig_file_path.to_str().unwrap()
-        )
-    })?;
+    let orig_file_path = track.file_path.as_path();
+    let orig_file = std::fs::File::open(orig_file_path)
+        .with_context(|| format!("Cannot open origin file {}", orig_file_path.display()))?;
 
     let orig_file_meta = orig_file.metadata().with_context(|| {
         format!(
             "Cannot obtain metadata information of {}",
-            orig_file_path.to_str().unwrap()
+            orig_file_path.display()
         )
     })?;
 
     let bar = mp.add(
         progress_bar(orig_file_meta.size(), track_style()).with_message(format!(
             "Copying: {}\nTo: {}",
-            track.file_path, track_storage_path
+            track.file_path,
+            track_storage_path.display()
         )),
     );
 
     let opts = CopyOptions::new().overwrite(true);
 
     if link {
-        std::fs::hard_link(track.file_path.clone(), track_storage_path.clone())?;
+        std::fs::hard_link(&track.file_path, &track_storage_path)?;
     } else {
-        match copy_with_progress(
-            track.file_path.clone(),
-            track_storage_path.clone(),
-            &opts,
-            |ph| {
-                bar.set_position(ph.copied_bytes);
-            },
-        ) {
+        match copy_with_progress(&track.file_path, &track_storage_path, &opts, |ph| {
+            bar.set_position(ph.copied_bytes);
+        }) {
             std::result::Result::Ok(_) => {}
             Err(err) => {
                 return Err(error::Error::CopyError(err)).with_context(|| {
-                    format!("Cannot copy {} to {}", track.file_path, track_storage_path)
+                    format!(
+                        "Cannot copy {} to {}",
+                        track.file_path,
+                        track_storage_path.display()
+                    )
                 });
             }
         };
@@ -441,11 +588,10 @@ async fn copy(
     }
 
     // step 3: update the destination track with the new state
-    dest_track.file_state = crate::model::FileState::Copied;
     dest_db
-        .insert_track(&dest_track)
+        .set_file_state(dest_id, crate::model::FileState::Copied)
         .await
-        .with_context(|| "Cannot insert copy finished track in destination database")?;
+        .with_context(|| "Cannot mark track as copied in destination database")?;
 
     bar.finish();
 
@@ -453,3 +599,40 @@ async fn copy(
 
     Ok(())
 }
+
+/// Returns where track should be copied under dest_dir: its storage path according to layout,
+/// numbered if a different track already occupies it.
+async fn free_storage_path(
+    track: &model::Track,
+    dest_db: &db::Instance,
+    dest_dir: &str,
+    layout: &model::Layout,
+) -> Result<std::path::PathBuf> {
+    let claimed: Vec<std::path::PathBuf> = dest_db
+        .tracks_by_key(&track.key())
+        .await
+        .with_context(|| "Cannot get tracks from destination database")?
+        .iter()
+        .map(|t| t.storage_path(dest_dir, &model::Layout::default()))
+        .collect();
+
+    let base = track.storage_path(dest_dir, layout);
+    let mut candidate = base.clone();
+    let mut n = 1;
+
+    while candidate.exists() && !claimed.contains(&candidate) {
+        n += 1;
+
+        let mut filename = base.file_stem().unwrap_or_default().to_os_string();
+        filename.push(format!(" ({n})"));
+
+        if let Some(ext) = base.extension() {
+            filename.push(".");
+            filename.push(ext);
+        }
+
+        candidate = base.with_file_name(filename);
+    }
+
+    Ok(candidate)
+}
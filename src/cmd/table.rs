@@ -0,0 +1,32 @@
+/// Prints rows in columns padded to their widest cell.
+pub fn print(header: &[&str], rows: &[Vec<String>]) {
+    let header = header.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+    let lines = std::iter::once(&header)
+        .filter(|h| !h.is_empty())
+        .chain(rows.iter())
+        .collect::<Vec<_>>();
+
+    let mut widths = vec![];
+
+    for l in &lines {
+        for (i, cell) in l.iter().enumerate() {
+            let w = cell.chars().count();
+
+            match widths.get_mut(i) {
+                Some(width) if *width < w => *width = w,
+                Some(_) => (),
+                None => widths.push(w),
+            }
+        }
+    }
+
+    for l in lines {
+        let cells = l
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{cell:w$}"))
+            .collect::<Vec<_>>();
+
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
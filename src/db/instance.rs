@@ -1,7 +1,12 @@
-use async_std::channel::{Receiver, Sender};
+use async_std::channel::Receiver;
 use futures::StreamExt;
-use sqlx::{migrate::Migrator, sqlite::SqliteConnectOptions, Error, Row, SqlitePool};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
+    ConnectOptions, Error, Sqlite, SqliteConnection, SqlitePool,
+};
 
+use super::planner::Planner;
 use crate::model;
 
 static MIGRATOR: Migrator = sqlx::migrate!("database/migrations/local");
@@ -10,8 +15,13 @@ static DATABASE_DEFAULT_NAME: &str = "tracksync.db";
 
 pub struct Instance {
     pool: SqlitePool,
+    path: std::path::PathBuf,
 }
 
+/// Writes applied all at once by commit, or not at all if dropped before.
+/// Writers other than the transaction wait for it to end, keep transactions short.
+pub struct Transaction(sqlx::Transaction<'static, Sqlite>);
+
 impl Instance {
     pub async fn new(database_path: &str, is_external: bool) -> Result<Instance, sqlx::Error> {
         let db_path = std::path::Path::new(database_path);
@@ -25,21 +35,19 @@ impl Instance {
         let mut db_path = db_path.to_path_buf();
         db_path.push(DATABASE_DEFAULT_NAME);
 
-        let db_path = db_path.to_str().unwrap();
-
-        let sqlite_conn_str = ("sqlite:".to_owned()) + db_path;
-        log::debug!("database path: {sqlite_conn_str}");
+        log::debug!("database path: sqlite:{}", db_path.display());
 
         let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::new()
-                .create_if_missing(true)
-                .filename(db_path),
+            connect_options(&db_path, is_external).create_if_missing(true),
         )
         .await?;
 
         MIGRATOR.run(&pool).await?;
 
-        let i = Instance { pool };
+        let i = Instance {
+            pool,
+            path: db_path,
+        };
 
         match crate::db::lib::is_initialized(&i.pool).await {
             Ok(_) => (),
@@ -48,19 +56,119 @@ impl Instance {
                     Error::RowNotFound => i.initialize_state(is_external).await?,
                     err => return Err(err),
                 };
-                ()
             }
         };
 
         if crate::db::lib::is_external(&i.pool).await? != is_external {
-            return Err(Error::Protocol(format!(
-                "database is marked as non-external, but it is",
-            )));
+            return Err(Error::Protocol(
+                "database is marked as non-external, but it is".to_owned(),
+            ));
         }
 
+        i.normalise_tags(is_external).await?;
+
         Ok(i)
     }
 
+    /// Normalises tags stored by older versions to NFC, merging the rows that only differed
+    /// in normalisation.
+    /// Copied tracks keep their current path on the destination.
+    async fn normalise_tags(&self, is_external: bool) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        let state = sqlx::query!(r#"select tags_normalised from state;"#)
+            .fetch_one(&mut *tx.0)
+            .await?;
+
+        if state.tags_normalised {
+            return Ok(());
+        }
+
+        let tracks = sqlx::query_as::<_, model::Track>(r#"select * from tracks;"#)
+            .fetch_all(&mut *tx.0)
+            .await?;
+
+        let count = tracks.len();
+
+        let mut normalised = 0;
+
+        for track in tracks {
+            let mut t = track.clone();
+
+            if !t.normalise_tags() {
+                continue;
+            }
+
+            // Tracks copied before stored paths followed the default layout, with names as
+            // tagged.
+            if is_external && t.stored_path.is_none() {
+                let layout = model::Layout {
+                    path_form: model::PathForm::Tagged,
+                    ..Default::default()
+                };
+
+                t.stored_path = Some(track.storage_path("", &layout).into());
+            }
+
+            // Rows of the same file whose tags only differed in normalisation are merged into
+            // this one. Rows of copies which didn't record their stored path are at the one
+            // computed above.
+            sqlx::query!(
+                r#"
+                DELETE FROM tracks WHERE id != ?1 AND track_id = ?2 AND file_path = ?3
+                AND (stored_path IS NULL OR stored_path = ?4);"#,
+                t.id,
+                t.track_id,
+                t.file_path,
+                t.stored_path,
+            )
+            .execute(&mut *tx.0)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                UPDATE tracks SET track_id = ?2, title = ?3, artist = ?4, album = ?5,
+                    extension = ?6, genre = ?7, composer = ?8, track_artist = ?9,
+                    tagged_artist = ?10, artist_sort = ?11, stored_path = ?12
+                WHERE id = ?1;"#,
+                t.id,
+                t.track_id,
+                t.title,
+                t.artist,
+                t.album,
+                t.extension,
+                t.genre,
+                t.composer,
+                t.track_artist,
+                t.tagged_artist,
+                t.artist_sort,
+                t.stored_path,
+            )
+            .execute(&mut *tx.0)
+            .await?;
+
+            normalised += 1;
+        }
+
+        let merged = count as i64
+            - sqlx::query!(r#"SELECT COUNT(*) AS "count!: i64" FROM tracks;"#)
+                .fetch_one(&mut *tx.0)
+                .await?
+                .count;
+
+        sqlx::query!(r#"update state set tags_normalised = TRUE;"#)
+            .execute(&mut *tx.0)
+            .await?;
+
+        tx.commit().await?;
+
+        if normalised > 0 {
+            log::info!("Normalised tags of {normalised} tracks, merged {merged} duplicate rows");
+        }
+
+        Ok(())
+    }
+
     pub async fn initialize_state(&self, is_external: bool) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
 
@@ -83,65 +191,61 @@ impl Instance {
         Ok(())
     }
 
-    pub async fn exists(&self, path: String) -> Result<bool, Error> {
-        let mut conn = self.pool.acquire().await?;
+    pub async fn begin(&self) -> Result<Transaction, Error> {
+        Ok(Transaction(self.pool.begin().await?))
+    }
 
-        match sqlx::query!(
-            r#"
-            SELECT id FROM tracks WHERE file_path = ?1;
-            "#,
-            path,
-        )
-        .fetch_one(&mut *conn)
-        .await
-        {
-            Ok(_) => Ok(true),
-            Err(err) => match err {
-                Error::RowNotFound => Ok(false),
-                rest => Err(rest),
-            },
+    /// Deletes tracks by id in a single transaction.
+    pub async fn delete_tracks(&self, ids: &[i64]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        for id in ids {
+            tx.delete(*id).await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Replaces all the tracks with the given ones in a single transaction: if anything fails,
+    /// the old tracks are kept.
+    pub async fn replace_tracks(&self, tracks: &[model::Track]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        sqlx::query!(r#"DELETE FROM tracks;"#)
+            .execute(&mut *tx.0)
+            .await?;
+
+        for t in tracks {
+            tx.insert_track(t).await?;
         }
+
+        tx.commit().await
+    }
+
+    pub async fn exists(&self, path: &model::FilePath) -> Result<bool, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        exists(&mut conn, path).await
+    }
+
+    /// Stores track, replacing its artist with the one it's an alias of, if any, and returns
+    /// its id.
+    /// A track stored at the same location, see the tracks_location index, is updated instead.
+    pub async fn insert_track(&self, track: &model::Track) -> Result<i64, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        insert_track(&mut conn, track).await
     }
 
-    pub async fn insert_track(&self, track: &model::Track) -> Result<(), Error> {
+    pub async fn set_file_state(&self, id: i64, state: model::FileState) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
 
         sqlx::query!(
             r#"
-            INSERT OR REPLACE INTO tracks (
-                track_id,
-                title,
-                artist,
-                album,
-                number,
-                file_path,
-                disc_number,
-                disc_total,
-                file_state,
-                extension
-            ) VALUES (
-                ?1,
-                ?2,
-                ?3,
-                ?4,
-                ?5,
-                ?6,
-                ?7,
-                ?8,
-                ?9,
-                ?10
-            );
+            UPDATE tracks SET file_state = ?2 WHERE id = ?1;
             "#,
-            track.track_id,
-            track.title,
-            track.artist,
-            track.album,
-            track.number,
-            track.file_path,
-            track.disc_number,
-            track.disc_total,
-            track.file_state,
-            track.extension,
+            id,
+            state,
         )
         .execute(&mut *conn)
         .await?;
@@ -149,306 +253,1629 @@ impl Instance {
         Ok(())
     }
 
-    pub async fn track_ids_by_state(&self, state: model::FileState) -> Result<Vec<String>, Error> {
+    /// Returns the tracks imported before content and audio hashes were stored.
+    pub async fn tracks_without_hashes(&self) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(
+        sqlx::query_as::<_, model::Track>(
             r#"
-            SELECT track_id FROM tracks WHERE file_state = ?1;
+            SELECT * FROM tracks WHERE content_hash IS NULL OR audio_hash IS NULL;
             "#,
-            state,
         )
         .fetch_all(&mut *conn)
-        .await?
-        .into_iter()
-        .map(|t| t.track_id)
-        .collect::<Vec<String>>())
+        .await
     }
 
-    pub async fn tracks_by_id(&self, ids: Vec<String>) -> Result<Vec<model::Track>, Error> {
+    pub async fn set_hashes(
+        &self,
+        id: i64,
+        file_size: Option<i64>,
+        content_hash: Option<&str>,
+        audio_hash: Option<&str>,
+    ) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
 
-        let ids_joined = ids
-            .into_iter()
-            .map(|mut id| {
-                id.insert_str(0, "'");
-                id.push_str("'");
+        set_hashes(&mut conn, id, file_size, content_hash, audio_hash).await
+    }
 
-                id
-            })
-            .collect::<Vec<_>>()
-            .join(",");
+    /// Returns the tracks with the given key, see model::Track::key.
+    pub async fn tracks_by_key(&self, key: &str) -> Result<Vec<model::Track>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT * FROM tracks
+            WHERE COALESCE(audio_hash, track_id || '-' || disc_number || '-' || number) = ?1;
+            "#,
+        )
+        .bind(key)
+        .fetch_all(&mut *conn)
+        .await
+    }
 
-        let query = format!("select * from tracks where track_id in ({});", ids_joined);
-        let rows = sqlx::query(&query).fetch_all(&mut *conn).await?;
+    /// Opens a planner comparing this local database with a destination's.
+    /// The planner has a connection of its own, closed when it's dropped.
+    pub async fn planner(&self, destination: &Instance) -> Result<Planner, Error> {
+        // Only the local database plans syncs.
+        let conn = connect_options(&self.path, false).connect().await?;
 
-        Ok(rows
-            .into_iter()
-            .map(|r| model::Track {
-                id: r.get("id"),
-                track_id: r.get("track_id"),
-                title: r.get("title"),
-                artist: r.get("artist"),
-                album: r.get("album"),
-                number: r.get("number"),
-                file_path: r.get("file_path"),
-                disc_number: r.get("disc_number"),
-                disc_total: r.get("disc_total"),
-                file_state: r.get("file_state"),
-                extension: r.get("extension"),
-            })
-            .collect())
+        Planner::new(conn, &destination.path).await
     }
 
-    pub async fn tracks_by_state(
-        &self,
-        state: model::FileState,
-    ) -> Result<Vec<model::Track>, Error> {
+    pub async fn tracks(&self) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(
+        sqlx::query_as::<_, model::Track>(
             r#"
-            SELECT * FROM tracks WHERE file_state = ?1;
+            SELECT * FROM tracks ORDER BY id;
             "#,
-            state,
         )
         .fetch_all(&mut *conn)
-        .await?
-        .into_iter()
-        .map(|r| model::Track {
-            id: r.id,
-            track_id: r.track_id,
-            title: r.title,
-            artist: r.artist,
-            album: r.album,
-            number: r.number,
-            file_path: r.file_path,
-            disc_number: r.disc_number,
-            disc_total: r.disc_total,
-            file_state: r.file_state.into(),
-            extension: r.extension,
-        })
-        .collect::<Vec<model::Track>>())
+        .await
     }
 
-    pub async fn delete(&self, id: i64) -> Result<(), Error> {
+    /// Returns whether the database holds neither tracks nor source directories.
+    pub async fn is_empty(&self) -> Result<bool, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        sqlx::query!(
+        let count = sqlx::query!(
             r#"
-            DELETE FROM tracks WHERE id = ?1;
-            "#,
-            id,
+            SELECT (SELECT COUNT(*) FROM tracks) + (SELECT COUNT(*) FROM directories) AS "count!: i64";
+            "#
         )
-        .execute(&mut *conn)
-        .await?;
+        .fetch_one(&mut *conn)
+        .await?
+        .count;
 
-        Ok(())
+        Ok(count == 0)
     }
 
-    pub async fn directories(&self) -> Result<Vec<String>, Error> {
+    /// Returns the tracks in the given state, along with their plays, ratings and labels.
+    pub async fn tracks_by_state(
+        &self,
+        state: model::FileState,
+    ) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(r#"SELECT * FROM directories;"#)
-            .fetch_all(&mut *conn)
-            .await?
-            .into_iter()
-            .map(|e| e.directory)
-            .collect())
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT * FROM annotated_tracks WHERE file_state = ?1;
+            "#,
+        )
+        .bind(state)
+        .fetch_all(&mut *conn)
+        .await
     }
 
-    pub async fn insert_directory(&self, directory: String) -> Result<(), Error> {
+    /// Returns tracks matching an FTS5 query, sorted by album.
+    /// Tracks still being copied to a destination aren't returned.
+    pub async fn search(&self, query: &str) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        sqlx::query!(
-            r#"INSERT OR REPLACE INTO directories (directory) VALUES (?1);"#,
-            directory,
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT tracks.* FROM track_fts JOIN tracks ON tracks.id = track_fts.rowid
+            WHERE track_fts MATCH ?1 AND tracks.file_state != ?2
+            ORDER BY tracks.artist, tracks.album, tracks.disc_number, tracks.number, tracks.title;
+            "#,
         )
-        .execute(&mut *conn)
-        .await?;
-
-        Ok(())
+        .bind(query)
+        .bind(model::FileState::Copying)
+        .fetch_all(&mut *conn)
+        .await
     }
 
-    pub async fn track_paths_from_dir(&self, directory: String) -> Result<Vec<String>, Error> {
+    pub async fn directories(&self) -> Result<Vec<model::Directory>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        let directory = format!("{}?", directory);
         Ok(sqlx::query!(
-            r#"SELECT file_path FROM tracks where file_path LIKE ?1"#,
-            directory,
+            r#"
+            SELECT
+                directory as "directory: model::FilePath",
+                include_patterns,
+                exclude_patterns,
+                follow_links,
+                same_file_system
+            FROM directories;
+            "#
         )
         .fetch_all(&mut *conn)
         .await?
         .into_iter()
-        .map(|e| e.file_path)
+        .map(|e| model::Directory {
+            directory: e.directory.as_path().to_path_buf(),
+            include: split_patterns(e.include_patterns),
+            exclude: split_patterns(e.exclude_patterns),
+            follow_links: e.follow_links,
+            same_file_system: e.same_file_system,
+        })
         .collect())
     }
 
-    pub async fn albums(&self) -> Result<Vec<model::Album>, Error> {
+    pub async fn directory(
+        &self,
+        directory: &std::path::Path,
+    ) -> Result<Option<model::Directory>, Error> {
+        Ok(self
+            .directories()
+            .await?
+            .into_iter()
+            .find(|d| d.directory == directory))
+    }
+
+    pub async fn insert_directory(&self, directory: &model::Directory) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(
+        insert_directory(&mut conn, directory).await
+    }
+
+    /// Returns a track for each audio hash no fingerprint has been computed for yet.
+    pub async fn tracks_without_fingerprint(&self) -> Result<Vec<model::Track>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Track>(
             r#"
-            SELECT * FROM albums;
-            "#
+            SELECT * FROM tracks
+            WHERE audio_hash IS NOT NULL
+                AND audio_hash NOT IN (SELECT audio_hash FROM fingerprints)
+            GROUP BY audio_hash;
+            "#,
         )
         .fetch_all(&mut *conn)
-        .await?
-        .into_iter()
-        .map(|e| model::Album {
-            title: e.title,
-            artist: e.artist,
-            format: e.format,
-        })
-        .collect())
+        .await
     }
 
-    pub async fn fuzzy_find_album(
+    pub async fn insert_fingerprint(
         &self,
-        query: &Vec<String>,
-    ) -> Result<Vec<(String, String, String)>, Error> {
+        audio_hash: &str,
+        fingerprint: &[u32],
+    ) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
 
-        let query_str = format!(
-            r#"select track_id, album, extension from track_fts where album match '{}' group by album;"#,
-            query.join(" "),
-        );
+        let raw = crate::fingerprint::to_bytes(fingerprint);
 
-        Ok(sqlx::query(&query_str)
-            .fetch_all(&mut *conn)
-            .await?
-            .into_iter()
-            .map(|r| {
-                let album: String = r.get("album");
-                let format: String = r.get("extension");
-                let track_id: String = r.get("track_id");
+        sqlx::query!(
+            r#"
+            INSERT OR REPLACE INTO fingerprints (audio_hash, fingerprint) VALUES (?1, ?2);
+            "#,
+            audio_hash,
+            raw,
+        )
+        .execute(&mut *conn)
+        .await?;
 
-                (track_id, album, format)
-            })
-            .collect())
+        Ok(())
     }
 
-    pub async fn duplicate_albums(&self) -> Result<Vec<(model::Album, i64)>, Error> {
+    /// Returns all the non-empty fingerprints of tracks in the database, by audio hash.
+    pub async fn fingerprints(&self) -> Result<Vec<(String, Vec<u32>)>, Error> {
         let mut conn = self.pool.acquire().await?;
 
         Ok(sqlx::query!(
             r#"
-                SELECT artist, title, count(*) as count FROM albums
-                GROUP BY artist, title
-                HAVING count > 1;
+            SELECT audio_hash, fingerprint FROM fingerprints
+            WHERE length(fingerprint) > 0
+                AND audio_hash IN (SELECT audio_hash FROM tracks);
             "#,
         )
         .fetch_all(&mut *conn)
         .await?
         .into_iter()
-        .map(|e| {
-            (
-                model::Album {
-                    title: e.title,
-                    artist: e.artist,
-                    format: String::new(),
-                },
-                e.count,
-            )
-        })
+        .map(|r| (r.audio_hash, crate::fingerprint::from_bytes(&r.fingerprint)))
         .collect())
     }
 
-    pub async fn album_paths(
+    pub async fn album_tracks(
         &self,
         title: &String,
         artist: &String,
-    ) -> Result<Vec<(String, String)>, Error> {
+    ) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(
+        sqlx::query_as::<_, model::Track>(
             r#"
-                select * from tracks where artist = ?2 and album = ?1 group by extension;
+            SELECT * FROM tracks WHERE album = ?1 AND artist = ?2;
             "#,
-            title,
-            artist,
         )
+        .bind(title)
+        .bind(artist)
         .fetch_all(&mut *conn)
-        .await?
-        .into_iter()
-        .map(|e| {
-            // parse dupe path and get the directory containing it
-            let dupe_path = std::path::Path::new(&e.file_path).parent().unwrap();
-            let dupe_path = dupe_path.to_str().unwrap().to_string();
+        .await
+    }
 
-            (dupe_path.clone(), e.extension.clone())
-        })
-        .collect())
+    pub async fn suppressions(&self) -> Result<Vec<model::Suppression>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Suppression>(
+            r#"
+            SELECT * FROM suppressions ORDER BY id;
+            "#,
+        )
+        .fetch_all(&mut *conn)
+        .await
     }
 
-    pub async fn tracks_iter(&self) -> Result<Receiver<Result<model::Track, Error>>, Error> {
+    pub async fn suppression(&self, id: i64) -> Result<Option<model::Suppression>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        let (tx, rx): (
-            Sender<Result<model::Track, Error>>,
-            Receiver<Result<model::Track, Error>>,
-        ) = async_std::channel::unbounded();
+        sqlx::query_as::<_, model::Suppression>(
+            r#"
+            SELECT * FROM suppressions WHERE id = ?1;
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await
+    }
 
-        async_std::task::spawn(async move {
-            let mut tracks_stream = sqlx::query!("SELECT * from tracks;").fetch(&mut *conn);
+    pub async fn delete_suppression(&self, id: i64) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
 
-            while let Some(track) = tracks_stream.next().await {
-                match track {
-                    Ok(track) => tx
-                        .send(Ok(model::Track {
-                            id: track.id,
-                            track_id: track.track_id,
-                            title: track.title,
-                            artist: track.artist,
-                            album: track.album,
-                            number: track.number,
-                            file_path: track.file_path,
-                            disc_number: track.disc_number,
-                            disc_total: track.disc_total,
-                            file_state: track.file_state.into(),
-                            extension: track.extension,
-                        }))
-                        .await
-                        .unwrap(),
-                    Err(e) => {
-                        tx.send(Err(e)).await.unwrap();
-                        tx.close();
-                        break;
-                    }
-                }
-            }
+        sqlx::query!(r#"DELETE FROM suppressions WHERE id = ?1;"#, id)
+            .execute(&mut *conn)
+            .await?;
 
-            tx.close();
-        });
+        Ok(())
+    }
 
-        Ok(rx)
+    /// Returns the keys of all the suppressed tracks, see model::Track::key.
+    pub async fn suppressed_keys(&self) -> Result<std::collections::HashSet<String>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(
+            sqlx::query!(r#"SELECT DISTINCT track_key FROM suppressions;"#)
+                .fetch_all(&mut *conn)
+                .await?
+                .into_iter()
+                .map(|r| r.track_key)
+                .collect(),
+        )
     }
 
-    pub async fn filter(&self) -> Result<Option<String>, Error> {
+    /// Returns the tracks a scrobbler log entry refers to: by MusicBrainz id when the entry
+    /// has one, or else by title, album and artist, compared case-insensitively.
+    /// The artist may be the album's or the track's own, an empty album matches any.
+    pub async fn tracks_by_scrobble(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+        musicbrainz_track_id: Option<&str>,
+    ) -> Result<Vec<model::Track>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        Ok(sqlx::query!(
+        if let Some(id) = musicbrainz_track_id {
+            let tracks = sqlx::query_as::<_, model::Track>(
+                r#"
+                SELECT * FROM tracks WHERE musicbrainz_track_id = ?1 AND file_state = ?2;
+                "#,
+            )
+            .bind(id)
+            .bind(model::FileState::Copied)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            if !tracks.is_empty() {
+                return Ok(tracks);
+            }
+        }
+
+        sqlx::query_as::<_, model::Track>(
             r#"
-                select filter from state;
+            SELECT * FROM tracks
+            WHERE title = ?1 COLLATE NOCASE AND file_state = ?4
+            AND (?2 = '' OR album = ?2 COLLATE NOCASE)
+            AND (
+                artist = ?3 COLLATE NOCASE OR track_artist = ?3 COLLATE NOCASE
+                OR tagged_artist = ?3 COLLATE NOCASE
+            );
             "#,
         )
-        .fetch_one(&mut *conn)
-        .await?
-        .filter)
+        .bind(title)
+        .bind(album)
+        .bind(artist)
+        .bind(model::FileState::Copied)
+        .fetch_all(&mut *conn)
+        .await
     }
 
-    pub async fn set_filter(&self, filter: String) -> Result<(), Error> {
+    /// Stores plays in a single transaction, and returns how many weren't stored already.
+    pub async fn insert_plays(&self, plays: &[model::Play]) -> Result<u64, Error> {
+        let mut tx = self.begin().await?;
+        let inserted = insert_plays(&mut tx.0, plays).await?;
+
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    pub async fn plays(&self) -> Result<Vec<model::Play>, Error> {
         let mut conn = self.pool.acquire().await?;
 
-        sqlx::query!(
+        sqlx::query_as::<_, model::Play>(
             r#"
-            update state set filter = ?1;"#,
-            filter,
+            SELECT * FROM plays ORDER BY played_at, track_key;
+            "#,
         )
-        .execute(&mut *conn)
-        .await?;
-
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Returns the most played tracks along with their plays, most played first.
+    pub async fn most_played(&self, limit: i64) -> Result<Vec<model::Track>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT * FROM annotated_tracks WHERE file_state = ?1 AND play_count > 0
+            ORDER BY play_count DESC, last_played DESC, id
+            LIMIT ?2;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .bind(limit)
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Returns the tracks given a rating or a label, their album's rating included, along with
+    /// their plays, ratings and labels.
+    pub async fn annotated_tracks(&self) -> Result<Vec<model::Track>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT * FROM annotated_tracks
+            WHERE file_state = ?1 AND (rating IS NOT NULL OR labels IS NOT NULL)
+            ORDER BY artist, album, disc_number, number, title;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Stores labels in a single transaction, and returns how many tracks didn't have them
+    /// already.
+    pub async fn insert_labels(&self, labels: &[model::Label]) -> Result<u64, Error> {
+        let mut tx = self.begin().await?;
+        let inserted = insert_labels(&mut tx.0, labels).await?;
+
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    /// Deletes labels in a single transaction, and returns how many tracks had them.
+    pub async fn delete_labels(&self, labels: &[model::Label]) -> Result<u64, Error> {
+        let mut tx = self.begin().await?;
+        let mut deleted = 0;
+
+        for l in labels {
+            deleted += sqlx::query!(
+                r#"
+                DELETE FROM labels WHERE track_key = ?1 AND label = ?2;
+                "#,
+                l.track_key,
+                l.label,
+            )
+            .execute(&mut *tx.0)
+            .await?
+            .rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    pub async fn labels(&self) -> Result<Vec<model::Label>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Label>(
+            r#"
+            SELECT * FROM labels ORDER BY label, track_key;
+            "#,
+        )
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Stores ratings in a single transaction, replacing the ones tracks had.
+    pub async fn insert_ratings(&self, ratings: &[model::Rating]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        insert_ratings(&mut tx.0, ratings).await?;
+
+        tx.commit().await
+    }
+
+    /// Deletes the ratings of the tracks with the given keys, in a single transaction.
+    pub async fn delete_ratings(&self, keys: &[String]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        for k in keys {
+            sqlx::query!(r#"DELETE FROM ratings WHERE track_key = ?1;"#, k)
+                .execute(&mut *tx.0)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    pub async fn ratings(&self) -> Result<Vec<model::Rating>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Rating>(
+            r#"
+            SELECT * FROM ratings ORDER BY track_key;
+            "#,
+        )
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Stores album ratings in a single transaction, replacing the ones albums had.
+    pub async fn insert_album_ratings(&self, ratings: &[model::AlbumRating]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        insert_album_ratings(&mut tx.0, ratings).await?;
+
+        tx.commit().await
+    }
+
+    /// Deletes the ratings of the given albums, as artist and title, in a single transaction.
+    pub async fn delete_album_ratings(&self, albums: &[(String, String)]) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+
+        for (artist, album) in albums {
+            sqlx::query!(
+                r#"DELETE FROM album_ratings WHERE artist = ?1 AND album = ?2;"#,
+                artist,
+                album,
+            )
+            .execute(&mut *tx.0)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    pub async fn album_ratings(&self) -> Result<Vec<model::AlbumRating>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::AlbumRating>(
+            r#"
+            SELECT * FROM album_ratings ORDER BY artist, album;
+            "#,
+        )
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Deletes a source directory along with all of its tracks, returning how many tracks
+    /// were deleted.
+    /// Tracks another source directory covers, such as one nested in directory, are kept.
+    pub async fn delete_directory(&self, directory: &model::Directory) -> Result<u64, Error> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let others: Vec<Vec<u8>> = self
+            .directories()
+            .await?
+            .iter()
+            .filter(|d| d.directory != directory.directory)
+            .map(|d| d.prefix())
+            .collect();
+
+        let mut tx = self.begin().await?;
+
+        let path: model::FilePath = directory.directory.as_path().into();
+        let prefix = directory.prefix();
+
+        let tracks = sqlx::query!(
+            r#"
+            SELECT id, file_path AS "file_path: model::FilePath" FROM tracks
+            WHERE substr(file_path, 1, length(?1)) = ?1;
+            "#,
+            prefix,
+        )
+        .fetch_all(&mut *tx.0)
+        .await?;
+
+        let mut deleted = 0;
+
+        for t in tracks {
+            let file_path = t.file_path.as_path().as_os_str().as_bytes();
+
+            if others.iter().any(|o| file_path.starts_with(o)) {
+                continue;
+            }
+
+            deleted += sqlx::query!(r#"DELETE FROM tracks WHERE id = ?1;"#, t.id)
+                .execute(&mut *tx.0)
+                .await?
+                .rows_affected();
+        }
+
+        sqlx::query!(r#"DELETE FROM directories WHERE directory = ?1;"#, path)
+            .execute(&mut *tx.0)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    pub async fn aliases(&self) -> Result<Vec<model::Alias>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as!(
+            model::Alias,
+            r#"SELECT alias, artist FROM artist_aliases ORDER BY artist, alias;"#
+        )
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    /// Stores an alias, and renames the artist of the tracks tagged with it.
+    /// Returns how many tracks were renamed.
+    pub async fn insert_alias(&self, alias: &model::Alias) -> Result<u64, Error> {
+        let mut tx = self.begin().await?;
+        let renamed = insert_alias(&mut tx.0, alias).await?;
+
+        tx.commit().await?;
+
+        Ok(renamed)
+    }
+
+    /// Deletes an alias, giving the tracks tagged with it their artist back.
+    /// Returns how many tracks were renamed, or None if there's no such alias.
+    pub async fn delete_alias(&self, alias: &str) -> Result<Option<u64>, Error> {
+        let mut tx = self.begin().await?;
+
+        let deleted = sqlx::query!(r#"DELETE FROM artist_aliases WHERE alias = ?1;"#, alias)
+            .execute(&mut *tx.0)
+            .await?
+            .rows_affected();
+
+        if deleted == 0 {
+            return Ok(None);
+        }
+
+        let renamed = sqlx::query!(
+            r#"
+            UPDATE tracks SET artist = tagged_artist
+            WHERE tagged_artist = ?1 COLLATE NOCASE;
+            "#,
+            alias,
+        )
+        .execute(&mut *tx.0)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(Some(renamed))
+    }
+
+    /// Moves every track, source directory and suppressed path found under from to the same
+    /// relative location under to, keeping everything else about them untouched.
+    /// Returns how many tracks and directories were moved.
+    pub async fn relocate(
+        &self,
+        from: &std::path::Path,
+        to: &std::path::Path,
+    ) -> Result<(u64, u64), Error> {
+        let directories = self.directories().await?;
+
+        let mut tx = self.begin().await?;
+
+        let from_prefix = model::path_prefix(from);
+        let to_prefix = model::path_prefix(to);
+
+        // Concatenation yields TEXT, cast it back so that paths stay comparable with BLOBs.
+        let tracks = sqlx::query!(
+            r#"
+            UPDATE tracks
+            SET file_path = CAST(?2 || substr(file_path, length(?1) + 1) AS BLOB)
+            WHERE substr(file_path, 1, length(?1)) = ?1;
+            "#,
+            from_prefix,
+            to_prefix,
+        )
+        .execute(&mut *tx.0)
+        .await?
+        .rows_affected();
+
+        // Suppressions keep pointing to the files they're about, and quarantined files go
+        // back under the new location when restored.
+        sqlx::query!(
+            r#"
+            UPDATE suppressions SET
+                file_path = CASE WHEN substr(file_path, 1, length(?1)) = ?1
+                    THEN CAST(?2 || substr(file_path, length(?1) + 1) AS BLOB)
+                    ELSE file_path END,
+                kept_path = CASE WHEN substr(kept_path, 1, length(?1)) = ?1
+                    THEN CAST(?2 || substr(kept_path, length(?1) + 1) AS BLOB)
+                    ELSE kept_path END,
+                quarantine_path = CASE WHEN substr(quarantine_path, 1, length(?1)) = ?1
+                    THEN CAST(?2 || substr(quarantine_path, length(?1) + 1) AS BLOB)
+                    ELSE quarantine_path END;
+            "#,
+            from_prefix,
+            to_prefix,
+        )
+        .execute(&mut *tx.0)
+        .await?;
+
+        let mut moved_dirs = 0;
+
+        for d in directories {
+            let rel = match d.directory.strip_prefix(from) {
+                Ok(rel) => rel,
+                Err(_) => continue,
+            };
+
+            let old_path: model::FilePath = d.directory.as_path().into();
+            let new_path: model::FilePath = match rel.as_os_str().is_empty() {
+                true => to.into(),
+                false => to.join(rel).into(),
+            };
+
+            sqlx::query!(
+                r#"UPDATE directories SET directory = ?2 WHERE directory = ?1;"#,
+                old_path,
+                new_path,
+            )
+            .execute(&mut *tx.0)
+            .await?;
+
+            moved_dirs += 1;
+        }
+
+        tx.commit().await?;
+
+        Ok((tracks, moved_dirs))
+    }
+
+    pub async fn tracks_from_dir(
+        &self,
+        directory: &model::Directory,
+    ) -> Result<Vec<model::Track>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query_as::<_, model::Track>(
+            r#"SELECT * FROM tracks WHERE substr(file_path, 1, length(?1)) = ?1;"#,
+        )
+        .bind(directory.prefix())
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    pub async fn track_paths_from_dir(
+        &self,
+        directory: &model::Directory,
+    ) -> Result<Vec<model::FilePath>, Error> {
+        Ok(self
+            .tracks_from_dir(directory)
+            .await?
+            .into_iter()
+            .map(|t| t.file_path)
+            .collect())
+    }
+
+    pub async fn albums(&self) -> Result<Vec<model::Album>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(sqlx::query!(
+            r#"
+            SELECT * FROM albums;
+            "#
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|e| model::Album {
+            title: e.title,
+            artist: e.artist,
+            format: e.format,
+        })
+        .collect())
+    }
+
+    pub async fn duplicate_albums(&self) -> Result<Vec<(model::Album, i64)>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(sqlx::query!(
+            r#"
+                SELECT artist, title, count(*) as count FROM albums
+                GROUP BY artist, title
+                HAVING count > 1;
+            "#,
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|e| {
+            (
+                model::Album {
+                    title: e.title,
+                    artist: e.artist,
+                    format: String::new(),
+                },
+                e.count,
+            )
+        })
+        .collect())
+    }
+
+    pub async fn album_paths(
+        &self,
+        title: &String,
+        artist: &String,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(sqlx::query!(
+            r#"
+                select file_path as "file_path: model::FilePath", extension from tracks
+                where artist = ?2 and album = ?1 group by extension;
+            "#,
+            title,
+            artist,
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|e| {
+            // parse dupe path and get the directory containing it
+            let dupe_path = e.file_path.as_path().parent().unwrap();
+            let dupe_path = dupe_path.display().to_string();
+
+            (dupe_path.clone(), e.extension.clone())
+        })
+        .collect())
+    }
+
+    pub async fn tracks_iter(&self) -> Result<Receiver<Result<model::Track, Error>>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        let (tx, rx) = async_std::channel::unbounded::<Result<model::Track, Error>>();
+
+        async_std::task::spawn(async move {
+            let mut tracks_stream =
+                sqlx::query_as::<_, model::Track>("SELECT * from tracks;").fetch(&mut *conn);
+
+            while let Some(track) = tracks_stream.next().await {
+                match track {
+                    Ok(track) => tx.send(Ok(track)).await.unwrap(),
+                    Err(e) => {
+                        tx.send(Err(e)).await.unwrap();
+                        tx.close();
+                        break;
+                    }
+                }
+            }
+
+            tx.close();
+        });
+
+        Ok(rx)
+    }
+
+    pub async fn filter(&self) -> Result<Option<String>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(sqlx::query!(
+            r#"
+                select filter from state;
+            "#,
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .filter)
+    }
+
+    pub async fn set_filter(&self, filter: String) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        set_filter(&mut conn, &filter).await
+    }
+
+    /// Runs SQLite's integrity check, returning the problems found.
+    pub async fn integrity_check(&self) -> Result<Vec<String>, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        let res = sqlx::query_scalar::<_, String>("PRAGMA integrity_check;")
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Ok(res.into_iter().filter(|r| r != "ok").collect())
+    }
+
+    /// Returns whether the full-text index matches the tracks table.
+    pub async fn fts_intact(&self) -> Result<bool, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        match sqlx::query("INSERT INTO track_fts(track_fts, rank) VALUES ('integrity-check', 1);")
+            .execute(&mut *conn)
+            .await
+        {
+            Ok(_) => Ok(true),
+            // SQLite reports a drifted index as a corrupt virtual table.
+            Err(Error::Database(e)) if e.message().contains("malformed") => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rebuilds the full-text index from the tracks table.
+    pub async fn rebuild_fts(&self) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query!(r#"INSERT INTO track_fts(track_fts) VALUES ('rebuild');"#)
+            .execute(&mut *conn)
+            .await?;
+
         Ok(())
     }
+
+    /// Returns how many rows the state table has, which should be one.
+    pub async fn state_rows(&self) -> Result<i64, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(
+            sqlx::query!(r#"SELECT COUNT(*) AS "count!: i64" FROM state;"#)
+                .fetch_one(&mut *conn)
+                .await?
+                .count,
+        )
+    }
+
+    /// Deletes every state row but the first one.
+    pub async fn dedupe_state(&self) -> Result<u64, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(
+            sqlx::query!(r#"DELETE FROM state WHERE id != (SELECT MIN(id) FROM state);"#)
+                .execute(&mut *conn)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    /// Returns how many bytes of the destination's scrobbler log were read, see history.
+    pub async fn scrobbler_log_read(&self) -> Result<i64, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        Ok(sqlx::query!(
+            r#"
+                select scrobbler_log_read from state;
+            "#,
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .scrobbler_log_read)
+    }
+
+    pub async fn set_scrobbler_log_read(&self, read: i64) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        set_scrobbler_log_read(&mut conn, read).await
+    }
+
+    /// Returns how tracks are laid out on this destination.
+    pub async fn layout(&self) -> Result<model::Layout, Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        let state = sqlx::query!(
+            r#"
+                select compilations, folder_names, path_form from state;
+            "#,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(model::Layout {
+            compilations: state.compilations,
+            folder_names: match state.folder_names.as_deref() {
+                Some("sort") => model::FolderNames::Sort,
+                _ => model::FolderNames::Display,
+            },
+            path_form: match state.path_form.as_deref() {
+                Some("nfd") => model::PathForm::Nfd,
+                Some("tagged") => model::PathForm::Tagged,
+                _ => model::PathForm::Nfc,
+            },
+        })
+    }
+
+    pub async fn set_layout(&self, layout: &model::Layout) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
+
+        set_layout(&mut conn, layout).await
+    }
+}
+
+impl Transaction {
+    pub async fn commit(self) -> Result<(), Error> {
+        self.0.commit().await
+    }
+
+    pub async fn insert_track(&mut self, track: &model::Track) -> Result<i64, Error> {
+        insert_track(&mut self.0, track).await
+    }
+
+    /// Returns the tracks with the given size and content hash, which are copies of the same
+    /// file.
+    pub async fn tracks_by_content(
+        &mut self,
+        file_size: i64,
+        content_hash: &str,
+    ) -> Result<Vec<model::Track>, Error> {
+        tracks_by_content(&mut self.0, file_size, content_hash).await
+    }
+
+    /// Returns the tracks with the given audio hash.
+    pub async fn tracks_by_audio(&mut self, audio_hash: &str) -> Result<Vec<model::Track>, Error> {
+        tracks_by_audio(&mut self.0, audio_hash).await
+    }
+
+    pub async fn set_hashes(
+        &mut self,
+        id: i64,
+        file_size: Option<i64>,
+        content_hash: Option<&str>,
+        audio_hash: Option<&str>,
+    ) -> Result<(), Error> {
+        set_hashes(&mut self.0, id, file_size, content_hash, audio_hash).await
+    }
+
+    pub async fn set_file_path(&mut self, id: i64, path: &model::FilePath) -> Result<(), Error> {
+        set_file_path(&mut self.0, id, path).await
+    }
+
+    pub async fn delete(&mut self, id: i64) -> Result<(), Error> {
+        delete(&mut self.0, id).await
+    }
+
+    pub async fn insert_directory(&mut self, directory: &model::Directory) -> Result<(), Error> {
+        insert_directory(&mut self.0, directory).await
+    }
+
+    pub async fn insert_suppression(&mut self, s: &model::Suppression) -> Result<(), Error> {
+        insert_suppression(&mut self.0, s).await
+    }
+
+    pub async fn insert_alias(&mut self, alias: &model::Alias) -> Result<u64, Error> {
+        insert_alias(&mut self.0, alias).await
+    }
+
+    pub async fn insert_plays(&mut self, plays: &[model::Play]) -> Result<u64, Error> {
+        insert_plays(&mut self.0, plays).await
+    }
+
+    pub async fn insert_ratings(&mut self, ratings: &[model::Rating]) -> Result<(), Error> {
+        insert_ratings(&mut self.0, ratings).await
+    }
+
+    pub async fn insert_album_ratings(
+        &mut self,
+        ratings: &[model::AlbumRating],
+    ) -> Result<(), Error> {
+        insert_album_ratings(&mut self.0, ratings).await
+    }
+
+    pub async fn insert_labels(&mut self, labels: &[model::Label]) -> Result<u64, Error> {
+        insert_labels(&mut self.0, labels).await
+    }
+
+    pub async fn set_filter(&mut self, filter: &str) -> Result<(), Error> {
+        set_filter(&mut self.0, filter).await
+    }
+
+    pub async fn set_layout(&mut self, layout: &model::Layout) -> Result<(), Error> {
+        set_layout(&mut self.0, layout).await
+    }
+
+    pub async fn set_scrobbler_log_read(&mut self, read: i64) -> Result<(), Error> {
+        set_scrobbler_log_read(&mut self.0, read).await
+    }
+
+    /// Deletes all the tracks, leaving source directories and filters untouched.
+    pub async fn clear_tracks(&mut self) -> Result<(), Error> {
+        clear_tracks(&mut self.0).await
+    }
+}
+
+// Queries run by Instance on a connection of its own, or by Transaction within itself.
+
+fn connect_options(path: &std::path::Path, is_external: bool) -> SqliteConnectOptions {
+    let options = SqliteConnectOptions::new().filename(path);
+
+    // Destinations are often unplugged right after a sync: their database is written in place,
+    // so that no write-ahead log or shared memory file is left behind next to it.
+    if is_external {
+        return options
+            .journal_mode(SqliteJournalMode::Delete)
+            .synchronous(SqliteSynchronous::Full);
+    }
+
+    // Commits append to the write-ahead log, which is synced at checkpoints only:
+    // a crash may lose the last commits, but never leaves the database corrupt.
+    options
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+}
+
+async fn exists(conn: &mut SqliteConnection, path: &model::FilePath) -> Result<bool, Error> {
+    match sqlx::query!(
+        r#"
+        SELECT id FROM tracks WHERE file_path = ?1;
+        "#,
+        path,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    {
+        Ok(_) => Ok(true),
+        Err(err) => match err {
+            Error::RowNotFound => Ok(false),
+            rest => Err(rest),
+        },
+    }
+}
+
+async fn insert_track(conn: &mut SqliteConnection, track: &model::Track) -> Result<i64, Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO tracks (
+            track_id,
+            title,
+            artist,
+            album,
+            number,
+            file_path,
+            disc_number,
+            disc_total,
+            file_state,
+            extension,
+            year,
+            genre,
+            composer,
+            track_total,
+            duration,
+            bitrate,
+            sample_rate,
+            bit_depth,
+            channels,
+            file_size,
+            replaygain_track_gain,
+            replaygain_track_peak,
+            replaygain_album_gain,
+            replaygain_album_peak,
+            musicbrainz_track_id,
+            musicbrainz_album_id,
+            musicbrainz_artist_id,
+            musicbrainz_album_artist_id,
+            content_hash,
+            audio_hash,
+            stored_path,
+            track_artist,
+            compilation,
+            tagged_artist,
+            artist_sort
+        ) VALUES (
+            ?1,
+            ?2,
+            COALESCE((SELECT artist FROM artist_aliases WHERE alias = ?3), ?3),
+            ?4,
+            ?5,
+            ?6,
+            ?7,
+            ?8,
+            ?9,
+            ?10,
+            ?11,
+            ?12,
+            ?13,
+            ?14,
+            ?15,
+            ?16,
+            ?17,
+            ?18,
+            ?19,
+            ?20,
+            ?21,
+            ?22,
+            ?23,
+            ?24,
+            ?25,
+            ?26,
+            ?27,
+            ?28,
+            ?29,
+            ?30,
+            ?31,
+            ?32,
+            ?33,
+            ?34,
+            ?35
+        )
+        ON CONFLICT (file_path, COALESCE(stored_path, X'')) DO UPDATE SET
+            track_id = excluded.track_id,
+            title = excluded.title,
+            artist = excluded.artist,
+            album = excluded.album,
+            number = excluded.number,
+            disc_number = excluded.disc_number,
+            disc_total = excluded.disc_total,
+            file_state = excluded.file_state,
+            extension = excluded.extension,
+            year = excluded.year,
+            genre = excluded.genre,
+            composer = excluded.composer,
+            track_total = excluded.track_total,
+            duration = excluded.duration,
+            bitrate = excluded.bitrate,
+            sample_rate = excluded.sample_rate,
+            bit_depth = excluded.bit_depth,
+            channels = excluded.channels,
+            file_size = excluded.file_size,
+            replaygain_track_gain = excluded.replaygain_track_gain,
+            replaygain_track_peak = excluded.replaygain_track_peak,
+            replaygain_album_gain = excluded.replaygain_album_gain,
+            replaygain_album_peak = excluded.replaygain_album_peak,
+            musicbrainz_track_id = excluded.musicbrainz_track_id,
+            musicbrainz_album_id = excluded.musicbrainz_album_id,
+            musicbrainz_artist_id = excluded.musicbrainz_artist_id,
+            musicbrainz_album_artist_id = excluded.musicbrainz_album_artist_id,
+            content_hash = excluded.content_hash,
+            audio_hash = excluded.audio_hash,
+            track_artist = excluded.track_artist,
+            compilation = excluded.compilation,
+            tagged_artist = excluded.tagged_artist,
+            artist_sort = excluded.artist_sort
+        RETURNING id AS "id!: i64";
+        "#,
+        track.track_id,
+        track.title,
+        track.artist,
+        track.album,
+        track.number,
+        track.file_path,
+        track.disc_number,
+        track.disc_total,
+        track.file_state,
+        track.extension,
+        track.year,
+        track.genre,
+        track.composer,
+        track.track_total,
+        track.duration,
+        track.bitrate,
+        track.sample_rate,
+        track.bit_depth,
+        track.channels,
+        track.file_size,
+        track.replaygain_track_gain,
+        track.replaygain_track_peak,
+        track.replaygain_album_gain,
+        track.replaygain_album_peak,
+        track.musicbrainz_track_id,
+        track.musicbrainz_album_id,
+        track.musicbrainz_artist_id,
+        track.musicbrainz_album_artist_id,
+        track.content_hash,
+        track.audio_hash,
+        track.stored_path,
+        track.track_artist,
+        track.compilation,
+        track.tagged_artist,
+        track.artist_sort,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map(|r| r.id)
+}
+
+async fn tracks_by_content(
+    conn: &mut SqliteConnection,
+    file_size: i64,
+    content_hash: &str,
+) -> Result<Vec<model::Track>, Error> {
+    sqlx::query_as::<_, model::Track>(
+        r#"
+        SELECT * FROM tracks WHERE file_size = ?1 AND content_hash = ?2;
+        "#,
+    )
+    .bind(file_size)
+    .bind(content_hash)
+    .fetch_all(&mut *conn)
+    .await
+}
+
+async fn tracks_by_audio(
+    conn: &mut SqliteConnection,
+    audio_hash: &str,
+) -> Result<Vec<model::Track>, Error> {
+    sqlx::query_as::<_, model::Track>(
+        r#"
+        SELECT * FROM tracks WHERE audio_hash = ?1;
+        "#,
+    )
+    .bind(audio_hash)
+    .fetch_all(&mut *conn)
+    .await
+}
+
+async fn set_hashes(
+    conn: &mut SqliteConnection,
+    id: i64,
+    file_size: Option<i64>,
+    content_hash: Option<&str>,
+    audio_hash: Option<&str>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        UPDATE tracks SET file_size = ?2, content_hash = ?3, audio_hash = ?4 WHERE id = ?1;
+        "#,
+        id,
+        file_size,
+        content_hash,
+        audio_hash,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn set_file_path(
+    conn: &mut SqliteConnection,
+    id: i64,
+    path: &model::FilePath,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        UPDATE tracks SET file_path = ?2 WHERE id = ?1;
+        "#,
+        id,
+        path,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn insert_suppression(
+    conn: &mut SqliteConnection,
+    s: &model::Suppression,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (
+            track_key,
+            file_path,
+            kept_path,
+            reason,
+            quarantine_path,
+            created_at
+        ) VALUES (
+            ?1,
+            ?2,
+            ?3,
+            ?4,
+            ?5,
+            COALESCE(NULLIF(?6, ''), CURRENT_TIMESTAMP)
+        );
+        "#,
+        s.track_key,
+        s.file_path,
+        s.kept_path,
+        s.reason,
+        s.quarantine_path,
+        s.created_at,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn clear_tracks(conn: &mut SqliteConnection) -> Result<(), Error> {
+    sqlx::query!(r#"DELETE FROM tracks;"#)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+async fn delete(conn: &mut SqliteConnection, id: i64) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM tracks WHERE id = ?1;
+        "#,
+        id,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn insert_directory(
+    conn: &mut SqliteConnection,
+    directory: &model::Directory,
+) -> Result<(), Error> {
+    let path: model::FilePath = directory.directory.as_path().into();
+    let include = join_patterns(&directory.include);
+    let exclude = join_patterns(&directory.exclude);
+
+    sqlx::query!(
+        r#"
+        INSERT OR REPLACE INTO directories (
+            directory,
+            include_patterns,
+            exclude_patterns,
+            follow_links,
+            same_file_system
+        ) VALUES (
+            ?1,
+            ?2,
+            ?3,
+            ?4,
+            ?5
+        );
+        "#,
+        path,
+        include,
+        exclude,
+        directory.follow_links,
+        directory.same_file_system,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Directory patterns are stored newline-separated, globs can't reasonably contain one.
+fn split_patterns(raw: Option<String>) -> Vec<String> {
+    raw.unwrap_or_default()
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_owned())
+        .collect()
+}
+
+fn join_patterns(patterns: &[String]) -> Option<String> {
+    match patterns.is_empty() {
+        true => None,
+        false => Some(patterns.join("\n")),
+    }
+}
+
+async fn insert_alias(conn: &mut SqliteConnection, alias: &model::Alias) -> Result<u64, Error> {
+    sqlx::query!(
+        r#"INSERT OR REPLACE INTO artist_aliases (alias, artist) VALUES (?1, ?2);"#,
+        alias.alias,
+        alias.artist,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(sqlx::query!(
+        r#"UPDATE tracks SET artist = ?2 WHERE tagged_artist = ?1 COLLATE NOCASE;"#,
+        alias.alias,
+        alias.artist,
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected())
+}
+
+async fn insert_plays(conn: &mut SqliteConnection, plays: &[model::Play]) -> Result<u64, Error> {
+    let mut inserted = 0;
+
+    for p in plays {
+        inserted += sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO plays (track_key, played_at) VALUES (?1, ?2);
+            "#,
+            p.track_key,
+            p.played_at,
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    }
+
+    Ok(inserted)
+}
+
+async fn insert_labels(conn: &mut SqliteConnection, labels: &[model::Label]) -> Result<u64, Error> {
+    let mut inserted = 0;
+
+    for p in labels {
+        inserted += sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO labels (track_key, label) VALUES (?1, ?2);
+            "#,
+            p.track_key,
+            p.label,
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    }
+
+    Ok(inserted)
+}
+
+async fn insert_ratings(
+    conn: &mut SqliteConnection,
+    ratings: &[model::Rating],
+) -> Result<(), Error> {
+    for p in ratings {
+        sqlx::query!(
+            r#"
+            INSERT INTO ratings (track_key, rating) VALUES (?1, ?2)
+            ON CONFLICT (track_key) DO UPDATE SET rating = excluded.rating;
+            "#,
+            p.track_key,
+            p.rating,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_album_ratings(
+    conn: &mut SqliteConnection,
+    ratings: &[model::AlbumRating],
+) -> Result<(), Error> {
+    for p in ratings {
+        sqlx::query!(
+            r#"
+            INSERT INTO album_ratings (artist, album, rating) VALUES (?1, ?2, ?3)
+            ON CONFLICT (artist, album) DO UPDATE SET rating = excluded.rating;
+            "#,
+            p.artist,
+            p.album,
+            p.rating,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn set_filter(conn: &mut SqliteConnection, filter: &str) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        update state set filter = ?1;"#,
+        filter,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn set_scrobbler_log_read(conn: &mut SqliteConnection, read: i64) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        update state set scrobbler_log_read = ?1;"#,
+        read,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn set_layout(conn: &mut SqliteConnection, layout: &model::Layout) -> Result<(), Error> {
+    let folder_names = match layout.folder_names {
+        model::FolderNames::Display => "display",
+        model::FolderNames::Sort => "sort",
+    };
+
+    let path_form = match layout.path_form {
+        model::PathForm::Nfc => "nfc",
+        model::PathForm::Nfd => "nfd",
+        model::PathForm::Tagged => "tagged",
+    };
+
+    sqlx::query!(
+        r#"
+        update state set compilations = ?1, folder_names = ?2, path_form = ?3;"#,
+        layout.compilations,
+        folder_names,
+        path_form,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[async_std::test]
+    async fn relocate_moves_suppressions() {
+        let dir = std::env::temp_dir().join(format!("tracksync-relocate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let db = Instance::new(dir.to_str().unwrap(), false).await.unwrap();
+
+        let suppression = |file: &str, kept: &str, quarantine: Option<&str>| model::Suppression {
+            track_key: file.to_owned(),
+            file_path: Path::new(file).into(),
+            kept_path: Path::new(kept).into(),
+            quarantine_path: quarantine.map(|q| Path::new(q).into()),
+            reason: "test".to_owned(),
+            ..Default::default()
+        };
+
+        let mut tx = db.begin().await.unwrap();
+
+        for s in [
+            suppression(
+                "/old/a.mp3",
+                "/old/a.flac",
+                Some("/old/quarantine/old/a.mp3"),
+            ),
+            suppression("/old/b.mp3", "/other/b.flac", None),
+            suppression("/older/c.mp3", "/older/c.flac", Some("/q/c.mp3")),
+        ] {
+            tx.insert_suppression(&s).await.unwrap();
+        }
+
+        tx.commit().await.unwrap();
+
+        db.relocate(Path::new("/old"), Path::new("/new"))
+            .await
+            .unwrap();
+
+        let paths: Vec<_> = db
+            .suppressions()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| {
+                (
+                    s.file_path.to_string(),
+                    s.kept_path.to_string(),
+                    s.quarantine_path.map(|q| q.to_string()),
+                )
+            })
+            .collect();
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            paths,
+            [
+                (
+                    "/new/a.mp3".to_owned(),
+                    "/new/a.flac".to_owned(),
+                    Some("/new/quarantine/old/a.mp3".to_owned())
+                ),
+                ("/new/b.mp3".to_owned(), "/other/b.flac".to_owned(), None),
+                (
+                    "/older/c.mp3".to_owned(),
+                    "/older/c.flac".to_owned(),
+                    Some("/q/c.mp3".to_owned())
+                ),
+            ]
+        );
+    }
 }
@@ -1,6 +1,7 @@
 mod instance;
 mod lib;
+mod planner;
 
-pub use instance::Instance;
+pub use instance::{Instance, Transaction};
 pub use lib::default_database_dir;
-pub use lib::diff;
+pub use planner::{Planner, Rename};
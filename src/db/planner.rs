@@ -0,0 +1,489 @@
+use futures::{stream::BoxStream, StreamExt};
+use sqlx::{Connection, Error, FromRow, Row, SqliteConnection};
+
+use crate::model;
+
+/// Compares the tracks of the local database with a destination's, within SQLite: the
+/// destination database is attached to a connection of the local one.
+/// Tracks are matched by key, see model::Track::key, spelled the way the tracks_key index
+/// expects. Sets are streamed rather than loaded, libraries can be large.
+pub struct Planner {
+    conn: SqliteConnection,
+}
+
+/// A copied track whose local track was retagged or moved since.
+pub struct Rename {
+    /// The track on the destination.
+    pub track: model::Track,
+    pub local_id: i64,
+    /// Where the local track is now.
+    pub file_path: model::FilePath,
+}
+
+impl Planner {
+    pub(super) async fn new(
+        mut conn: SqliteConnection,
+        destination: &std::path::Path,
+    ) -> Result<Planner, Error> {
+        let destination = destination.to_str().ok_or_else(|| {
+            Error::Protocol(format!("{} is not valid UTF-8", destination.display()))
+        })?;
+
+        sqlx::query("ATTACH DATABASE ?1 AS dest;")
+            .bind(destination)
+            .execute(&mut conn)
+            .await?;
+
+        sqlx::query("CREATE TEMP TABLE filtered_keys (key TEXT PRIMARY KEY NOT NULL);")
+            .execute(&mut conn)
+            .await?;
+
+        Ok(Planner { conn })
+    }
+
+    /// Returns the local tracks missing from the destination, suppressed ones aside, along with
+    /// their plays, ratings and labels.
+    pub fn copies(&mut self) -> BoxStream<'_, Result<model::Track, Error>> {
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT l.* FROM main.annotated_tracks AS l
+            WHERE l.file_state = ?1
+            AND NOT EXISTS (
+                SELECT 1 FROM dest.tracks AS d
+                WHERE COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                    = COALESCE(l.audio_hash, l.track_id || '-' || l.disc_number || '-' || l.number)
+                AND d.file_state = ?1
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM main.suppressions AS s
+                WHERE s.track_key = COALESCE(l.audio_hash, l.track_id || '-' || l.disc_number || '-' || l.number)
+            )
+            ORDER BY l.id;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .fetch(&mut self.conn)
+    }
+
+    /// Returns the local tracks already on the destination, along with their plays,
+    /// ratings and labels.
+    pub fn synced(&mut self) -> BoxStream<'_, Result<model::Track, Error>> {
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT l.* FROM main.annotated_tracks AS l
+            WHERE l.file_state = ?1
+            AND EXISTS (
+                SELECT 1 FROM dest.tracks AS d
+                WHERE COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                    = COALESCE(l.audio_hash, l.track_id || '-' || l.disc_number || '-' || l.number)
+                AND d.file_state = ?1
+            )
+            ORDER BY l.id;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .fetch(&mut self.conn)
+    }
+
+    /// Records the keys of tracks the destination's filters leave out, for deletions.
+    pub async fn mark_filtered(
+        &mut self,
+        keys: impl IntoIterator<Item = String>,
+    ) -> Result<(), Error> {
+        let mut tx = self.conn.begin().await?;
+
+        for key in keys {
+            sqlx::query("INSERT OR IGNORE INTO temp.filtered_keys (key) VALUES (?1);")
+                .bind(key)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Returns the destination tracks that aren't in the library anymore, were suppressed, or
+    /// were marked as filtered out.
+    pub fn deletions(&mut self) -> BoxStream<'_, Result<model::Track, Error>> {
+        sqlx::query_as::<_, model::Track>(
+            r#"
+            SELECT d.* FROM dest.tracks AS d
+            WHERE NOT EXISTS (
+                SELECT 1 FROM main.tracks AS l
+                WHERE COALESCE(l.audio_hash, l.track_id || '-' || l.disc_number || '-' || l.number)
+                    = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                AND l.file_state = ?1
+            )
+            OR EXISTS (
+                SELECT 1 FROM main.suppressions AS s
+                WHERE s.track_key = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+            )
+            OR EXISTS (
+                SELECT 1 FROM temp.filtered_keys AS f
+                WHERE f.key = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+            )
+            ORDER BY d.id;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .fetch(&mut self.conn)
+    }
+
+    /// Returns the destination tracks whose local track has other tags, or another path, than
+    /// when it was copied.
+    /// Among local tracks sharing a key, the one at the same path is preferred, then the one
+    /// with the same tags.
+    pub fn renames(&mut self) -> BoxStream<'_, Result<Rename, Error>> {
+        sqlx::query(
+            r#"
+            SELECT d.*, l.id AS local_id, l.file_path AS local_file_path
+            FROM dest.tracks AS d, main.tracks AS l
+            WHERE d.file_state = ?1
+            AND l.id = COALESCE(
+                (
+                    SELECT MIN(c.id) FROM main.tracks AS c
+                    WHERE COALESCE(c.audio_hash, c.track_id || '-' || c.disc_number || '-' || c.number)
+                        = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                    AND c.file_state = ?1 AND c.file_path = d.file_path
+                ),
+                (
+                    SELECT MIN(c.id) FROM main.tracks AS c
+                    WHERE COALESCE(c.audio_hash, c.track_id || '-' || c.disc_number || '-' || c.number)
+                        = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                    AND c.file_state = ?1 AND c.track_id = d.track_id
+                ),
+                (
+                    SELECT MIN(c.id) FROM main.tracks AS c
+                    WHERE COALESCE(c.audio_hash, c.track_id || '-' || c.disc_number || '-' || c.number)
+                        = COALESCE(d.audio_hash, d.track_id || '-' || d.disc_number || '-' || d.number)
+                    AND c.file_state = ?1
+                )
+            )
+            AND (l.track_id != d.track_id OR l.file_path != d.file_path)
+            ORDER BY d.id;
+            "#,
+        )
+        .bind(model::FileState::Copied)
+        .fetch(&mut self.conn)
+        .map(|row| {
+            let row = row?;
+
+            Ok(Rename {
+                track: model::Track::from_row(&row)?,
+                local_id: row.try_get("local_id")?,
+                file_path: row.try_get("local_file_path")?,
+            })
+        })
+        .boxed()
+    }
+
+    /// Returns the local track with the given id, e.g. the one a rename takes its tags from.
+    pub async fn local_track(&mut self, id: i64) -> Result<model::Track, Error> {
+        sqlx::query_as::<_, model::Track>("SELECT * FROM main.tracks WHERE id = ?1;")
+            .bind(id)
+            .fetch_one(&mut self.conn)
+            .await
+    }
+
+    /// Updates a destination track with the tags and path of its local track, its file being
+    /// at stored_path.
+    pub async fn rename(
+        &mut self,
+        rename: &Rename,
+        stored_path: &model::FilePath,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            r#"
+            UPDATE dest.tracks AS d SET
+                track_id = l.track_id,
+                title = l.title,
+                artist = l.artist,
+                album = l.album,
+                number = l.number,
+                file_path = l.file_path,
+                disc_number = l.disc_number,
+                disc_total = l.disc_total,
+                year = l.year,
+                genre = l.genre,
+                composer = l.composer,
+                track_total = l.track_total,
+                replaygain_track_gain = l.replaygain_track_gain,
+                replaygain_track_peak = l.replaygain_track_peak,
+                replaygain_album_gain = l.replaygain_album_gain,
+                replaygain_album_peak = l.replaygain_album_peak,
+                musicbrainz_track_id = l.musicbrainz_track_id,
+                musicbrainz_album_id = l.musicbrainz_album_id,
+                musicbrainz_artist_id = l.musicbrainz_artist_id,
+                musicbrainz_album_artist_id = l.musicbrainz_album_artist_id,
+                track_artist = l.track_artist,
+                compilation = l.compilation,
+                tagged_artist = l.tagged_artist,
+                artist_sort = l.artist_sort,
+                stored_path = ?3
+            FROM main.tracks AS l
+            WHERE d.id = ?1 AND l.id = ?2;
+            "#,
+        )
+        .bind(rename.track.id)
+        .bind(rename.local_id)
+        .bind(stored_path)
+        .execute(&mut self.conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Instance;
+    use futures::TryStreamExt;
+
+    /// A local and a destination database in a directory of their own, removed when dropped.
+    struct Databases {
+        dir: std::path::PathBuf,
+        local: Instance,
+        dest: Instance,
+    }
+
+    impl Databases {
+        async fn new(name: &str) -> Databases {
+            let dir = std::env::temp_dir()
+                .join(format!("tracksync-planner-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+
+            for sub in ["local", "dest"] {
+                std::fs::create_dir_all(dir.join(sub)).unwrap();
+            }
+
+            let local = Instance::new(dir.join("local").to_str().unwrap(), false)
+                .await
+                .unwrap();
+            let dest = Instance::new(dir.join("dest").to_str().unwrap(), true)
+                .await
+                .unwrap();
+
+            Databases { dir, local, dest }
+        }
+
+        async fn planner(&self) -> Planner {
+            self.local.planner(&self.dest).await.unwrap()
+        }
+    }
+
+    impl Drop for Databases {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn track(title: &str, audio_hash: Option<&str>) -> model::Track {
+        model::Track {
+            track_id: format!("id of {title}"),
+            title: title.to_owned(),
+            artist: "Artist".to_owned(),
+            album: "Album".to_owned(),
+            number: 1,
+            disc_number: 1,
+            file_path: std::path::Path::new(&format!("/music/{title}.flac")).into(),
+            extension: "flac".to_owned(),
+            audio_hash: audio_hash.map(|h| h.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn titles(tracks: &[model::Track]) -> Vec<&str> {
+        tracks.iter().map(|t| t.title.as_str()).collect()
+    }
+
+    #[async_std::test]
+    async fn copies_what_is_missing() {
+        let dbs = Databases::new("copies").await;
+
+        for t in ["a", "b", "c"] {
+            dbs.local.insert_track(&track(t, Some(t))).await.unwrap();
+        }
+
+        // Retagged since it was copied: it's the same track.
+        dbs.dest
+            .insert_track(&track("old a", Some("a")))
+            .await
+            .unwrap();
+
+        let mut tx = dbs.local.begin().await.unwrap();
+
+        tx.insert_suppression(&model::Suppression {
+            track_key: "c".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut planner = dbs.planner().await;
+
+        let copies: Vec<_> = planner.copies().try_collect().await.unwrap();
+        let synced: Vec<_> = planner.synced().try_collect().await.unwrap();
+
+        assert_eq!(titles(&copies), ["b"]);
+        assert_eq!(titles(&synced), ["a"]);
+    }
+
+    #[async_std::test]
+    async fn unhashed_tracks_are_told_apart_by_number() {
+        let dbs = Databases::new("unhashed").await;
+
+        let first = track("intro", None);
+        let second = model::Track {
+            disc_number: 2,
+            file_path: std::path::Path::new("/music/intro 2.flac").into(),
+            ..track("intro", None)
+        };
+
+        dbs.local.insert_track(&first).await.unwrap();
+        dbs.local.insert_track(&second).await.unwrap();
+        dbs.dest.insert_track(&first).await.unwrap();
+
+        let copies: Vec<_> = dbs.planner().await.copies().try_collect().await.unwrap();
+
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].disc_number, 2);
+        assert_eq!(copies[0].key(), second.key());
+    }
+
+    #[async_std::test]
+    async fn deletes_what_is_gone_suppressed_or_filtered() {
+        let dbs = Databases::new("deletions").await;
+
+        for t in ["kept", "suppressed", "filtered"] {
+            dbs.local.insert_track(&track(t, Some(t))).await.unwrap();
+        }
+
+        for t in ["kept", "suppressed", "filtered", "gone"] {
+            dbs.dest.insert_track(&track(t, Some(t))).await.unwrap();
+        }
+
+        let mut tx = dbs.local.begin().await.unwrap();
+
+        tx.insert_suppression(&model::Suppression {
+            track_key: "suppressed".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut planner = dbs.planner().await;
+
+        planner
+            .mark_filtered(["filtered".to_owned()])
+            .await
+            .unwrap();
+
+        let deletions: Vec<_> = planner.deletions().try_collect().await.unwrap();
+
+        assert_eq!(titles(&deletions), ["suppressed", "filtered", "gone"]);
+    }
+
+    #[async_std::test]
+    async fn renames_retagged_and_moved_tracks() {
+        let dbs = Databases::new("renames").await;
+
+        let moved = model::Track {
+            file_path: std::path::Path::new("/elsewhere/moved.flac").into(),
+            ..track("moved", Some("moved"))
+        };
+
+        for t in [
+            track("same", Some("same")),
+            track("new", Some("retagged")),
+            moved,
+        ] {
+            dbs.local.insert_track(&t).await.unwrap();
+        }
+
+        for t in [
+            track("same", Some("same")),
+            track("old", Some("retagged")),
+            track("moved", Some("moved")),
+        ] {
+            dbs.dest.insert_track(&t).await.unwrap();
+        }
+
+        let mut planner = dbs.planner().await;
+        let renames: Vec<_> = planner.renames().try_collect().await.unwrap();
+
+        let found: Vec<_> = renames
+            .iter()
+            .map(|r| (r.track.title.as_str(), r.file_path.to_string()))
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                ("old", "/music/new.flac".to_owned()),
+                ("moved", "/elsewhere/moved.flac".to_owned())
+            ]
+        );
+
+        let stored_path = std::path::Path::new("Artist/Album/1/new.flac").into();
+        planner.rename(&renames[0], &stored_path).await.unwrap();
+
+        let local = planner.local_track(renames[0].local_id).await.unwrap();
+        assert_eq!(local.title, "new");
+
+        let renamed = dbs.dest.tracks_by_key("retagged").await.unwrap();
+        assert_eq!(titles(&renamed), ["new"]);
+        assert_eq!(renamed[0].stored_path, Some(stored_path));
+    }
+
+    #[async_std::test]
+    async fn album_plays_count_each_track_once() {
+        let dbs = Databases::new("plays").await;
+
+        let copy = model::Track {
+            file_path: std::path::Path::new("/backup/a.flac").into(),
+            ..track("a", Some("a"))
+        };
+
+        for t in [track("a", Some("a")), copy, track("b", Some("b"))] {
+            dbs.local.insert_track(&t).await.unwrap();
+        }
+
+        dbs.local
+            .insert_plays(&[
+                model::Play {
+                    track_key: "a".to_owned(),
+                    played_at: 1,
+                },
+                model::Play {
+                    track_key: "b".to_owned(),
+                    played_at: 2,
+                },
+            ])
+            .await
+            .unwrap();
+
+        let copies: Vec<_> = dbs.planner().await.copies().try_collect().await.unwrap();
+
+        assert_eq!(copies.len(), 3);
+
+        for t in copies {
+            assert_eq!(t.play_count, 1);
+            assert_eq!(t.album_play_count, 2);
+        }
+    }
+    #[async_std::test]
+    async fn destination_path_must_be_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let conn = SqliteConnection::connect(":memory:").await.unwrap();
+        let destination = std::path::Path::new(std::ffi::OsStr::from_bytes(b"/mnt/\xff"));
+
+        assert!(matches!(
+            Planner::new(conn, destination).await,
+            Err(Error::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{db, model};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+/// Version of the dump format, bumped whenever a change would make older versions of tracksync
+/// misread dumps.
+/// Dumps of older versions stay readable: fields they lack take their default value.
+pub const VERSION: u32 = 1;
+
+// Files of a CSV dump.
+const STATE_FILE: &str = "state.csv";
+const DIRECTORIES_FILE: &str = "directories.csv";
+const ALIASES_FILE: &str = "aliases.csv";
+const SUPPRESSIONS_FILE: &str = "suppressions.csv";
+const TRACKS_FILE: &str = "tracks.csv";
+const PLAYS_FILE: &str = "plays.csv";
+const RATINGS_FILE: &str = "ratings.csv";
+const ALBUM_RATINGS_FILE: &str = "album_ratings.csv";
+const LABELS_FILE: &str = "labels.csv";
+
+/// The content of a database, in a form that doesn't depend on its schema.
+/// Fingerprints are left out, they're recomputed when needed.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Dump {
+    pub version: u32,
+    #[serde(flatten)]
+    pub state: State,
+    #[serde(default)]
+    pub directories: Vec<model::Directory>,
+    #[serde(default)]
+    pub aliases: Vec<model::Alias>,
+    #[serde(default)]
+    pub suppressions: Vec<model::Suppression>,
+    #[serde(default)]
+    pub tracks: Vec<model::Track>,
+    #[serde(default)]
+    pub plays: Vec<model::Play>,
+    #[serde(default)]
+    pub ratings: Vec<model::Rating>,
+    #[serde(default)]
+    pub album_ratings: Vec<model::AlbumRating>,
+    #[serde(default)]
+    pub labels: Vec<model::Label>,
+}
+
+/// Settings of a database.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct State {
+    /// Whether the database is a destination's.
+    pub external: bool,
+    pub filter: Option<String>,
+    pub compilations: Option<String>,
+    pub folder_names: model::FolderNames,
+    pub path_form: model::PathForm,
+    /// How many bytes of the destination's scrobbler log were read.
+    pub scrobbler_log_read: i64,
+}
+
+/// A row of directories.csv: CSV has no lists, patterns are newline-separated.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DirectoryRow {
+    directory: model::FilePath,
+    include: String,
+    exclude: String,
+    follow_links: bool,
+    same_file_system: bool,
+}
+
+/// The single row of state.csv, CSV doesn't support flattening State.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StateRow {
+    version: u32,
+    external: bool,
+    filter: Option<String>,
+    compilations: Option<String>,
+    folder_names: model::FolderNames,
+    path_form: model::PathForm,
+    #[serde(default)]
+    scrobbler_log_read: i64,
+}
+
+impl Dump {
+    pub async fn read(db: &db::Instance, external: bool) -> Result<Self> {
+        let layout = db.layout().await.with_context(|| "Cannot fetch layout")?;
+
+        Ok(Self {
+            version: VERSION,
+            state: State {
+                external,
+                filter: db.filter().await.with_context(|| "Cannot fetch filters")?,
+                compilations: layout.compilations,
+                folder_names: layout.folder_names,
+                path_form: layout.path_form,
+                scrobbler_log_read: db
+                    .scrobbler_log_read()
+                    .await
+                    .with_context(|| "Cannot fetch scrobbler log offset")?,
+            },
+            directories: db
+                .directories()
+                .await
+                .with_context(|| "Cannot fetch source directories")?,
+            aliases: db
+                .aliases()
+                .await
+                .with_context(|| "Cannot fetch artist aliases")?,
+            suppressions: db
+                .suppressions()
+                .await
+                .with_context(|| "Cannot fetch suppressed tracks")?,
+            tracks: db.tracks().await.with_context(|| "Cannot fetch tracks")?,
+            plays: db.plays().await.with_context(|| "Cannot fetch plays")?,
+            ratings: db.ratings().await.with_context(|| "Cannot fetch ratings")?,
+            album_ratings: db
+                .album_ratings()
+                .await
+                .with_context(|| "Cannot fetch album ratings")?,
+            labels: db.labels().await.with_context(|| "Cannot fetch labels")?,
+        })
+    }
+
+    /// Stores the dump in db, which should be empty.
+    pub async fn write(&self, db: &db::Instance) -> Result<()> {
+        // A partial write would leave a database that can't be imported into again.
+        let mut tx = db.begin().await.with_context(|| "Cannot store dump")?;
+
+        if let Some(filter) = &self.state.filter {
+            tx.set_filter(filter)
+                .await
+                .with_context(|| "Cannot store filters")?;
+        }
+
+        tx.set_layout(&model::Layout {
+            compilations: self.state.compilations.clone(),
+            folder_names: self.state.folder_names.clone(),
+            path_form: self.state.path_form.clone(),
+        })
+        .await
+        .with_context(|| "Cannot store layout")?;
+
+        tx.set_scrobbler_log_read(self.state.scrobbler_log_read)
+            .await
+            .with_context(|| "Cannot store scrobbler log offset")?;
+
+        for d in &self.directories {
+            tx.insert_directory(d).await.with_context(|| {
+                format!("Cannot store source directory {}", d.directory.display())
+            })?;
+        }
+
+        for a in &self.aliases {
+            tx.insert_alias(a)
+                .await
+                .with_context(|| format!("Cannot store artist alias {}", a.alias))?;
+        }
+
+        for s in &self.suppressions {
+            tx.insert_suppression(s)
+                .await
+                .with_context(|| format!("Cannot store suppressed track {}", s.file_path))?;
+        }
+
+        for t in &self.tracks {
+            tx.insert_track(t).await.with_context(|| {
+                format!("Cannot store track {}", t.file_path.as_path().display())
+            })?;
+        }
+
+        tx.insert_plays(&self.plays)
+            .await
+            .with_context(|| "Cannot store plays")?;
+
+        tx.insert_ratings(&self.ratings)
+            .await
+            .with_context(|| "Cannot store ratings")?;
+
+        tx.insert_album_ratings(&self.album_ratings)
+            .await
+            .with_context(|| "Cannot store album ratings")?;
+
+        tx.insert_labels(&self.labels)
+            .await
+            .with_context(|| "Cannot store labels")?;
+
+        tx.commit().await.with_context(|| "Cannot store dump")
+    }
+
+    pub fn to_json(&self, writer: impl std::io::Write) -> Result<()> {
+        serde_json::to_writer_pretty(writer, self).with_context(|| "Cannot write dump")
+    }
+
+    pub fn from_json(reader: impl std::io::Read) -> Result<Self> {
+        let dump: Self = serde_json::from_reader(reader).with_context(|| "Cannot read dump")?;
+
+        check_version(dump.version)?;
+
+        Ok(dump)
+    }
+
+    /// Writes the dump as one CSV file per table in dir, which is created if needed.
+    pub fn to_csv(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Cannot create directory {}", dir.display()))?;
+
+        write_csv(
+            &dir.join(STATE_FILE),
+            [StateRow {
+                version: self.version,
+                external: self.state.external,
+                filter: self.state.filter.clone(),
+                compilations: self.state.compilations.clone(),
+                folder_names: self.state.folder_names.clone(),
+                path_form: self.state.path_form.clone(),
+                scrobbler_log_read: self.state.scrobbler_log_read,
+            }],
+        )?;
+
+        write_csv(
+            &dir.join(DIRECTORIES_FILE),
+            self.directories.iter().map(|d| DirectoryRow {
+                directory: d.directory.as_path().into(),
+                include: d.include.join("\n"),
+                exclude: d.exclude.join("\n"),
+                follow_links: d.follow_links,
+                same_file_system: d.same_file_system,
+            }),
+        )?;
+
+        write_csv(&dir.join(ALIASES_FILE), &self.aliases)?;
+        write_csv(&dir.join(SUPPRESSIONS_FILE), &self.suppressions)?;
+        write_csv(&dir.join(TRACKS_FILE), &self.tracks)?;
+        write_csv(&dir.join(PLAYS_FILE), &self.plays)?;
+        write_csv(&dir.join(RATINGS_FILE), &self.ratings)?;
+        write_csv(&dir.join(ALBUM_RATINGS_FILE), &self.album_ratings)?;
+        write_csv(&dir.join(LABELS_FILE), &self.labels)
+    }
+
+    pub fn from_csv(dir: &Path) -> Result<Self> {
+        if !dir.join(STATE_FILE).exists() {
+            return Err(anyhow!("{} has no {STATE_FILE}", dir.display()));
+        }
+
+        let mut state: Vec<StateRow> = read_csv(&dir.join(STATE_FILE))?;
+
+        let state = state
+            .pop()
+            .ok_or_else(|| anyhow!("{STATE_FILE} is empty"))?;
+
+        check_version(state.version)?;
+
+        let directories: Vec<DirectoryRow> = read_csv(&dir.join(DIRECTORIES_FILE))?;
+
+        Ok(Self {
+            version: state.version,
+            state: State {
+                external: state.external,
+                filter: state.filter,
+                compilations: state.compilations,
+                folder_names: state.folder_names,
+                path_form: state.path_form,
+                scrobbler_log_read: state.scrobbler_log_read,
+            },
+            directories: directories
+                .into_iter()
+                .map(|d| model::Directory {
+                    directory: d.directory.as_path().to_path_buf(),
+                    include: split_lines(&d.include),
+                    exclude: split_lines(&d.exclude),
+                    follow_links: d.follow_links,
+                    same_file_system: d.same_file_system,
+                })
+                .collect(),
+            aliases: read_csv(&dir.join(ALIASES_FILE))?,
+            suppressions: read_csv(&dir.join(SUPPRESSIONS_FILE))?,
+            tracks: read_csv(&dir.join(TRACKS_FILE))?,
+            plays: read_csv(&dir.join(PLAYS_FILE))?,
+            ratings: read_csv(&dir.join(RATINGS_FILE))?,
+            album_ratings: read_csv(&dir.join(ALBUM_RATINGS_FILE))?,
+            labels: read_csv(&dir.join(LABELS_FILE))?,
+        })
+    }
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version > VERSION {
+        return Err(anyhow!(
+            "dump version {version} is newer than the supported one, {VERSION}: upgrade tracksync"
+        ));
+    }
+
+    Ok(())
+}
+
+fn write_csv<T: serde::Serialize>(path: &Path, rows: impl IntoIterator<Item = T>) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)
+        .with_context(|| format!("Cannot create {}", path.display()))?;
+
+    for r in rows {
+        writer
+            .serialize(r)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+    }
+
+    writer
+        .flush()
+        .with_context(|| format!("Cannot write {}", path.display()))
+}
+
+/// Reads the rows of a CSV file, none if it's missing.
+fn read_csv<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    csv::Reader::from_path(path)
+        .with_context(|| format!("Cannot open {}", path.display()))?
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .with_context(|| format!("Cannot read {}", path.display()))
+}
+
+fn split_lines(s: &str) -> Vec<String> {
+    s.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_owned())
+        .collect()
+}
@@ -2,6 +2,7 @@ use crate::model;
 use rhai::{Engine, Scope, AST};
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     ParseError(String),
     RunError(String),
@@ -39,7 +40,7 @@ impl From<regex::Error> for Error {
 }
 
 // fn filter(track: model::BaseTrack)
-const FILTER_FN_NAME: &'static str = "filter";
+const FILTER_FN_NAME: &str = "filter";
 
 pub struct ScriptRuntime {
     ast: AST,
@@ -86,6 +87,7 @@ fn compile(script: String) -> Result<ScriptRuntime, Error> {
     let mut engine = Engine::new();
 
     engine.register_fn("regex_match", regex_match);
+    engine.register_fn("unix_time", unix_time);
     engine.build_type::<model::BaseTrack>();
     engine
         .register_type_with_name::<Vec<model::BaseTrack>>("VecTrack")
@@ -95,6 +97,14 @@ fn compile(script: String) -> Result<ScriptRuntime, Error> {
     Ok(ScriptRuntime { ast, engine })
 }
 
+/// Returns the current time in seconds since the epoch, to compare with track.last_played.
+fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
 fn regex_match(expr: String, data: String) -> bool {
     let r = regex::Regex::new(&expr).unwrap();
 
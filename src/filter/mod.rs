@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod filter;
 pub use filter::check;
 pub use filter::evaluate;
@@ -0,0 +1,247 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use rusty_chromaprint::{match_fingerprints, Configuration, Fingerprinter};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// How much audio, from the beginning of a track, makes up its fingerprint.
+const SECONDS: u64 = 120;
+
+/// Fingerprints of different recordings share a handful of items by chance: pairs sharing less
+/// than this many aren't compared at all.
+const MIN_SHARED_ITEMS: usize = 8;
+
+/// Items found in more fingerprints than this are silence or similar, and tell nothing.
+const MAX_ITEM_OCCURRENCES: usize = 64;
+
+fn configuration() -> Configuration {
+    Configuration::preset_test2()
+}
+
+/// Decodes the audio of the file at path and computes its Chromaprint fingerprint.
+pub fn compute(path: &Path) -> Result<Vec<u32>, String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let mut format = probed.format;
+
+    let track = format
+        .default_track()
+        .ok_or_else(|| "no audio stream".to_owned())?;
+    let track_id = track.id;
+
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| "unknown sample rate".to_owned())?;
+    let channels = track
+        .codec_params
+        .channels
+        .ok_or_else(|| "unknown channel layout".to_owned())?
+        .count() as u32;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut printer = Fingerprinter::new(&configuration());
+    printer
+        .start(sample_rate, channels)
+        .map_err(|e| e.to_string())?;
+
+    let limit = SECONDS * sample_rate as u64 * channels as u64;
+    let mut consumed = 0;
+
+    while consumed < limit {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupted packet here and there doesn't change the fingerprint much.
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let mut samples = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+        samples.copy_interleaved_ref(decoded);
+
+        printer.consume(samples.samples());
+        consumed += samples.samples().len() as u64;
+    }
+
+    printer.finish();
+
+    Ok(printer.fingerprint().to_vec())
+}
+
+/// Returns how much of the shorter of two fingerprints matches the other one, from 0 to 1.
+pub fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let shortest = a.len().min(b.len());
+
+    if shortest == 0 {
+        return 0.0;
+    }
+
+    let segments = match match_fingerprints(a, b, &configuration()) {
+        Ok(segments) => segments,
+        Err(_) => return 0.0,
+    };
+
+    let matching: usize = segments.iter().map(|s| s.items_count).sum();
+
+    (matching as f64 / shortest as f64).min(1.0)
+}
+
+/// Groups fingerprints of the same recording, returning the indexes of each group's members.
+/// Only groups of more than one fingerprint are returned.
+pub fn group(fingerprints: &[Vec<u32>], threshold: f64) -> Vec<Vec<usize>> {
+    // Index fingerprints by their items, so that only those likely to match get compared.
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+
+    for (i, fp) in fingerprints.iter().enumerate() {
+        for item in fp.iter().collect::<HashSet<_>>() {
+            index.entry(*item).or_default().push(i);
+        }
+    }
+
+    let mut shared: HashMap<(usize, usize), usize> = HashMap::new();
+
+    for holders in index.values() {
+        if holders.len() < 2 || holders.len() > MAX_ITEM_OCCURRENCES {
+            continue;
+        }
+
+        for (n, a) in holders.iter().enumerate() {
+            for b in &holders[n + 1..] {
+                *shared.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..fingerprints.len()).collect();
+
+    for ((a, b), count) in shared {
+        if count < MIN_SHARED_ITEMS {
+            continue;
+        }
+
+        if similarity(&fingerprints[a], &fingerprints[b]) >= threshold {
+            let (ra, rb) = (root(&mut parents, a), root(&mut parents, b));
+            parents[ra.max(rb)] = ra.min(rb);
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+
+    for i in 0..fingerprints.len() {
+        let r = root(&mut parents, i);
+        groups.entry(r).or_default().push(i);
+    }
+
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+
+    groups
+}
+
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+
+    i
+}
+
+pub fn to_bytes(fingerprint: &[u32]) -> Vec<u8> {
+    fingerprint.iter().flat_map(|i| i.to_le_bytes()).collect()
+}
+
+pub fn from_bytes(raw: &[u8]) -> Vec<u32> {
+    raw.chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a fingerprint of len pseudo-random items.
+    fn fingerprint(seed: u32, len: usize) -> Vec<u32> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_recording() {
+        let a = fingerprint(1, 300);
+
+        assert_eq!(similarity(&a, &a), 1.0);
+        // A shorter copy, e.g. a fade out cut short.
+        assert!(similarity(&a, &a[..200]) > 0.9);
+        assert!(similarity(&a, &fingerprint(2, 300)) < 0.1);
+        assert_eq!(similarity(&a, &[]), 0.0);
+    }
+
+    #[test]
+    fn groups_of_recordings() {
+        let a = fingerprint(1, 300);
+        let b = fingerprint(2, 300);
+
+        let fingerprints = vec![
+            a.clone(),
+            b.clone(),
+            fingerprint(3, 300),
+            a[..250].to_vec(),
+            vec![],
+            b,
+            a,
+        ];
+
+        assert_eq!(group(&fingerprints, 0.8), [vec![0, 3, 6], vec![1, 5]]);
+        assert!(group(&fingerprints[..3], 0.8).is_empty());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let fp = fingerprint(1, 10);
+
+        assert_eq!(to_bytes(&fp).len(), 40);
+        assert_eq!(from_bytes(&to_bytes(&fp)), fp);
+    }
+}
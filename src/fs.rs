@@ -1,46 +1,135 @@
 use async_std::channel::{Receiver, Sender};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
-/// Traverses the file system from the given path.
-pub async fn traverse(path: &str) -> Receiver<Result<String, std::io::Error>> {
-    let (tx, rx): (
-        Sender<Result<String, std::io::Error>>,
-        Receiver<Result<String, std::io::Error>>,
-    ) = async_std::channel::unbounded();
+use crate::model;
 
-    let path = path.to_owned();
+/// Include and exclude rules of a source directory.
+#[derive(Clone)]
+pub struct Rules {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Rules {
+    pub fn new(directory: &model::Directory) -> Result<Rules, globset::Error> {
+        let include = match directory.include.is_empty() {
+            true => None,
+            false => Some(glob_set(&directory.include)?),
+        };
+
+        Ok(Rules {
+            root: directory.directory.clone(),
+            include,
+            exclude: glob_set(&directory.exclude)?,
+        })
+    }
+
+    /// Returns true if the file at path should be imported.
+    /// A file is imported if neither it nor any of its parent directories match an exclude
+    /// pattern, and it matches at least one include pattern, if any was given.
+    pub fn allows(&self, path: &Path) -> bool {
+        let rel = match path.strip_prefix(&self.root) {
+            Ok(rel) => rel,
+            Err(_) => return false,
+        };
+
+        if rel.ancestors().any(|a| self.excludes(a)) {
+            return false;
+        }
+
+        match &self.include {
+            Some(include) => include.is_match(rel),
+            None => true,
+        }
+    }
+
+    fn excludes(&self, rel: &Path) -> bool {
+        !rel.as_os_str().is_empty() && self.exclude.is_match(rel)
+    }
+
+    fn excludes_dir(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) => self.excludes(rel),
+            Err(_) => false,
+        }
+    }
+}
 
-    async_std::task::spawn(async move { traverse_inner(path, tx).await });
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+
+    for p in patterns {
+        builder.add(Glob::new(p)?);
+    }
+
+    builder.build()
+}
+
+/// Traverses the file system from the given directory, skipping anything rules exclude.
+/// Only an unreadable directory root stops the traversal: errors below it are logged and the
+/// offending entry skipped.
+pub async fn traverse(
+    directory: &model::Directory,
+    rules: Rules,
+) -> Receiver<Result<PathBuf, std::io::Error>> {
+    let (tx, rx) = async_std::channel::unbounded::<Result<PathBuf, std::io::Error>>();
+
+    let walker = walkdir::WalkDir::new(&directory.directory)
+        .follow_links(directory.follow_links)
+        .same_file_system(directory.same_file_system);
+
+    async_std::task::spawn(async move { traverse_inner(walker, rules, tx).await });
 
     rx
 }
 
-async fn traverse_inner(path: String, tx: Sender<Result<String, std::io::Error>>) {
-    for maybe_path in walkdir::WalkDir::new(path) {
+async fn traverse_inner(
+    walker: walkdir::WalkDir,
+    rules: Rules,
+    tx: Sender<Result<PathBuf, std::io::Error>>,
+) {
+    let walker = walker
+        .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && rules.excludes_dir(e.path())));
+
+    for maybe_path in walker {
         let path = match maybe_path {
             Ok(p) => p,
-            Err(e) => {
+            Err(e) if e.depth() == 0 => {
                 tx.send(Err(e.into())).await.unwrap();
                 tx.close();
                 return;
             }
+            Err(e) => {
+                match e.loop_ancestor() {
+                    Some(ancestor) => log::warn!(
+                        "Skipping symbolic link loop at {}, pointing to {}",
+                        e.path().unwrap_or(Path::new("")).display(),
+                        ancestor.display()
+                    ),
+                    None => log::warn!("Skipping unreadable entry: {}", e),
+                };
+
+                continue;
+            }
         };
 
         let meta = match path.metadata() {
             Ok(m) => m,
             Err(e) => {
-                tx.send(Err(e.into())).await.unwrap();
-                tx.close();
-                return;
+                log::warn!("Skipping unreadable entry: {}", e);
+                continue;
             }
         };
 
-        let path_str = path.path().to_str().unwrap().to_string();
-
         match meta.is_dir() {
             true => {}
             false => {
-                if is_music(&path_str) {
-                    tx.send(Ok(path_str)).await.unwrap();
+                if is_music(path.path()) && rules.allows(path.path()) {
+                    tx.send(Ok(path.into_path())).await.unwrap();
                 }
             }
         }
@@ -49,15 +138,18 @@ async fn traverse_inner(path: String, tx: Sender<Result<String, std::io::Error>>
     tx.close();
 }
 
-/// Returns true if name has one of the supported music file extension.
-fn is_music(name: &String) -> bool {
+/// Returns true if path has one of the supported music file extension.
+fn is_music(path: &Path) -> bool {
     let formats = ["flac", "mp3", "ogg", "mp4", "m4a"];
+    let name = path.as_os_str().as_bytes();
 
     formats
         .into_iter()
-        .filter_map(|format| name.ends_with(&format!(".{format}")).then_some(true))
+        .filter_map(|format| {
+            name.ends_with(format!(".{format}").as_bytes())
+                .then_some(true)
+        })
         .collect::<Vec<bool>>()
         .into_iter()
-        .find(|x| *x == true)
-        .is_some()
+        .any(|x| x)
 }
@@ -2,8 +2,12 @@ use clap::Parser;
 mod cli;
 mod cmd;
 mod db;
+mod dump;
 mod filter;
+mod fingerprint;
 mod fs;
+mod matching;
+mod media;
 mod model;
 
 #[async_std::main]
@@ -19,6 +23,19 @@ async fn main() -> anyhow::Result<()> {
         cli::Commands::Clean(clean_args) => Ok(cmd::clean::run(clean_args).await?),
         cli::Commands::Update(update_args) => Ok(cmd::add::run(update_args, true).await?),
         cli::Commands::Filter(filter_args) => Ok(cmd::filter::run(filter_args).await?),
+        cli::Commands::Destination(destination_args) => {
+            Ok(cmd::destination::run(destination_args).await?)
+        }
+        cli::Commands::Search(search_args) => Ok(cmd::search::run(search_args).await?),
+        cli::Commands::Stats(stats_args) => Ok(cmd::stats::run(stats_args).await?),
+        cli::Commands::Source(source_args) => Ok(cmd::source::run(source_args).await?),
+        cli::Commands::Alias(alias_args) => Ok(cmd::alias::run(alias_args).await?),
+        cli::Commands::Export(export_args) => Ok(cmd::export::run(export_args).await?),
+        cli::Commands::Import(import_args) => Ok(cmd::import::run(import_args).await?),
+        cli::Commands::Doctor(doctor_args) => Ok(cmd::doctor::run(doctor_args).await?),
+        cli::Commands::Rescan(rescan_args) => Ok(cmd::rescan::run(rescan_args).await?),
+        cli::Commands::History(history_args) => Ok(cmd::history::run(history_args).await?),
+        cli::Commands::Label(label_args) => Ok(cmd::label::run(label_args).await?),
     }
 }
 
@@ -0,0 +1,260 @@
+use regex::{Regex, RegexBuilder};
+use std::collections::{BTreeMap, HashSet};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Qualifiers naming an edition of an album or a track, rather than the album or the track
+/// itself. They're matched after case and accents have been folded.
+const QUALIFIERS: &[&str] = &[
+    // "Abbey Road (Super Deluxe Edition)", "Nevermind [2011 Remaster]"
+    r"[(\[][^)\]]*\b(edition|remaster|remastered|remasters|deluxe|expanded|anniversary|reissue|bonus tracks?|mono|stereo|version)\b[^)\]]*[)\]]",
+    // "Let It Be - Remastered 2009", "Help! - 2013 Remaster"
+    r"\s[-–—]\s[^-–—]*\b(edition|remaster|remastered|deluxe|expanded|anniversary|reissue|mono|stereo|version)\b[^-–—]*$",
+    // "Song (feat. Someone)", "Song ft. Someone"
+    r"[(\[]\s*(feat|ft|featuring)\b[^)\]]*[)\]]",
+    r"\s(feat|ft|featuring)\b.*$",
+];
+
+/// Settings deciding what dupes considers a duplicate.
+/// Every setting is optional in configuration files, missing ones take their default value.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Minimum similarity of two normalised album titles, from 0 to 1, for the albums to be
+    /// considered the same.
+    pub album_threshold: f64,
+
+    /// Minimum similarity of two normalised artist names, from 0 to 1, for them to be considered
+    /// the same artist.
+    pub artist_threshold: f64,
+
+    /// Minimum similarity of two fingerprints for their tracks to be considered the same
+    /// recording.
+    pub acoustic_threshold: f64,
+
+    /// Maximum difference in seconds between the durations of two copies of the same track.
+    pub duration_tolerance: f64,
+
+    /// Strip the built-in edition and remaster qualifiers.
+    pub builtin_qualifiers: bool,
+
+    /// Regular expressions matching more qualifiers to strip, case-insensitively.
+    pub qualifiers: Vec<String>,
+
+    /// Text to replace before comparison, e.g. "&" with "and".
+    pub replacements: BTreeMap<String, String>,
+
+    /// Words ignored in comparison, e.g. "the".
+    pub stop_words: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            album_threshold: 0.6,
+            artist_threshold: 0.9,
+            acoustic_threshold: 0.8,
+            duration_tolerance: 2.0,
+            builtin_qualifiers: true,
+            qualifiers: vec![],
+            replacements: BTreeMap::new(),
+            stop_words: vec![],
+        }
+    }
+}
+
+/// Turns album, artist and track names into a form suitable for comparison.
+pub struct Normaliser {
+    qualifiers: Vec<Regex>,
+    replacements: Vec<(String, String)>,
+    stop_words: HashSet<String>,
+}
+
+impl Normaliser {
+    pub fn new(config: &Config) -> Result<Self, regex::Error> {
+        let builtin = match config.builtin_qualifiers {
+            true => QUALIFIERS,
+            false => &[],
+        };
+
+        let qualifiers = builtin
+            .iter()
+            .map(|q| q.to_string())
+            .chain(config.qualifiers.iter().cloned())
+            .map(|q| RegexBuilder::new(&q).case_insensitive(true).build())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            qualifiers,
+            replacements: config
+                .replacements
+                .iter()
+                .map(|(from, to)| (fold(from), fold(to)))
+                .collect(),
+            stop_words: config.stop_words.iter().map(|w| fold(w)).collect(),
+        })
+    }
+
+    /// Normalises a name: case and accents are folded, edition qualifiers, punctuation and stop
+    /// words are left out.
+    pub fn normalise(&self, s: &str) -> String {
+        let mut s = fold(s);
+
+        for (from, to) in &self.replacements {
+            s = s.replace(from, to);
+        }
+
+        let mut stripped = s.clone();
+
+        for q in &self.qualifiers {
+            stripped = q.replace_all(&stripped, " ").into_owned();
+        }
+
+        // Don't strip names made of a qualifier alone, e.g. "(Remastered)".
+        if words(&stripped, &self.stop_words).is_empty() {
+            stripped = s;
+        }
+
+        words(&stripped, &self.stop_words).join(" ")
+    }
+}
+
+/// Folds case and decomposes characters, leaving accents out.
+fn fold(s: &str) -> String {
+    s.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn words(s: &str, stop_words: &HashSet<String>) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && !stop_words.contains(*w))
+        .map(|w| w.to_owned())
+        .collect()
+}
+
+/// Returns how similar two normalised names are, from 0 to 1.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    match a == b {
+        true => 1.0,
+        false => similar_string::compare_similarity(a, b),
+    }
+}
+
+/// Groups names similar enough to one another, directly or through other names, so that groups
+/// don't depend on the order names come in.
+/// Returns the group of each name, as the index of its first name.
+pub fn cluster(names: &[String], threshold: f64) -> Vec<usize> {
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+
+        i
+    }
+
+    let mut parents: Vec<usize> = (0..names.len()).collect();
+
+    for i in 0..names.len() {
+        for j in i + 1..names.len() {
+            if similarity(&names[i], &names[j]) >= threshold {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    (0..names.len()).map(|i| root(&mut parents, i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalise(s: &str) -> String {
+        Normaliser::new(&Config::default()).unwrap().normalise(s)
+    }
+
+    #[test]
+    fn editions_are_stripped() {
+        for title in [
+            "Abbey Road (Super Deluxe Edition)",
+            "Abbey Road [2019 Remaster]",
+            "Abbey Road - Remastered 2009",
+            "ABBEY ROAD",
+        ] {
+            assert_eq!(normalise(title), "abbey road");
+        }
+
+        assert_eq!(normalise("Song (feat. Someone)"), "song");
+        assert_eq!(normalise("Song ft. Someone Else"), "song");
+    }
+
+    #[test]
+    fn case_accents_and_punctuation_are_folded() {
+        assert_eq!(normalise("Sigur Rós"), "sigur ros");
+        assert_eq!(normalise("Beyoncé"), normalise("BEYONCE"));
+        assert_eq!(normalise("AC/DC"), "ac dc");
+        assert_eq!(normalise("ﬁve"), "five");
+    }
+
+    #[test]
+    fn qualifiers_alone_are_kept() {
+        assert_eq!(normalise("(Remastered)"), "remastered");
+        assert_eq!(normalise("Mono"), "mono");
+    }
+
+    #[test]
+    fn configured_rules() {
+        let config = Config {
+            builtin_qualifiers: false,
+            qualifiers: vec![r"\(live\)".to_owned()],
+            replacements: BTreeMap::from([("&".to_owned(), " and ".to_owned())]),
+            stop_words: vec!["The".to_owned()],
+            ..Default::default()
+        };
+
+        let normaliser = Normaliser::new(&config).unwrap();
+
+        assert_eq!(normaliser.normalise("The Jam & Friends"), "jam and friends");
+        assert_eq!(normaliser.normalise("Alive (LIVE)"), "alive");
+        assert_eq!(
+            normaliser.normalise("Let It Be (Remastered)"),
+            "let it be remastered"
+        );
+
+        assert!(Normaliser::new(&Config {
+            qualifiers: vec!["(".to_owned()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn similar_names() {
+        assert_eq!(similarity("abbey road", "abbey road"), 1.0);
+        assert!(similarity("abbey road", "abey road") > 0.8);
+        assert!(similarity("abbey road", "let it be") < 0.5);
+    }
+
+    #[test]
+    fn clusters_ignore_order() {
+        // "beatles" and "the beatless" are only similar through "the beatles".
+        let names = ["beatles", "the beatles", "the beatless", "kinks"];
+        let threshold =
+            similarity("beatles", "the beatles").min(similarity("the beatles", "the beatless"));
+
+        assert!(similarity("beatles", "the beatless") < threshold);
+
+        for order in [[0, 1, 2, 3], [2, 0, 3, 1], [3, 2, 1, 0]] {
+            let ordered: Vec<String> = order.iter().map(|i| names[*i].to_owned()).collect();
+            let clusters = cluster(&ordered, threshold);
+            let of = |name: &str| clusters[ordered.iter().position(|n| n == name).unwrap()];
+
+            assert_eq!(of("beatles"), of("the beatles"));
+            assert_eq!(of("beatles"), of("the beatless"));
+            assert_ne!(of("beatles"), of("kinks"));
+        }
+    }
+}
@@ -0,0 +1,1217 @@
+use id3::TagLike;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Technical and extended tag metadata `audiotags` doesn't expose.
+#[derive(Debug, Clone, Default)]
+pub struct Properties {
+    pub duration: Option<f64>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub bit_depth: Option<i64>,
+    pub channels: Option<i64>,
+    pub file_size: Option<i64>,
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
+    pub musicbrainz_track_id: Option<String>,
+    pub musicbrainz_album_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    pub musicbrainz_album_artist_id: Option<String>,
+    /// Whether the track is part of a compilation of various artists.
+    pub compilation: bool,
+    pub artist_sort: Option<String>,
+    pub album_artist_sort: Option<String>,
+    pub content_hash: Option<String>,
+    pub audio_hash: Option<String>,
+}
+
+// Tag keys, in (Vorbis comment, ID3v2 TXXX description, MP4 freeform name) order.
+const REPLAYGAIN_TRACK_GAIN: [&str; 3] = [
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_GAIN",
+    "replaygain_track_gain",
+];
+const REPLAYGAIN_TRACK_PEAK: [&str; 3] = [
+    "REPLAYGAIN_TRACK_PEAK",
+    "REPLAYGAIN_TRACK_PEAK",
+    "replaygain_track_peak",
+];
+const REPLAYGAIN_ALBUM_GAIN: [&str; 3] = [
+    "REPLAYGAIN_ALBUM_GAIN",
+    "REPLAYGAIN_ALBUM_GAIN",
+    "replaygain_album_gain",
+];
+const REPLAYGAIN_ALBUM_PEAK: [&str; 3] = [
+    "REPLAYGAIN_ALBUM_PEAK",
+    "REPLAYGAIN_ALBUM_PEAK",
+    "replaygain_album_peak",
+];
+const MUSICBRAINZ_TRACK_ID: [&str; 3] = [
+    "MUSICBRAINZ_TRACKID",
+    "MusicBrainz Track Id",
+    "MusicBrainz Track Id",
+];
+const MUSICBRAINZ_ALBUM_ID: [&str; 3] = [
+    "MUSICBRAINZ_ALBUMID",
+    "MusicBrainz Album Id",
+    "MusicBrainz Album Id",
+];
+const MUSICBRAINZ_ARTIST_ID: [&str; 3] = [
+    "MUSICBRAINZ_ARTISTID",
+    "MusicBrainz Artist Id",
+    "MusicBrainz Artist Id",
+];
+const MUSICBRAINZ_ALBUM_ARTIST_ID: [&str; 3] = [
+    "MUSICBRAINZ_ALBUMARTISTID",
+    "MusicBrainz Album Artist Id",
+    "MusicBrainz Album Artist Id",
+];
+
+const ITUNES_MEAN: &str = "com.apple.iTunes";
+
+/// Reads technical metadata off the file at path.
+/// Missing or unreadable information is left empty, a file we can't probe is still a
+/// perfectly good track.
+pub fn probe(path: &Path) -> Properties {
+    let mut props = Properties {
+        file_size: std::fs::metadata(path).ok().map(|m| m.len() as i64),
+        ..Default::default()
+    };
+
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    let res = match extension.as_str() {
+        "flac" => probe_flac(path, &mut props),
+        "mp3" => probe_mp3(path, &mut props),
+        "mp4" | "m4a" => probe_mp4(path, &mut props),
+        "ogg" => probe_ogg(path, &mut props),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = res {
+        log::warn!("Cannot read audio properties of {}: {}", path.display(), e);
+    }
+
+    props.content_hash = content_hash(path);
+    props.audio_hash = audio_hash(path);
+
+    props
+}
+
+/// Reads the tags of the file at path.
+/// audiotags doesn't read Ogg files: their Vorbis comments, the same FLAC files carry, are
+/// read here and handed over as a FLAC tag.
+pub fn read_tags(path: &Path) -> audiotags::Result<Box<dyn audiotags::AudioTag + Send + Sync>> {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    if extension != "ogg" {
+        return audiotags::Tag::new().read_from_path(path);
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    let packets = ogg_packets(&mut file, 2)?;
+
+    Ok(Box::new(audiotags::FlacTag::from(vorbis_comments(
+        &packets[1],
+    )?)))
+}
+
+/// Returns the SHA-256 of the whole file at path.
+pub fn content_hash(path: &Path) -> Option<String> {
+    match sha256::try_digest(path) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            log::warn!("Cannot hash {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Returns the SHA-256 of the audio payload of the file at path, leaving out any tag block so
+/// that editing tags doesn't change it.
+/// Files in formats we can't split are hashed whole.
+pub fn audio_hash(path: &Path) -> Option<String> {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    let res = std::fs::File::open(path).and_then(|mut file| match extension.as_str() {
+        "flac" => hash_flac_audio(&mut file),
+        "mp3" => hash_mpeg_audio(&mut file),
+        "mp4" | "m4a" => hash_mp4_audio(&mut file),
+        "ogg" => hash_ogg_audio(&mut file),
+        _ => {
+            let len = file.seek(SeekFrom::End(0))?;
+            hash_regions(&mut file, &[(0, len)])
+        }
+    });
+
+    match res {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            log::warn!("Cannot hash audio of {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn hash_regions<R: Read + Seek>(file: &mut R, regions: &[(u64, u64)]) -> std::io::Result<String> {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    for (start, end) in regions {
+        file.seek(SeekFrom::Start(*start))?;
+
+        let mut left = end.saturating_sub(*start);
+        while left > 0 {
+            let want = left.min(buf.len() as u64) as usize;
+            let read = file.read(&mut buf[..want])?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..read]);
+            left -= read as u64;
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+// Skips any ID3v2 tag and the metadata blocks, audio frames follow the last one and run to the
+// end of the file: unlike MP3s, FLAC files have no trailing tags.
+fn hash_flac_audio<R: Read + Seek>(file: &mut R) -> std::io::Result<String> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = id3v2_end(file)?.min(len);
+
+    let mut magic = [0u8; 4];
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(invalid_data("missing FLAC stream marker"));
+    }
+    pos += 4;
+
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        pos += 4 + size;
+        file.seek(SeekFrom::Start(pos))?;
+
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    hash_regions(file, &[(pos.min(len), len)])
+}
+
+// ID3v1 and ID3v2 are handled by mpeg_audio_region, APEv2 tags sit right before ID3v1.
+fn hash_mpeg_audio<R: Read + Seek>(file: &mut R) -> std::io::Result<String> {
+    let (start, mut end) = mpeg_audio_region(file)?;
+
+    if end >= start + 32 {
+        let mut footer = [0u8; 32];
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+
+        if &footer[0..8] == b"APETAGEX" {
+            let size = u32::from_le_bytes([footer[12], footer[13], footer[14], footer[15]]) as u64;
+            let has_header = footer[23] & 0x80 != 0;
+
+            end = end.saturating_sub(size + if has_header { 32 } else { 0 });
+        }
+    }
+
+    hash_regions(file, &[(start, end.max(start))])
+}
+
+// Audio lives in mdat atoms, tags in moov: only hash the former.
+fn hash_mp4_audio<R: Read + Seek>(file: &mut R) -> std::io::Result<String> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    let mut regions = vec![];
+
+    while pos + 8 <= len {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_len = 8;
+
+        if size == 1 {
+            let mut large = [0u8; 8];
+            file.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        } else if size == 0 {
+            size = len - pos;
+        }
+
+        let end = pos
+            .checked_add(size)
+            .filter(|&end| size >= header_len && end <= len)
+            .ok_or_else(|| invalid_data("malformed MP4 atom"))?;
+
+        if &header[4..8] == b"mdat" {
+            regions.push((pos + header_len, end));
+        }
+
+        pos = end;
+    }
+
+    if regions.is_empty() {
+        return Err(invalid_data("no MP4 media data"));
+    }
+
+    hash_regions(file, &regions)
+}
+
+// The first three packets of an Ogg stream are headers, comments included: hash the payload
+// of the ones after them.
+// Page headers are left out, since their sequence numbers and checksums change whenever the
+// comment packet spans a different number of pages.
+fn hash_ogg_audio<R: Read + Seek>(file: &mut R) -> std::io::Result<String> {
+    const HEADER_PACKETS: usize = 3;
+
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    let mut packet = 0;
+    let mut regions: Vec<(u64, u64)> = vec![];
+
+    while pos + 27 <= len {
+        let mut header = [0u8; 27];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+
+        if &header[0..4] != b"OggS" {
+            return Err(invalid_data("missing Ogg page marker"));
+        }
+
+        let mut lacing = vec![0u8; header[26] as usize];
+        file.read_exact(&mut lacing)?;
+
+        let mut segment = pos + 27 + lacing.len() as u64;
+
+        for l in lacing {
+            let end = segment + l as u64;
+
+            if packet >= HEADER_PACKETS {
+                // Merge contiguous segments, so that we seek as little as possible.
+                match regions.last_mut() {
+                    Some(last) if last.1 == segment => last.1 = end,
+                    _ => regions.push((segment, end)),
+                }
+            }
+
+            if l < 255 {
+                packet += 1;
+            }
+
+            segment = end;
+        }
+
+        pos = segment;
+    }
+
+    hash_regions(file, &regions)
+}
+
+/// Returns true if a flag tag is set, e.g. "1".
+fn is_set(value: &str) -> bool {
+    !matches!(value.trim(), "" | "0")
+}
+
+fn probe_flac(path: &Path, props: &mut Properties) -> Result<(), String> {
+    let tag = metaflac::Tag::read_from_path(path).map_err(|e| e.to_string())?;
+
+    if let Some(si) = tag.get_streaminfo() {
+        props.sample_rate = Some(si.sample_rate as i64);
+        props.bit_depth = Some(si.bits_per_sample as i64);
+        props.channels = Some(si.num_channels as i64);
+
+        if si.sample_rate > 0 && si.total_samples > 0 {
+            props.duration = Some(si.total_samples as f64 / si.sample_rate as f64);
+        }
+    }
+
+    fill_vorbis(props, &tag);
+    fill_bitrate(props);
+
+    Ok(())
+}
+
+fn probe_ogg(path: &Path, props: &mut Properties) -> Result<(), String> {
+    let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let packets = ogg_packets(&mut file, 2).map_err(|e| e.to_string())?;
+
+    let stream = match parse_ogg_identification(&packets[0]) {
+        Some(s) => s,
+        None => return Err("unsupported Ogg stream".to_owned()),
+    };
+
+    props.sample_rate = Some(stream.sample_rate as i64);
+    props.channels = Some(stream.channels as i64);
+    props.bitrate = stream.nominal_bitrate.map(|br| br as i64 / 1000);
+
+    if let Some(granule) = ogg_last_granule(&mut file).map_err(|e| e.to_string())? {
+        let samples = granule.saturating_sub(stream.pre_skip);
+        props.duration = Some(samples as f64 / stream.granule_rate as f64);
+    }
+
+    let tag = vorbis_comments(&packets[1]).map_err(|e| e.to_string())?;
+
+    fill_vorbis(props, &tag);
+    fill_bitrate(props);
+
+    Ok(())
+}
+
+// FLAC and Ogg files share Vorbis comments.
+fn fill_vorbis(props: &mut Properties, tag: &metaflac::Tag) {
+    let get = |keys: [&str; 3]| {
+        tag.get_vorbis(keys[0])
+            .and_then(|mut v| v.next())
+            .map(|v| v.to_owned())
+    };
+
+    fill_extended(props, get);
+
+    let get = |key: &str| {
+        tag.get_vorbis(key)
+            .and_then(|mut v| v.next())
+            .map(|v| v.to_owned())
+    };
+
+    props.compilation = get("COMPILATION").is_some_and(|v| is_set(&v));
+    props.artist_sort = get("ARTISTSORT");
+    props.album_artist_sort = get("ALBUMARTISTSORT");
+}
+
+fn probe_mp4(path: &Path, props: &mut Properties) -> Result<(), String> {
+    let tag = mp4ameta::Tag::read_from_path(path).map_err(|e| e.to_string())?;
+
+    props.duration = tag.duration().map(|d| d.as_secs_f64());
+    props.sample_rate = tag.sample_rate().map(|sr| sr.hz() as i64);
+    props.channels = tag.channel_config().map(|cc| cc.channel_count() as i64);
+    props.bitrate = tag.avg_bitrate().map(|br| br as i64 / 1000);
+
+    let get = |keys: [&str; 3]| {
+        let ident = mp4ameta::FreeformIdent::new(ITUNES_MEAN, keys[2]);
+        let value = tag.strings_of(&ident).next().map(|v| v.to_owned());
+
+        value
+    };
+
+    fill_extended(props, get);
+    fill_bitrate(props);
+
+    props.compilation = tag.compilation();
+
+    let get = |fourcc: &[u8; 4]| {
+        tag.strings_of(&mp4ameta::Fourcc(*fourcc))
+            .next()
+            .map(|v| v.to_owned())
+    };
+
+    props.artist_sort = get(b"soar");
+    props.album_artist_sort = get(b"soaa");
+
+    Ok(())
+}
+
+fn probe_mp3(path: &Path, props: &mut Properties) -> Result<(), String> {
+    // A file without an ID3v2 tag is fine, we still want its stream info.
+    if let Ok(tag) = id3::Tag::read_from_path(path) {
+        let get = |keys: [&str; 3]| {
+            tag.extended_texts()
+                .find(|et| et.description.eq_ignore_ascii_case(keys[1]))
+                .map(|et| et.value.clone())
+        };
+
+        fill_extended(props, get);
+
+        let get = |id: &str| {
+            tag.get(id)
+                .and_then(|f| f.content().text())
+                .map(|v| v.to_owned())
+        };
+
+        props.compilation = get("TCMP").is_some_and(|v| is_set(&v));
+        props.artist_sort = get("TSOP");
+        props.album_artist_sort = get("TSO2");
+
+        // Picard stores the recording id in an UFID frame rather than in a TXXX one.
+        if props.musicbrainz_track_id.is_none() {
+            props.musicbrainz_track_id = tag
+                .frames()
+                .filter(|f| f.id() == "UFID")
+                .filter_map(|f| f.content().to_unknown().ok())
+                .find_map(|unknown| {
+                    let data = &unknown.data;
+                    let sep = data.iter().position(|b| *b == 0)?;
+                    let (owner, id) = data.split_at(sep);
+
+                    (owner == b"http://musicbrainz.org")
+                        .then(|| String::from_utf8_lossy(&id[1..]).into_owned())
+                });
+        }
+    }
+
+    let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let region = mpeg_audio_region(&mut file).map_err(|e| e.to_string())?;
+
+    let header = match find_mpeg_frame(&mut file, region.0)? {
+        Some(h) => h,
+        None => return Err("no MPEG audio frame found".to_owned()),
+    };
+
+    props.sample_rate = Some(header.sample_rate as i64);
+    props.channels = Some(header.channels as i64);
+
+    let audio_bytes = region.1.saturating_sub(header.offset);
+
+    match header.frames {
+        Some(frames) if frames > 0 => {
+            let duration =
+                frames as f64 * header.samples_per_frame as f64 / header.sample_rate as f64;
+            props.duration = Some(duration);
+            props.bitrate = Some((audio_bytes as f64 * 8.0 / duration / 1000.0).round() as i64);
+        }
+        _ => {
+            props.bitrate = Some(header.bitrate as i64);
+            props.duration = Some(audio_bytes as f64 * 8.0 / (header.bitrate as f64 * 1000.0));
+        }
+    };
+
+    Ok(())
+}
+
+fn fill_extended<F>(props: &mut Properties, get: F)
+where
+    F: Fn([&str; 3]) -> Option<String>,
+{
+    props.replaygain_track_gain = get(REPLAYGAIN_TRACK_GAIN).and_then(parse_gain);
+    props.replaygain_track_peak = get(REPLAYGAIN_TRACK_PEAK).and_then(parse_gain);
+    props.replaygain_album_gain = get(REPLAYGAIN_ALBUM_GAIN).and_then(parse_gain);
+    props.replaygain_album_peak = get(REPLAYGAIN_ALBUM_PEAK).and_then(parse_gain);
+    props.musicbrainz_track_id = get(MUSICBRAINZ_TRACK_ID);
+    props.musicbrainz_album_id = get(MUSICBRAINZ_ALBUM_ID);
+    props.musicbrainz_artist_id = get(MUSICBRAINZ_ARTIST_ID);
+    props.musicbrainz_album_artist_id = get(MUSICBRAINZ_ALBUM_ARTIST_ID);
+}
+
+// Lossless formats don't advertise a bitrate, derive it from the file size instead.
+fn fill_bitrate(props: &mut Properties) {
+    if props.bitrate.is_some() {
+        return;
+    }
+
+    if let (Some(size), Some(duration)) = (props.file_size, props.duration) {
+        if duration > 0.0 {
+            props.bitrate = Some((size as f64 * 8.0 / duration / 1000.0).round() as i64);
+        }
+    }
+}
+
+// ReplayGain values look like "-6.54 dB" or "0.988525".
+fn parse_gain(raw: String) -> Option<f64> {
+    raw.split_whitespace().next()?.parse().ok()
+}
+
+/// Returns the byte range of a MPEG file that holds audio frames, excluding ID3v2 and ID3v1
+/// tag blocks.
+fn mpeg_audio_region<R: Read + Seek>(file: &mut R) -> std::io::Result<(u64, u64)> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let start = id3v2_end(file)?;
+    let mut end = len;
+
+    if len >= 128 {
+        let mut trailer = [0u8; 3];
+        file.seek(SeekFrom::Start(len - 128))?;
+        if file.read_exact(&mut trailer).is_ok() && &trailer == b"TAG" {
+            end = len - 128;
+        }
+    }
+
+    Ok((start.min(end), end))
+}
+
+/// Returns the offset right after the ID3v2 tag at the start of file, 0 if there's none.
+fn id3v2_end<R: Read + Seek>(file: &mut R) -> std::io::Result<u64> {
+    let mut header = [0u8; 10];
+    file.seek(SeekFrom::Start(0))?;
+
+    if file.read_exact(&mut header).is_err() || &header[0..3] != b"ID3" {
+        return Ok(0);
+    }
+
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+
+    Ok(10 + size + footer)
+}
+
+struct MpegFrame {
+    offset: u64,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u32,
+    samples_per_frame: u32,
+    frames: Option<u32>,
+}
+
+const MPEG_SCAN_WINDOW: usize = 64 * 1024;
+
+fn find_mpeg_frame<R: Read + Seek>(file: &mut R, start: u64) -> Result<Option<MpegFrame>, String> {
+    let mut buf = vec![0u8; MPEG_SCAN_WINDOW];
+
+    file.seek(SeekFrom::Start(start))
+        .map_err(|e| e.to_string())?;
+    let read = file.read(&mut buf).map_err(|e| e.to_string())?;
+    let buf = &buf[..read];
+
+    for pos in 0..buf.len().saturating_sub(4) {
+        if let Some(mut frame) = parse_mpeg_header(&buf[pos..]) {
+            frame.offset = start + pos as u64;
+            return Ok(Some(frame));
+        }
+    }
+
+    Ok(None)
+}
+
+const BITRATES_V1: [[u32; 15]; 3] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+
+const BITRATES_V2: [[u32; 15]; 2] = [
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+fn parse_mpeg_header(buf: &[u8]) -> Option<MpegFrame> {
+    if buf.len() < 4 || buf[0] != 0xff || buf[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+
+    // 0: MPEG 2.5, 2: MPEG 2, 3: MPEG 1
+    let version = (buf[1] >> 3) & 0x03;
+    // 1: layer III, 2: layer II, 3: layer I
+    let layer = (buf[1] >> 1) & 0x03;
+    let bitrate_idx = (buf[2] >> 4) as usize;
+    let sample_rate_idx = ((buf[2] >> 2) & 0x03) as usize;
+    let mono = (buf[3] >> 6) == 0x03;
+
+    if version == 1 || layer == 0 || bitrate_idx == 0 || bitrate_idx == 15 || sample_rate_idx == 3 {
+        return None;
+    }
+
+    let bitrate = match version {
+        3 => BITRATES_V1[(3 - layer) as usize][bitrate_idx],
+        _ => BITRATES_V2[if layer == 3 { 0 } else { 1 }][bitrate_idx],
+    };
+
+    let sample_rate = match version {
+        3 => [44100, 48000, 32000][sample_rate_idx],
+        2 => [22050, 24000, 16000][sample_rate_idx],
+        _ => [11025, 12000, 8000][sample_rate_idx],
+    };
+
+    let samples_per_frame = match (layer, version) {
+        (3, _) => 384,
+        (1, 0) | (1, 2) => 576,
+        _ => 1152,
+    };
+
+    // VBR files carry the total frame count in a Xing/Info or VBRI header, right after the
+    // side information of the first frame.
+    let side_info = match (version, mono) {
+        (3, false) => 32,
+        (3, true) => 17,
+        (_, false) => 17,
+        (_, true) => 9,
+    };
+
+    let be_u32 = |at: usize| -> Option<u32> {
+        buf.get(at..at + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let xing = 4 + side_info;
+    let frames = match buf.get(xing..xing + 4) {
+        Some(b"Xing") | Some(b"Info") => match be_u32(xing + 4) {
+            Some(flags) if flags & 0x01 != 0 => be_u32(xing + 8),
+            _ => None,
+        },
+        _ => match buf.get(36..40) {
+            Some(b"VBRI") => be_u32(36 + 14),
+            _ => None,
+        },
+    };
+
+    Some(MpegFrame {
+        offset: 0,
+        bitrate,
+        sample_rate,
+        channels: if mono { 1 } else { 2 },
+        samples_per_frame,
+        frames,
+    })
+}
+
+/// Stream information found in the first packet of an Ogg stream.
+struct OggStream {
+    sample_rate: u32,
+    channels: u32,
+    /// Granule positions count samples at this rate.
+    granule_rate: u32,
+    /// Samples to drop at the start of the stream.
+    pre_skip: u64,
+    nominal_bitrate: Option<u32>,
+}
+
+/// Parses the identification header of a Vorbis or Opus stream.
+fn parse_ogg_identification(packet: &[u8]) -> Option<OggStream> {
+    let le_u32 = |at: usize| -> Option<u32> {
+        packet
+            .get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    if packet.get(0..7) == Some(b"\x01vorbis") {
+        let sample_rate = le_u32(12)?;
+
+        if sample_rate == 0 {
+            return None;
+        }
+
+        return Some(OggStream {
+            sample_rate,
+            channels: *packet.get(11)? as u32,
+            granule_rate: sample_rate,
+            pre_skip: 0,
+            // Zero, or all bits set, when the encoder doesn't say.
+            nominal_bitrate: le_u32(20).filter(|br| *br > 0 && *br < i32::MAX as u32),
+        });
+    }
+
+    if packet.get(0..8) == Some(b"OpusHead") {
+        // Opus always decodes at 48 kHz, whatever the rate of the original input.
+        return Some(OggStream {
+            sample_rate: 48000,
+            channels: *packet.get(9)? as u32,
+            granule_rate: 48000,
+            pre_skip: u16::from_le_bytes([*packet.get(10)?, *packet.get(11)?]) as u64,
+            nominal_bitrate: None,
+        });
+    }
+
+    None
+}
+
+/// Reads the first count packets of an Ogg stream.
+fn ogg_packets<R: Read + Seek>(r: &mut R, count: usize) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut packets = vec![];
+    let mut packet = vec![];
+
+    r.seek(SeekFrom::Start(0))?;
+
+    while packets.len() < count {
+        let mut header = [0u8; 27];
+        r.read_exact(&mut header)?;
+
+        if &header[0..4] != b"OggS" {
+            return Err(invalid_data("missing Ogg page marker"));
+        }
+
+        let mut lacing = vec![0u8; header[26] as usize];
+        r.read_exact(&mut lacing)?;
+
+        for l in lacing {
+            let start = packet.len();
+            packet.resize(start + l as usize, 0);
+            r.read_exact(&mut packet[start..])?;
+
+            if l < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+
+    packets.truncate(count);
+
+    Ok(packets)
+}
+
+/// Returns the granule position of the last Ogg page that has one: the number of samples of
+/// the stream, pre-skip included.
+fn ogg_last_granule<R: Read + Seek>(r: &mut R) -> std::io::Result<Option<u64>> {
+    // Pages are at most 64 KiB long: the last one starts within the tail of the stream.
+    const TAIL: u64 = 65307;
+
+    let len = r.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(TAIL);
+
+    let mut tail = vec![];
+    r.seek(SeekFrom::Start(start))?;
+    r.take(TAIL).read_to_end(&mut tail)?;
+
+    let mut end = tail.len();
+
+    while let Some(pos) = tail[..end].windows(4).rposition(|w| w == b"OggS") {
+        if let Some(g) = tail.get(pos + 6..pos + 14) {
+            let granule = i64::from_le_bytes([g[0], g[1], g[2], g[3], g[4], g[5], g[6], g[7]]);
+
+            // -1 marks pages on which no packet ends.
+            if tail[pos + 4] == 0 && granule >= 0 {
+                return Ok(Some(granule as u64));
+            }
+        }
+
+        end = pos;
+    }
+
+    Ok(None)
+}
+
+/// Parses the comment header of a Vorbis or Opus stream into a tag holding the same comments.
+fn vorbis_comments(packet: &[u8]) -> std::io::Result<metaflac::Tag> {
+    let data = if packet.starts_with(b"\x03vorbis") {
+        &packet[7..]
+    } else if packet.starts_with(b"OpusTags") {
+        &packet[8..]
+    } else {
+        return Err(invalid_data("missing Ogg comment header"));
+    };
+
+    let mut r = std::io::Cursor::new(data);
+
+    // Lengths come from the file: check them against what's left rather than allocating them.
+    let read = |r: &mut std::io::Cursor<&[u8]>, len: usize| -> std::io::Result<Vec<u8>> {
+        if (data.len() as u64 - r.position()) < len as u64 {
+            return Err(invalid_data("truncated Ogg comment header"));
+        }
+
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+
+        Ok(buf)
+    };
+
+    let read_len = |r: &mut std::io::Cursor<&[u8]>| -> std::io::Result<usize> {
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf)?;
+
+        Ok(u32::from_le_bytes(buf) as usize)
+    };
+
+    let mut tag = metaflac::Tag::new();
+
+    let vendor_len = read_len(&mut r)?;
+    let vendor = read(&mut r, vendor_len)?;
+    tag.vorbis_comments_mut().vendor_string = String::from_utf8_lossy(&vendor).into_owned();
+
+    for _ in 0..read_len(&mut r)? {
+        let len = read_len(&mut r)?;
+        let comment = String::from_utf8_lossy(&read(&mut r, len)?).into_owned();
+
+        if let Some((key, value)) = comment.split_once('=') {
+            tag.vorbis_comments_mut()
+                .comments
+                .entry(key.to_ascii_uppercase())
+                .or_default()
+                .push(value.to_owned());
+        }
+    }
+
+    Ok(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Returns an Ogg page holding packets, each ending on it.
+    fn ogg_page(packets: &[&[u8]], granule: i64) -> Vec<u8> {
+        let mut lacing = vec![];
+        let mut data = vec![];
+
+        for p in packets {
+            lacing.extend(std::iter::repeat_n(255, p.len() / 255));
+            lacing.push((p.len() % 255) as u8);
+            data.extend_from_slice(p);
+        }
+
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(data);
+
+        page
+    }
+
+    fn vorbis_identification(sample_rate: u32, nominal_bitrate: u32) -> Vec<u8> {
+        let mut p = b"\x01vorbis".to_vec();
+        p.extend_from_slice(&0u32.to_le_bytes());
+        p.push(2);
+        p.extend_from_slice(&sample_rate.to_le_bytes());
+        p.extend_from_slice(&0u32.to_le_bytes());
+        p.extend_from_slice(&nominal_bitrate.to_le_bytes());
+        p.extend_from_slice(&0u32.to_le_bytes());
+        p.push(0xb8);
+
+        p
+    }
+
+    fn comment_header(prefix: &[u8], comments: &[&str]) -> Vec<u8> {
+        let mut p = prefix.to_vec();
+        p.extend_from_slice(&6u32.to_le_bytes());
+        p.extend_from_slice(b"vendor");
+        p.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+
+        for c in comments {
+            p.extend_from_slice(&(c.len() as u32).to_le_bytes());
+            p.extend_from_slice(c.as_bytes());
+        }
+
+        p
+    }
+
+    #[test]
+    fn mpeg1_layer3_header() {
+        // 128 kbit/s, 44.1 kHz, joint stereo.
+        let frame = parse_mpeg_header(&[0xff, 0xfb, 0x90, 0x64]).unwrap();
+
+        assert_eq!(frame.bitrate, 128);
+        assert_eq!(frame.sample_rate, 44100);
+        assert_eq!(frame.channels, 2);
+        assert_eq!(frame.samples_per_frame, 1152);
+        assert_eq!(frame.frames, None);
+    }
+
+    #[test]
+    fn mpeg2_layer3_mono_header() {
+        // 64 kbit/s, 22.05 kHz, mono.
+        let frame = parse_mpeg_header(&[0xff, 0xf3, 0x80, 0xc0]).unwrap();
+
+        assert_eq!(frame.bitrate, 64);
+        assert_eq!(frame.sample_rate, 22050);
+        assert_eq!(frame.channels, 1);
+        assert_eq!(frame.samples_per_frame, 576);
+    }
+
+    #[test]
+    fn invalid_mpeg_headers() {
+        // No sync, reserved version, free bitrate, bad bitrate and reserved sample rate.
+        for header in [
+            [0xfe, 0xfb, 0x90, 0x64],
+            [0xff, 0xeb, 0x90, 0x64],
+            [0xff, 0xfb, 0x00, 0x64],
+            [0xff, 0xfb, 0xf0, 0x64],
+            [0xff, 0xfb, 0x9c, 0x64],
+        ] {
+            assert!(parse_mpeg_header(&header).is_none(), "{header:x?}");
+        }
+
+        assert!(parse_mpeg_header(&[0xff, 0xfb]).is_none());
+    }
+
+    #[test]
+    fn xing_frame_count() {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x64];
+        frame.extend_from_slice(&[0; 32]);
+        frame.extend_from_slice(b"Xing");
+        frame.extend_from_slice(&1u32.to_be_bytes());
+        frame.extend_from_slice(&1234u32.to_be_bytes());
+
+        assert_eq!(parse_mpeg_header(&frame).unwrap().frames, Some(1234));
+    }
+
+    #[test]
+    fn mpeg_frame_after_garbage() {
+        let mut data = vec![0x00, 0x12, 0xff, 0x00];
+        data.extend_from_slice(&[0xff, 0xfb, 0x90, 0x64]);
+        data.extend_from_slice(&[0; 16]);
+
+        let frame = find_mpeg_frame(&mut Cursor::new(data), 0).unwrap().unwrap();
+
+        assert_eq!(frame.offset, 4);
+    }
+
+    #[test]
+    fn vorbis_identification_header() {
+        let stream = parse_ogg_identification(&vorbis_identification(96000, 320000)).unwrap();
+
+        assert_eq!(stream.sample_rate, 96000);
+        assert_eq!(stream.granule_rate, 96000);
+        assert_eq!(stream.channels, 2);
+        assert_eq!(stream.pre_skip, 0);
+        assert_eq!(stream.nominal_bitrate, Some(320000));
+
+        let stream = parse_ogg_identification(&vorbis_identification(44100, 0)).unwrap();
+        assert_eq!(stream.nominal_bitrate, None);
+
+        assert!(parse_ogg_identification(&vorbis_identification(0, 0)).is_none());
+    }
+
+    #[test]
+    fn opus_identification_header() {
+        let mut packet = b"OpusHead\x01\x02".to_vec();
+        packet.extend_from_slice(&312u16.to_le_bytes());
+        packet.extend_from_slice(&44100u32.to_le_bytes());
+
+        let stream = parse_ogg_identification(&packet).unwrap();
+
+        assert_eq!(stream.sample_rate, 48000);
+        assert_eq!(stream.channels, 2);
+        assert_eq!(stream.pre_skip, 312);
+    }
+
+    #[test]
+    fn unknown_ogg_stream() {
+        assert!(parse_ogg_identification(b"\x7fFLAC\x01\x00").is_none());
+        assert!(parse_ogg_identification(b"\x01vorb").is_none());
+    }
+
+    #[test]
+    fn ogg_packets_across_pages() {
+        let ident = vorbis_identification(44100, 0);
+        // Spans two pages: 255-byte lacing values continue the packet on the next one.
+        let comments = comment_header(b"\x03vorbis", &["TITLE=Uno", &"X".repeat(600)]);
+
+        let mut data = ogg_page(&[&ident], 0);
+        let (first, rest) = comments.split_at(510);
+        let mut page = ogg_page(&[first], -1);
+        // Drop the 0 lacing value that would end the packet here.
+        page.remove(27 + 2);
+        page[26] -= 1;
+        data.extend(page);
+        data.extend(ogg_page(&[rest], 0));
+
+        let packets = ogg_packets(&mut Cursor::new(data), 2).unwrap();
+
+        assert_eq!(packets[0], ident);
+        assert_eq!(packets[1], comments);
+    }
+
+    #[test]
+    fn ogg_packets_need_marker() {
+        let mut data = ogg_page(&[b"packet"], 0);
+        data[0] = b'X';
+
+        assert!(ogg_packets(&mut Cursor::new(data), 1).is_err());
+    }
+
+    #[test]
+    fn last_ogg_granule() {
+        let mut data = ogg_page(&[&vorbis_identification(44100, 0)], 0);
+        data.extend(ogg_page(&[&[1; 300]], 44100));
+        data.extend(ogg_page(&[&[2; 300]], 88200));
+        // A page on which no packet ends.
+        data.extend(ogg_page(&[&[3; 100]], -1));
+
+        assert_eq!(
+            ogg_last_granule(&mut Cursor::new(data)).unwrap(),
+            Some(88200)
+        );
+        assert_eq!(ogg_last_granule(&mut Cursor::new(vec![])).unwrap(), None);
+    }
+
+    #[test]
+    fn vorbis_and_opus_comments() {
+        for prefix in [&b"\x03vorbis"[..], b"OpusTags"] {
+            let tag = vorbis_comments(&comment_header(
+                prefix,
+                &["title=Uno", "ARTIST=A", "ARTIST=B", "not a comment"],
+            ))
+            .unwrap();
+
+            assert_eq!(
+                tag.get_vorbis("TITLE").unwrap().collect::<Vec<_>>(),
+                ["Uno"]
+            );
+            assert_eq!(
+                tag.get_vorbis("ARTIST").unwrap().collect::<Vec<_>>(),
+                ["A", "B"]
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_comments() {
+        let mut header = comment_header(b"\x03vorbis", &["TITLE=Uno"]);
+        header.truncate(header.len() - 2);
+
+        assert!(vorbis_comments(&header).is_err());
+        assert!(vorbis_comments(b"\x03theora").is_err());
+    }
+
+    fn sha(data: &[u8]) -> String {
+        hash_regions(&mut Cursor::new(data), &[(0, data.len() as u64)]).unwrap()
+    }
+
+    fn id3v2(frames: &[u8]) -> Vec<u8> {
+        let size = frames.len() as u32;
+        let mut tag = b"ID3\x04\0\0".to_vec();
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        tag.extend_from_slice(frames);
+
+        tag
+    }
+
+    fn flac(id3: &[u8], comment: &[u8], audio: &[u8]) -> Vec<u8> {
+        let mut file = id3.to_vec();
+        file.extend_from_slice(b"fLaC");
+        // STREAMINFO, then the comments as the last metadata block.
+        file.extend_from_slice(&[0, 0, 0, 34]);
+        file.extend_from_slice(&[0; 34]);
+        file.push(0x84);
+        file.extend_from_slice(&(comment.len() as u32).to_be_bytes()[1..]);
+        file.extend_from_slice(comment);
+        file.extend_from_slice(audio);
+
+        file
+    }
+
+    #[test]
+    fn flac_audio_leaves_out_tags() {
+        // Audio frames whose last 128 bytes happen to start with "TAG", as ID3v1 tags do.
+        let mut audio = vec![0x5a; 300];
+        audio[300 - 128..300 - 125].copy_from_slice(b"TAG");
+
+        let hash = hash_flac_audio(&mut Cursor::new(flac(b"", b"TITLE=Uno", &audio))).unwrap();
+        let retagged = flac(&id3v2(b"TIT2"), b"TITLE=Dos, longer", &audio);
+
+        assert_eq!(hash, sha(&audio));
+        assert_eq!(hash_flac_audio(&mut Cursor::new(retagged)).unwrap(), hash);
+        assert!(hash_flac_audio(&mut Cursor::new(b"OggS\0\0\0\0".to_vec())).is_err());
+    }
+
+    #[test]
+    fn mpeg_audio_leaves_out_tags() {
+        let audio = [0xff, 0xfb, 0x90, 0x64].repeat(100);
+
+        let mut ape = b"APETAGEX".to_vec();
+        ape.extend_from_slice(&2000u32.to_le_bytes());
+        ape.extend_from_slice(&(32u32 + 8).to_le_bytes());
+        ape.extend_from_slice(&0u32.to_le_bytes());
+        ape.extend_from_slice(&0u32.to_le_bytes());
+        ape.extend_from_slice(&[0; 8]);
+
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+
+        let mut file = id3v2(b"TIT2");
+        file.extend_from_slice(&audio);
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&ape);
+        file.extend_from_slice(&id3v1);
+
+        assert_eq!(
+            hash_mpeg_audio(&mut Cursor::new(file)).unwrap(),
+            sha(&audio)
+        );
+        assert_eq!(
+            hash_mpeg_audio(&mut Cursor::new(&audio[..])).unwrap(),
+            sha(&audio)
+        );
+    }
+
+    #[test]
+    fn mp4_audio_is_media_data() {
+        fn atom(kind: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut atom = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+            atom.extend_from_slice(kind);
+            atom.extend_from_slice(data);
+
+            atom
+        }
+
+        let mut file = atom(b"ftyp", b"M4A ");
+        file.extend(atom(b"moov", b"tags"));
+        file.extend(atom(b"mdat", b"first"));
+        file.extend(atom(b"free", &[0; 16]));
+        file.extend(atom(b"mdat", b"second"));
+
+        assert_eq!(
+            hash_mp4_audio(&mut Cursor::new(file)).unwrap(),
+            sha(b"firstsecond")
+        );
+        assert!(hash_mp4_audio(&mut Cursor::new(atom(b"moov", b"tags"))).is_err());
+
+        // Atoms running past the end of the file, or past the end of any file.
+        let mut truncated = atom(b"mdat", b"audio");
+        truncated.truncate(10);
+        assert!(hash_mp4_audio(&mut Cursor::new(truncated)).is_err());
+
+        let mut huge = atom(b"ftyp", b"M4A ");
+        huge.extend_from_slice(&1u32.to_be_bytes());
+        huge.extend_from_slice(b"mdat");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend_from_slice(b"audio");
+        assert!(hash_mp4_audio(&mut Cursor::new(huge)).is_err());
+    }
+
+    #[test]
+    fn ogg_audio_leaves_out_headers() {
+        let audio: [&[u8]; 3] = [&[1; 300], &[2; 20], &[3; 40]];
+
+        let stream = |comments: &[&str]| {
+            let mut file = ogg_page(&[&vorbis_identification(44100, 128000)], 0);
+            file.extend(ogg_page(
+                &[&comment_header(b"\x03vorbis", comments), b"\x05vorbis"],
+                0,
+            ));
+            file.extend(ogg_page(&audio[..2], 1000));
+            file.extend(ogg_page(&audio[2..], 2000));
+
+            file
+        };
+
+        let hash = hash_ogg_audio(&mut Cursor::new(stream(&["TITLE=Uno"]))).unwrap();
+
+        assert_eq!(hash, sha(&audio.concat()));
+        assert_eq!(
+            hash_ogg_audio(&mut Cursor::new(stream(&["TITLE=Dos", "ARTIST=A"]))).unwrap(),
+            hash
+        );
+    }
+}
@@ -1,10 +1,19 @@
+use crate::media;
 use audiotags::AudioTag;
 use once_cell::sync::Lazy;
 use rhai::{CustomType, TypeBuilder};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
 
 static NULL_CHAR: once_cell::sync::Lazy<String> = Lazy::new(|| String::from_utf8(vec![0]).unwrap());
 
-#[derive(Debug, Clone, sqlx::Type, Default)]
+/// Album artist of compilations that don't have one.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+#[derive(Debug, Clone, sqlx::Type, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(i64)]
 pub enum FileState {
     #[default]
@@ -23,51 +32,272 @@ impl From<i64> for FileState {
     }
 }
 
+/// A file path as stored in the database.
+/// Paths are kept as raw bytes, so that names which aren't valid UTF-8 survive the round trip:
+/// they're converted lossily only when displayed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct FilePath(Vec<u8>);
+
+impl FilePath {
+    pub fn as_path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.0))
+    }
+}
+
+impl From<&Path> for FilePath {
+    fn from(value: &Path) -> Self {
+        Self(value.as_os_str().as_bytes().to_vec())
+    }
+}
+
+impl From<PathBuf> for FilePath {
+    fn from(value: PathBuf) -> Self {
+        value.as_path().into()
+    }
+}
+
+impl From<Vec<u8>> for FilePath {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+/// Paths are written as strings: valid UTF-8 as-is, other bytes and "%" as "%XX" escapes.
+impl serde::Serialize for FilePath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut escaped = String::new();
+        let mut rest = self.0.as_slice();
+
+        while !rest.is_empty() {
+            let (valid, invalid) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, rest.len()),
+                Err(e) => (
+                    std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap(),
+                    e.valid_up_to() + e.error_len().unwrap_or(rest.len() - e.valid_up_to()),
+                ),
+            };
+
+            escaped.push_str(&valid.replace('%', "%25"));
+
+            for b in &rest[valid.len()..invalid] {
+                escaped.push_str(&format!("%{b:02X}"));
+            }
+
+            rest = &rest[invalid..];
+        }
+
+        serializer.serialize_str(&escaped)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FilePath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let escaped = String::deserialize(deserializer)?;
+        let mut bytes = vec![];
+        let mut rest = escaped.as_bytes();
+
+        while let Some((&b, tail)) = rest.split_first() {
+            if b != b'%' {
+                bytes.push(b);
+                rest = tail;
+                continue;
+            }
+
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| {
+                    serde::de::Error::custom(format!("invalid escape in path {escaped}"))
+                })?;
+
+            bytes.push(byte);
+            rest = &tail[2..];
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl AsRef<Path> for FilePath {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl std::fmt::Display for FilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_path().display())
+    }
+}
+
 pub struct RawTrack {
     pub tags: Box<dyn AudioTag + Send + Sync>,
-    pub path: String,
+    pub properties: media::Properties,
+    pub path: PathBuf,
 }
 
+/// The track object exposed to filters.
+/// Metadata missing from the file is represented by zero values, e.g. an empty genre or a
+/// sample rate of 0.
 #[derive(Debug, Clone, Default, rhai::CustomType)]
 pub struct BaseTrack {
     pub title: String,
     pub artist: String,
+    pub track_artist: String,
+    pub artist_sort: String,
     pub album: String,
     pub number: i64,
     pub file_path: String,
     pub disc_number: i64,
     pub disc_total: i64,
     pub extension: String,
+    pub year: i64,
+    pub genre: String,
+    pub composer: String,
+    pub track_total: i64,
+    pub duration: f64,
+    pub bitrate: i64,
+    pub sample_rate: i64,
+    pub bit_depth: i64,
+    pub channels: i64,
+    pub file_size: i64,
+    pub replaygain_track_gain: f64,
+    pub replaygain_track_peak: f64,
+    pub replaygain_album_gain: f64,
+    pub replaygain_album_peak: f64,
+    pub musicbrainz_track_id: String,
+    pub musicbrainz_album_id: String,
+    pub musicbrainz_artist_id: String,
+    pub musicbrainz_album_artist_id: String,
+    pub compilation: bool,
+    pub play_count: i64,
+    pub album_play_count: i64,
+    pub last_played: i64,
+    pub rating: i64,
+    pub album_rating: i64,
+    pub labels: rhai::Array,
 }
 
 impl From<Track> for BaseTrack {
     fn from(value: Track) -> Self {
+        let track_artist = value
+            .track_artist
+            .clone()
+            .unwrap_or_else(|| value.artist.clone());
+        let artist_sort = value.sort_artist();
+
         Self {
             title: value.title,
             artist: value.artist,
+            track_artist,
+            artist_sort,
             album: value.album,
             number: value.number,
-            file_path: value.file_path,
+            file_path: value.file_path.to_string(),
             disc_number: value.disc_number,
             disc_total: value.disc_total,
             extension: value.extension,
+            year: value.year.unwrap_or_default(),
+            genre: value.genre.unwrap_or_default(),
+            composer: value.composer.unwrap_or_default(),
+            track_total: value.track_total.unwrap_or_default(),
+            duration: value.duration.unwrap_or_default(),
+            bitrate: value.bitrate.unwrap_or_default(),
+            sample_rate: value.sample_rate.unwrap_or_default(),
+            bit_depth: value.bit_depth.unwrap_or_default(),
+            channels: value.channels.unwrap_or_default(),
+            file_size: value.file_size.unwrap_or_default(),
+            replaygain_track_gain: value.replaygain_track_gain.unwrap_or_default(),
+            replaygain_track_peak: value.replaygain_track_peak.unwrap_or_default(),
+            replaygain_album_gain: value.replaygain_album_gain.unwrap_or_default(),
+            replaygain_album_peak: value.replaygain_album_peak.unwrap_or_default(),
+            musicbrainz_track_id: value.musicbrainz_track_id.unwrap_or_default(),
+            musicbrainz_album_id: value.musicbrainz_album_id.unwrap_or_default(),
+            musicbrainz_artist_id: value.musicbrainz_artist_id.unwrap_or_default(),
+            musicbrainz_album_artist_id: value.musicbrainz_album_artist_id.unwrap_or_default(),
+            compilation: value.compilation,
+            play_count: value.play_count,
+            album_play_count: value.album_play_count,
+            last_played: value.last_played.unwrap_or_default(),
+            rating: value.rating.unwrap_or_default(),
+            album_rating: value.album_rating.unwrap_or_default(),
+            labels: value
+                .labels
+                .unwrap_or_default()
+                .lines()
+                .map(|l| l.to_owned().into())
+                .collect(),
         }
     }
 }
 
-#[derive(Debug, Clone, sqlx::Type, Default)]
+/// Tracks are written to and read from dumps, see dump.rs.
+/// Fields missing from older dumps take their default value.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Track {
+    #[serde(skip)]
     pub id: i64,
     pub track_id: String,
     pub title: String,
     pub artist: String,
     pub album: String,
     pub number: i64,
-    pub file_path: String,
+    pub file_path: FilePath,
     pub disc_number: i64,
     pub disc_total: i64,
     pub file_state: FileState,
     pub extension: String,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub track_total: Option<i64>,
+    pub duration: Option<f64>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub bit_depth: Option<i64>,
+    pub channels: Option<i64>,
+    pub file_size: Option<i64>,
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
+    pub musicbrainz_track_id: Option<String>,
+    pub musicbrainz_album_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+    pub musicbrainz_album_artist_id: Option<String>,
+    pub content_hash: Option<String>,
+    pub audio_hash: Option<String>,
+    pub stored_path: Option<FilePath>,
+    pub track_artist: Option<String>,
+    pub compilation: bool,
+    /// Album artist as found in tags, before aliases are applied to artist.
+    pub tagged_artist: Option<String>,
+    pub artist_sort: Option<String>,
+    /// Plays, ratings and labels of the track, for filters: they're stored apart, see Play,
+    /// Rating and Label, and only read by the queries that select them.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub play_count: i64,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub album_play_count: i64,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub last_played: Option<i64>,
+    /// The track's own rating, or else its album's.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub rating: Option<i64>,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub album_rating: Option<i64>,
+    /// Newline-separated.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub labels: Option<String>,
 }
 
 impl std::fmt::Display for Track {
@@ -77,24 +307,100 @@ impl std::fmt::Display for Track {
 }
 
 impl Track {
-    pub fn storage_path(&self, base: &str) -> String {
-        let mut p = std::path::PathBuf::new();
+    /// Returns the key identifying this track across databases: the hash of its audio if known,
+    /// its tag-derived id along with its disc and track numbers otherwise, since albums may
+    /// repeat a title.
+    /// Queries compute it as COALESCE(audio_hash, track_id || '-' || disc_number || '-' || number).
+    pub fn key(&self) -> String {
+        self.audio_hash
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}-{}", self.track_id, self.disc_number, self.number))
+    }
 
-        let extension = std::path::Path::new(&self.file_path)
-            .extension()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap();
+    /// Returns where this track is stored under base: where it was copied to for tracks of a
+    /// destination database, where layout says it should be copied to otherwise.
+    pub fn storage_path(&self, base: impl AsRef<Path>, layout: &Layout) -> PathBuf {
+        if let Some(stored) = &self.stored_path {
+            return base.as_ref().join(stored);
+        }
+
+        let folder = match (&layout.compilations, self.compilation, &layout.folder_names) {
+            (Some(compilations), true, _) => compilations.clone(),
+            (_, _, FolderNames::Sort) => self.sort_artist(),
+            _ => self.artist.clone(),
+        };
+
+        let mut p = PathBuf::new();
 
-        let filename = format!("{}.{}", self.title, extension);
+        // Keep the source extension as-is, even if it isn't valid UTF-8.
+        let mut filename = OsString::from(clean(self.title.clone(), true));
+        filename.push(".");
+        filename.push(self.file_path.as_path().extension().unwrap_or_default());
 
         p.push(base);
-        p.push(clean(self.artist.clone(), false));
-        p.push(clean(self.album.clone(), false));
+        p.push(layout.path_form.apply(&clean(folder, false)));
+        p.push(layout.path_form.apply(&clean(self.album.clone(), false)));
         p.push(clean(self.disc_number.to_string(), false));
-        p.push(clean(filename, true));
+        p.push(layout.path_form.apply_os(&filename));
 
-        p.to_str().unwrap().to_string()
+        p
+    }
+
+    /// Normalises tags to NFC, updating the track id if needed.
+    /// Returns true if any tag changed.
+    pub fn normalise_tags(&mut self) -> bool {
+        let mut changed = false;
+
+        for s in [
+            &mut self.title,
+            &mut self.artist,
+            &mut self.album,
+            &mut self.extension,
+        ] {
+            changed |= nfc_in_place(s);
+        }
+
+        for s in [
+            &mut self.genre,
+            &mut self.composer,
+            &mut self.track_artist,
+            &mut self.tagged_artist,
+            &mut self.artist_sort,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            changed |= nfc_in_place(s);
+        }
+
+        if changed {
+            self.track_id = track_hash(self);
+        }
+
+        changed
+    }
+
+    /// Returns the name artist sorts by: the one found in tags if any, "Beatles, The" for
+    /// "The Beatles" otherwise.
+    /// Tags describe the tagged artist, they're ignored if an alias renamed it.
+    pub fn sort_artist(&self) -> String {
+        let renamed = self
+            .tagged_artist
+            .as_ref()
+            .is_some_and(|tagged| *tagged != self.artist);
+
+        if let Some(sort) = self
+            .artist_sort
+            .as_ref()
+            .filter(|s| !renamed && !s.trim().is_empty())
+        {
+            return sort.clone();
+        }
+
+        match self.artist.strip_prefix("The ") {
+            Some(rest) if !rest.is_empty() => format!("{}, The", rest),
+            _ => self.artist.clone(),
+        }
     }
 }
 
@@ -102,11 +408,18 @@ impl From<RawTrack> for Track {
     fn from(track: RawTrack) -> Self {
         let disc = track.tags.disc();
 
-        // If no album artist has been found, use the artist tag.
+        let props = track.properties;
+
+        // If no album artist has been found, use the artist tag, unless the track is part of a
+        // compilation: its tracks would be scattered across artists.
         // If that's missing too, we have an Unknown album.
-        let artist = match track.tags.album_artist() {
-            Some(aa) => aa,
-            None => track.tags.artist().unwrap_or("Unknown Album"),
+        let (artist, artist_sort) = match track.tags.album_artist() {
+            Some(aa) => (aa, props.album_artist_sort.clone()),
+            None if props.compilation => (VARIOUS_ARTISTS, None),
+            None => (
+                track.tags.artist().unwrap_or("Unknown Album"),
+                props.artist_sort.clone(),
+            ),
         };
 
         let mut t = Self {
@@ -120,21 +433,50 @@ impl From<RawTrack> for Track {
                 .unwrap_or("Unknown Album")
                 .to_owned(),
             number: track.tags.track_number().unwrap_or_default() as i64,
-            file_path: track.path,
+            file_path: track.path.into(),
             disc_number: disc.0.unwrap_or_default() as i64,
             disc_total: disc.1.unwrap_or_default() as i64,
             file_state: FileState::Unknown,
             extension: String::new(),
+            year: track.tags.year().map(|y| y as i64),
+            genre: track.tags.genre().map(|g| g.to_owned()),
+            composer: track.tags.composer().map(|c| c.to_owned()),
+            track_total: track.tags.total_tracks().map(|tt| tt as i64),
+            // Tag-declared durations are often missing or rounded, prefer the stream one.
+            duration: props.duration.or(track.tags.duration()),
+            bitrate: props.bitrate,
+            sample_rate: props.sample_rate,
+            bit_depth: props.bit_depth,
+            channels: props.channels,
+            file_size: props.file_size,
+            replaygain_track_gain: props.replaygain_track_gain,
+            replaygain_track_peak: props.replaygain_track_peak,
+            replaygain_album_gain: props.replaygain_album_gain,
+            replaygain_album_peak: props.replaygain_album_peak,
+            musicbrainz_track_id: props.musicbrainz_track_id,
+            musicbrainz_album_id: props.musicbrainz_album_id,
+            musicbrainz_artist_id: props.musicbrainz_artist_id,
+            musicbrainz_album_artist_id: props.musicbrainz_album_artist_id,
+            content_hash: props.content_hash,
+            audio_hash: props.audio_hash,
+            stored_path: None,
+            track_artist: track.tags.artist().map(|a| a.to_owned()),
+            compilation: props.compilation,
+            tagged_artist: Some(artist.to_owned()),
+            artist_sort,
+            ..Default::default()
         };
 
+        t.normalise_tags();
         t.track_id = track_hash(&t);
 
-        let extension = std::path::Path::new(&t.file_path)
+        let extension = t
+            .file_path
+            .as_path()
             .extension()
-            .unwrap_or(std::ffi::OsStr::new("NONE"))
-            .to_str()
-            .unwrap()
-            .to_string();
+            .unwrap_or(OsStr::new("NONE"))
+            .to_string_lossy()
+            .into_owned();
 
         t.extension = extension;
 
@@ -142,17 +484,35 @@ impl From<RawTrack> for Track {
     }
 }
 
+/// Hashes the tags identifying a track.
+/// The tagged artist is used, so that aliases don't change the hash.
+/// The extension isn't part of it: imports have always hashed tracks before reading it.
 fn track_hash(track: &Track) -> String {
     let mut sb = string_builder::Builder::default();
 
-    sb.append(track.artist.clone());
+    sb.append(track.tagged_artist.clone().unwrap_or(track.artist.clone()));
     sb.append(track.album.clone());
     sb.append(track.title.clone());
-    sb.append(track.extension.clone());
 
     sha256::digest(sb.string().unwrap())
 }
 
+/// Normalises s to NFC, the form tags are stored in.
+pub fn nfc(s: &str) -> String {
+    s.nfc().collect()
+}
+
+/// Normalises s to NFC, returning true if it changed.
+fn nfc_in_place(s: &mut String) -> bool {
+    if unicode_normalization::is_nfc(s) {
+        return false;
+    }
+
+    *s = nfc(s);
+
+    true
+}
+
 // Incredibly ugly way to remove all characters sqlite3's FTS5 hates.
 // I am ashamed of my self, but as they say, if it works it isn't stupid.
 fn clean(s: String, is_file: bool) -> String {
@@ -184,7 +544,7 @@ fn clean(s: String, is_file: bool) -> String {
         "]",
         &NULL_CHAR,
     ] {
-        if c != "" {
+        if !c.is_empty() {
             s = s.replace(c, "_")
         }
     }
@@ -192,6 +552,163 @@ fn clean(s: String, is_file: bool) -> String {
     s
 }
 
+/// A source directory, along with the glob patterns deciding which of its files get imported
+/// and the options used to traverse it.
+/// Patterns are matched against paths relative to the directory itself.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Directory {
+    #[serde(with = "path_string")]
+    pub directory: PathBuf,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub follow_links: bool,
+    pub same_file_system: bool,
+}
+
+impl Directory {
+    /// Returns the prefix shared by the paths of all the tracks contained in this directory.
+    pub fn prefix(&self) -> Vec<u8> {
+        path_prefix(&self.directory)
+    }
+}
+
+/// Returns the raw prefix shared by the paths of all the files contained in path.
+pub fn path_prefix(path: &Path) -> Vec<u8> {
+    let mut prefix = path.as_os_str().as_bytes().to_vec();
+
+    while prefix.last() == Some(&b'/') {
+        prefix.pop();
+    }
+
+    prefix.push(b'/');
+
+    prefix
+}
+
+/// Writes paths the way FilePath does.
+mod path_string {
+    use super::FilePath;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        FilePath::from(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(FilePath::deserialize(deserializer)?.as_path().to_path_buf())
+    }
+}
+
+/// A track that lost to another copy of itself in duplicate resolution, and won't be copied to
+/// destinations.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Suppression {
+    #[serde(skip)]
+    pub id: i64,
+    pub track_key: String,
+    pub file_path: FilePath,
+    pub kept_path: FilePath,
+    pub reason: String,
+    pub quarantine_path: Option<FilePath>,
+    pub created_at: String,
+}
+
+/// A play of a track, read from a device's scrobbler log.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+pub struct Play {
+    pub track_key: String,
+    /// Seconds since the epoch.
+    pub played_at: i64,
+}
+
+/// A rating given to a track with 'label rate', from 1 to 5.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+pub struct Rating {
+    pub track_key: String,
+    pub rating: i64,
+}
+
+/// A rating given to an album with 'label rate --albums', from 1 to 5.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+pub struct AlbumRating {
+    pub artist: String,
+    pub album: String,
+    pub rating: i64,
+}
+
+/// A label given to a track with 'label add'.
+#[derive(Debug, Clone, sqlx::FromRow, Default, serde::Serialize, serde::Deserialize)]
+pub struct Label {
+    pub track_key: String,
+    pub label: String,
+}
+
+/// How tracks are laid out on a destination.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    /// Folder grouping compilations, in place of their album artist's one.
+    pub compilations: Option<String>,
+    pub folder_names: FolderNames,
+    pub path_form: PathForm,
+}
+
+/// Unicode normalisation form of the names of the files and folders created on a destination.
+#[derive(
+    Debug, Clone, Default, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PathForm {
+    /// Composed, as expected by most systems.
+    #[default]
+    Nfc,
+    /// Decomposed, as stored by macOS HFS+ file systems.
+    Nfd,
+    /// As tagged, the way older versions copied tracks.
+    #[value(skip)]
+    Tagged,
+}
+
+impl PathForm {
+    pub fn apply(&self, s: &str) -> String {
+        match self {
+            Self::Nfc => s.nfc().collect(),
+            Self::Nfd => s.nfd().collect(),
+            Self::Tagged => s.to_owned(),
+        }
+    }
+
+    /// Normalises s if it's valid UTF-8, leaves it as-is otherwise.
+    fn apply_os(&self, s: &OsStr) -> OsString {
+        match s.to_str() {
+            Some(s) => self.apply(s).into(),
+            None => s.to_owned(),
+        }
+    }
+}
+
+/// Which name of an artist their folder is named after.
+#[derive(
+    Debug, Clone, Default, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FolderNames {
+    /// "The Beatles"
+    #[default]
+    Display,
+    /// "Beatles, The"
+    Sort,
+}
+
+/// An alternative spelling of an artist's name, e.g. "Beatles, The" for "The Beatles".
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Alias {
+    pub alias: String,
+    pub artist: String,
+}
+
 #[allow(dead_code)]
 pub struct Album {
     pub title: String,